@@ -0,0 +1,66 @@
+//! Compares the original regex-per-call assembler with the compiled template path.
+//!
+//! Run with `cargo bench --bench assemble_number`.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use number_generator::number_assembler::NumberAssembler;
+use number_generator::prefix_rule::PrefixRule;
+use regex::Regex;
+use std::collections::HashMap;
+use std::hint::black_box;
+
+/// The assembler as it was before templates were compiled: a fresh `Regex` and a
+/// `String::replace` pass per placeholder on every call.
+fn legacy_assemble_number(prefix: &str, prefix_rule: &PrefixRule, sequence: u64) -> String {
+    let mut replacements: HashMap<String, String> = HashMap::new();
+    replacements.insert("prefix".to_string(), prefix.to_string());
+
+    let year = chrono::Utc::now().format("%Y").to_string();
+    replacements.insert("year".to_string(), year);
+
+    let seq_formatted = format!("{:0width$}", sequence, width = prefix_rule.seq_length as usize);
+    replacements.insert("SEQ".to_string(), seq_formatted);
+
+    let mut formatted_number = prefix_rule.format.clone();
+
+    let re = Regex::new(r"\{([A-Za-z0-9_]+)(?::(\d+))?\}").unwrap();
+    for capture in re.captures_iter(&prefix_rule.format) {
+        let full_match = capture.get(0).unwrap().as_str();
+        let variable_name = capture.get(1).unwrap().as_str();
+
+        if let Some(replacement_value) = replacements.get(variable_name) {
+            formatted_number = formatted_number.replace(full_match, replacement_value);
+        }
+    }
+
+    formatted_number
+}
+
+fn bench_assemble_number(c: &mut Criterion) {
+    let mut prefix_rule = PrefixRule {
+        prefix_key: "ORDER".to_string(),
+        format: "{prefix}-{year}-{SEQ:6}".to_string(),
+        seq_length: 6,
+        initial_seq: 1,
+        ..Default::default()
+    };
+    let assembler = NumberAssembler::new();
+
+    let mut group = c.benchmark_group("assemble_number");
+    group.bench_function("legacy_regex", |b| {
+        b.iter(|| legacy_assemble_number(black_box("ORDER"), black_box(&prefix_rule), black_box(123_456)))
+    });
+
+    prefix_rule.compile().unwrap();
+    group.bench_function("compiled_template", |b| {
+        b.iter(|| {
+            assembler
+                .assemble_number(black_box("ORDER"), black_box(&prefix_rule), black_box(123_456))
+                .unwrap()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_assemble_number);
+criterion_main!(benches);
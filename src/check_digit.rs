@@ -0,0 +1,210 @@
+//! Check digit algorithms for the `{CHECK:<algorithm>}` placeholder.
+//!
+//! Each algorithm runs over the characters rendered before the placeholder. The purely
+//! numeric algorithms only look at ASCII digits and skip everything else (separators,
+//! letters), so `INV-2026-00042` is checked as `202600042`. ISO 7064 MOD 97-10 also
+//! folds letters in, IBAN-style (`A` = 10 … `Z` = 35).
+
+/// Damm's totally anti-symmetric quasigroup of order 10.
+const DAMM_TABLE: [[u8; 10]; 10] = [
+    [0, 3, 1, 7, 5, 9, 8, 6, 4, 2],
+    [7, 0, 9, 2, 1, 5, 4, 8, 6, 3],
+    [4, 2, 0, 6, 8, 7, 1, 3, 5, 9],
+    [1, 7, 5, 0, 9, 8, 3, 4, 2, 6],
+    [6, 1, 2, 3, 0, 4, 5, 9, 7, 8],
+    [3, 6, 7, 4, 2, 0, 9, 5, 8, 1],
+    [5, 8, 6, 9, 7, 2, 0, 1, 3, 4],
+    [8, 9, 4, 5, 3, 6, 2, 0, 1, 7],
+    [9, 4, 3, 8, 6, 1, 7, 2, 0, 5],
+    [2, 5, 8, 1, 4, 3, 6, 7, 9, 0],
+];
+
+/// Verhoeff's multiplication table of the dihedral group D5.
+const VERHOEFF_MULTIPLY: [[u8; 10]; 10] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9],
+    [1, 2, 3, 4, 0, 6, 7, 8, 9, 5],
+    [2, 3, 4, 0, 1, 7, 8, 9, 5, 6],
+    [3, 4, 0, 1, 2, 8, 9, 5, 6, 7],
+    [4, 0, 1, 2, 3, 9, 5, 6, 7, 8],
+    [5, 9, 8, 7, 6, 0, 4, 3, 2, 1],
+    [6, 5, 9, 8, 7, 1, 0, 4, 3, 2],
+    [7, 6, 5, 9, 8, 2, 1, 0, 4, 3],
+    [8, 7, 6, 5, 9, 3, 2, 1, 0, 4],
+    [9, 8, 7, 6, 5, 4, 3, 2, 1, 0],
+];
+
+/// Verhoeff's position-dependent permutation.
+const VERHOEFF_PERMUTE: [[u8; 10]; 8] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9],
+    [1, 5, 7, 6, 2, 8, 3, 0, 9, 4],
+    [5, 8, 0, 3, 7, 9, 6, 1, 4, 2],
+    [8, 9, 1, 6, 0, 4, 3, 5, 2, 7],
+    [9, 4, 5, 3, 1, 2, 6, 8, 7, 0],
+    [4, 2, 8, 6, 5, 7, 3, 9, 0, 1],
+    [2, 7, 9, 3, 8, 0, 6, 4, 1, 5],
+    [7, 0, 4, 6, 9, 1, 3, 2, 5, 8],
+];
+
+const VERHOEFF_INVERSE: [u8; 10] = [0, 4, 3, 2, 1, 5, 6, 7, 8, 9];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CheckDigitAlgorithm {
+    /// `luhn`: the Luhn mod 10 algorithm used by payment cards.
+    Luhn,
+    /// `mod11`: weights 2..=7 repeating from the right; a remainder of 10 is written `X`.
+    Mod11,
+    /// `damm`: catches all single-digit errors and adjacent transpositions.
+    Damm,
+    /// `verhoeff`: dihedral-group based, same guarantees as Damm.
+    Verhoeff,
+    /// `iso7064_mod11_2`: ISO 7064 MOD 11-2 (ISNI, ORCID); 10 is written `X`.
+    Iso7064Mod11_2,
+    /// `iso7064_mod97_10`: ISO 7064 MOD 97-10 (IBAN); always two digits.
+    Iso7064Mod97_10,
+}
+
+impl CheckDigitAlgorithm {
+    pub fn from_name(name: &str) -> Option<Self> {
+        let algorithm = match name {
+            "luhn" => CheckDigitAlgorithm::Luhn,
+            "mod11" => CheckDigitAlgorithm::Mod11,
+            "damm" => CheckDigitAlgorithm::Damm,
+            "verhoeff" => CheckDigitAlgorithm::Verhoeff,
+            "iso7064_mod11_2" => CheckDigitAlgorithm::Iso7064Mod11_2,
+            "iso7064_mod97_10" => CheckDigitAlgorithm::Iso7064Mod97_10,
+            _ => return None,
+        };
+        Some(algorithm)
+    }
+
+    /// Number of characters the check takes in the rendered number.
+    pub fn width(self) -> usize {
+        match self {
+            CheckDigitAlgorithm::Iso7064Mod97_10 => 2,
+            _ => 1,
+        }
+    }
+
+    /// Regex fragment matching a rendered check of this algorithm.
+    pub fn pattern(self) -> &'static str {
+        match self {
+            CheckDigitAlgorithm::Mod11 | CheckDigitAlgorithm::Iso7064Mod11_2 => "[0-9X]",
+            CheckDigitAlgorithm::Iso7064Mod97_10 => "[0-9]{2}",
+            _ => "[0-9]",
+        }
+    }
+
+    /// Computes the check value over `input`.
+    pub fn compute(self, input: &str) -> u32 {
+        let digits = input.bytes().filter(u8::is_ascii_digit).map(|b| (b - b'0') as u32);
+        match self {
+            CheckDigitAlgorithm::Luhn => {
+                let sum: u32 = digits
+                    .rev()
+                    .enumerate()
+                    .map(|(i, d)| if i % 2 == 0 { if d * 2 > 9 { d * 2 - 9 } else { d * 2 } } else { d })
+                    .sum();
+                (10 - sum % 10) % 10
+            }
+            CheckDigitAlgorithm::Mod11 => {
+                let sum: u32 = digits.rev().enumerate().map(|(i, d)| d * (2 + (i as u32 % 6))).sum();
+                (11 - sum % 11) % 11
+            }
+            CheckDigitAlgorithm::Damm => digits.fold(0, |interim, d| DAMM_TABLE[interim as usize][d as usize] as u32),
+            CheckDigitAlgorithm::Verhoeff => {
+                let digits: Vec<u32> = digits.collect();
+                let checksum = digits.iter().rev().enumerate().fold(0u8, |c, (i, &d)| {
+                    VERHOEFF_MULTIPLY[c as usize][VERHOEFF_PERMUTE[(i + 1) % 8][d as usize] as usize]
+                });
+                VERHOEFF_INVERSE[checksum as usize] as u32
+            }
+            CheckDigitAlgorithm::Iso7064Mod11_2 => {
+                let product = digits.fold(0, |p, d| (p + d) * 2 % 11);
+                (12 - product) % 11
+            }
+            CheckDigitAlgorithm::Iso7064Mod97_10 => {
+                // Folds the number into the remainder piece by piece, so arbitrarily long
+                // inputs never overflow.
+                let remainder = input.chars().fold(0u32, |r, c| match c.to_digit(36) {
+                    Some(v) if v < 10 => (r * 10 + v) % 97,
+                    Some(v) => (r * 100 + v) % 97,
+                    None => r,
+                });
+                98 - (remainder * 100 % 97)
+            }
+        }
+    }
+
+    /// Appends a check value computed by `compute` in its rendered form.
+    pub fn write(self, value: u32, out: &mut String) {
+        match self {
+            CheckDigitAlgorithm::Iso7064Mod97_10 => {
+                out.push(char::from_digit(value / 10, 10).unwrap_or('0'));
+                out.push(char::from_digit(value % 10, 10).unwrap_or('0'));
+            }
+            _ if value == 10 => out.push('X'),
+            _ => out.push(char::from_digit(value, 10).unwrap_or('0')),
+        }
+    }
+
+    /// Renders the check for `input` as it would appear in a number.
+    pub fn render(self, input: &str) -> String {
+        let mut out = String::with_capacity(self.width());
+        self.write(self.compute(input), &mut out);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_luhn() {
+        assert_eq!(CheckDigitAlgorithm::Luhn.render("7992739871"), "3");
+        assert_eq!(CheckDigitAlgorithm::Luhn.render("799-273-987-1"), "3");
+    }
+
+    #[test]
+    fn test_mod11() {
+        assert_eq!(CheckDigitAlgorithm::Mod11.render("036532"), "7");
+        assert_eq!(CheckDigitAlgorithm::Mod11.render("12345"), "5");
+    }
+
+    #[test]
+    fn test_damm() {
+        assert_eq!(CheckDigitAlgorithm::Damm.render("572"), "4");
+    }
+
+    #[test]
+    fn test_verhoeff() {
+        assert_eq!(CheckDigitAlgorithm::Verhoeff.render("236"), "3");
+        assert_eq!(CheckDigitAlgorithm::Verhoeff.render("12345"), "1");
+        assert_eq!(CheckDigitAlgorithm::Verhoeff.render("75872"), "2");
+    }
+
+    #[test]
+    fn test_iso7064_mod11_2() {
+        assert_eq!(CheckDigitAlgorithm::Iso7064Mod11_2.render("079"), "X");
+        assert_eq!(CheckDigitAlgorithm::Iso7064Mod11_2.render("0794"), "0");
+    }
+
+    #[test]
+    fn test_iso7064_mod97_10() {
+        assert_eq!(CheckDigitAlgorithm::Iso7064Mod97_10.render("794"), "44");
+        assert_eq!(CheckDigitAlgorithm::Iso7064Mod97_10.render("3214282912345698765432161182"), "95");
+    }
+
+    #[test]
+    fn test_iso7064_mod97_10_folds_letters() {
+        // IBAN GB82 WEST 1234 5698 7654 32: BBAN + country code, check digits recomputed.
+        assert_eq!(CheckDigitAlgorithm::Iso7064Mod97_10.render("WEST12345698765432GB"), "82");
+    }
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(CheckDigitAlgorithm::from_name("luhn"), Some(CheckDigitAlgorithm::Luhn));
+        assert_eq!(CheckDigitAlgorithm::from_name("iso7064_mod97_10"), Some(CheckDigitAlgorithm::Iso7064Mod97_10));
+        assert_eq!(CheckDigitAlgorithm::from_name("crc32"), None);
+    }
+}
@@ -0,0 +1,1119 @@
+//! Parsed form of a prefix rule's `format` string.
+//!
+//! A template is parsed once, when its rule is registered or loaded, into a list of
+//! literal segments and typed placeholders. Rendering then walks that list and writes
+//! into a single pre-sized `String` instead of rescanning the format on every call.
+//!
+//! The parser is strict: unknown placeholders, unbalanced braces, a second sequence
+//! placeholder and empty templates are all rejected with the offending token and its
+//! byte offset. Literal braces are written as `{{` and `}}`.
+
+use chrono::format::{Fixed, Item, Numeric, StrftimeItems};
+use chrono::{DateTime, Datelike};
+use chrono_tz::Tz;
+use regex::Regex;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use thiserror::Error;
+
+use crate::check_digit::CheckDigitAlgorithm;
+use crate::sequence_encoding::SequenceEncoding;
+use crate::sequence_permutation::{ScrambleKey, SequencePermutation};
+
+/// Widest sequence placeholder accepted, and the width reserved for the sequence when
+/// sizing the output buffer; enough for any `u64`.
+const MAX_SEQUENCE_DIGITS: usize = 20;
+
+/// Room reserved for a template variable's value when sizing the output buffer.
+const VARIABLE_LEN_HINT: usize = 8;
+
+/// Room reserved for suffixes appended after rendering (e.g. the `-NP` partition tag).
+const SUFFIX_RESERVE: usize = 3;
+
+/// The fixed date/time tokens. Names follow the usual `yyyy-MM-dd HH:mm` pattern letters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DateToken {
+    Year,
+    TwoDigitYear,
+    Month,
+    Day,
+    DayOfYear,
+    IsoWeek,
+    Hour,
+    Minute,
+    Second,
+    Quarter,
+}
+
+impl DateToken {
+    fn from_name(name: &str) -> Option<Self> {
+        let token = match name {
+            "year" | "yyyy" => DateToken::Year,
+            "yy" => DateToken::TwoDigitYear,
+            "MM" => DateToken::Month,
+            "dd" => DateToken::Day,
+            "DDD" => DateToken::DayOfYear,
+            "ww" => DateToken::IsoWeek,
+            "HH" => DateToken::Hour,
+            "mm" => DateToken::Minute,
+            "ss" => DateToken::Second,
+            "Q" => DateToken::Quarter,
+            _ => return None,
+        };
+        Some(token)
+    }
+
+    fn rendered_len(self) -> usize {
+        match self {
+            DateToken::Year => 4,
+            DateToken::DayOfYear => 3,
+            DateToken::Quarter => 1,
+            _ => 2,
+        }
+    }
+
+    /// Regex fragment matching any rendering of this token.
+    fn pattern(self) -> &'static str {
+        match self {
+            DateToken::Year => "[0-9]{4}",
+            DateToken::DayOfYear => "[0-9]{3}",
+            DateToken::Quarter => "[1-4]",
+            _ => "[0-9]{2}",
+        }
+    }
+
+    fn write(self, out: &mut String, now: &DateTime<Tz>) -> fmt::Result {
+        match self {
+            DateToken::Year => write!(out, "{}", now.format("%Y")),
+            DateToken::TwoDigitYear => write!(out, "{}", now.format("%y")),
+            DateToken::Month => write!(out, "{}", now.format("%m")),
+            DateToken::Day => write!(out, "{}", now.format("%d")),
+            DateToken::DayOfYear => write!(out, "{}", now.format("%j")),
+            DateToken::IsoWeek => write!(out, "{}", now.format("%V")),
+            DateToken::Hour => write!(out, "{}", now.format("%H")),
+            DateToken::Minute => write!(out, "{}", now.format("%M")),
+            DateToken::Second => write!(out, "{}", now.format("%S")),
+            DateToken::Quarter => write!(out, "{}", (now.month() - 1) / 3 + 1),
+        }
+    }
+}
+
+/// Calendar fields a template can print, used to check that numbers from different
+/// reset periods cannot collide.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalendarField {
+    Year,
+    Month,
+    Day,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Placeholder {
+    /// `{prefix}`: the prefix key the number is generated for.
+    Prefix,
+    /// `{SEQ}` / `{SEQ:N}`: the zero-padded sequence value. When present, `N` is the
+    /// authoritative width; a bare `{SEQ}` falls back to the rule's `seq_length`.
+    /// `{SEQ36:N}`, `{SEQ32C:N}` and `{SEQX:N}` pick another encoding, upper case unless
+    /// written `{SEQ36:N:lower}`.
+    Sequence { width: Option<u32>, encoding: SequenceEncoding, lowercase: bool },
+    /// One of the fixed date/time tokens.
+    Date(DateToken),
+    /// `{date:<strftime>}`: a free-form, pre-validated strftime pattern.
+    Strftime(String),
+    /// `{CHECK:<algorithm>}`: a check digit over everything rendered before it.
+    Check(CheckDigitAlgorithm),
+    /// `{<name>}`: the caller-supplied value of a variable declared on the rule.
+    Variable(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// Everything a template needs to know about the number being rendered.
+pub struct RenderContext<'a> {
+    pub prefix: &'a str,
+    pub sequence: u64,
+    /// Width used for a bare `{SEQ}`.
+    pub seq_length: u32,
+    /// The moment of issue, already converted into the rule's time zone.
+    pub now: DateTime<Tz>,
+    /// When set, the sequence is rendered through a keyed permutation of its width.
+    pub scramble_key: Option<ScrambleKey>,
+    /// Values of the rule's template variables, by name.
+    pub variables: &'a HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TemplateErrorReason {
+    EmptyTemplate,
+    UnbalancedBrace,
+    UnknownPlaceholder,
+    UnexpectedArgument,
+    DuplicateSequence,
+    InvalidSequenceWidth,
+    InvalidStrftime,
+    MissingPlaceholder,
+    MissingSequenceWidth,
+    SequenceLengthMismatch,
+    UnknownCheckAlgorithm,
+    InvalidVariable,
+    InvalidScope,
+    AmbiguousScope,
+}
+
+impl fmt::Display for TemplateErrorReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            TemplateErrorReason::EmptyTemplate => "empty template",
+            TemplateErrorReason::UnbalancedBrace => "unbalanced brace",
+            TemplateErrorReason::UnknownPlaceholder => "unknown placeholder",
+            TemplateErrorReason::UnexpectedArgument => "placeholder does not take an argument",
+            TemplateErrorReason::DuplicateSequence => "duplicate sequence placeholder",
+            TemplateErrorReason::InvalidSequenceWidth => "sequence width must be a whole number from 1 to 20",
+            TemplateErrorReason::InvalidStrftime => "invalid strftime pattern",
+            TemplateErrorReason::MissingPlaceholder => "missing required placeholder",
+            TemplateErrorReason::MissingSequenceWidth => "sequence width not given by the template or seqLength",
+            TemplateErrorReason::SequenceLengthMismatch => "sequence width does not match seqLength",
+            TemplateErrorReason::UnknownCheckAlgorithm => "unknown check digit algorithm",
+            TemplateErrorReason::InvalidVariable => "invalid template variable declaration",
+            TemplateErrorReason::InvalidScope => "only date tokens and template variables can scope the sequence",
+            TemplateErrorReason::AmbiguousScope => "scoped variable must be separated from other variables by literal text",
+        };
+        f.write_str(reason)
+    }
+}
+
+/// Why a format string was rejected, and where.
+#[derive(Debug, Clone, PartialEq, Eq, Error, Serialize)]
+#[error("{reason} `{token}` at byte {offset}")]
+pub struct TemplateError {
+    /// The offending text, e.g. `{yaer}` or a lone `}`.
+    pub token: String,
+    /// Byte offset of `token` within the format string.
+    pub offset: usize,
+    pub reason: TemplateErrorReason,
+}
+
+impl TemplateError {
+    pub fn new(token: impl Into<String>, offset: usize, reason: TemplateErrorReason) -> Self {
+        TemplateError { token: token.into(), offset, reason }
+    }
+}
+
+/// A template's verification regex for one prefix and sequence width. Compiling it is
+/// far more expensive than matching, so rules build it once (see `PrefixRule::compile`).
+#[derive(Debug, Clone)]
+pub struct Verifier {
+    prefix: String,
+    seq_length: u32,
+    regex: Regex,
+}
+
+impl Verifier {
+    /// Whether this verifier was built for `prefix` and `seq_length`.
+    pub fn is_for(&self, prefix: &str, seq_length: u32) -> bool {
+        self.prefix == prefix && self.seq_length == seq_length
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatTemplate {
+    segments: Vec<Segment>,
+    /// Where the sequence placeholder sits in the source, as `(offset, token)`, kept
+    /// for error reporting once the segments are built.
+    sequence_token: Option<(usize, String)>,
+    /// Length of the source format; missing placeholders are reported at its end.
+    source_len: usize,
+    /// Upper bound of the rendered length, excluding the prefix, used to size the buffer.
+    capacity_hint: usize,
+}
+
+impl FormatTemplate {
+    /// Parses a format string, rejecting anything that is not a well-formed template.
+    pub fn parse(format: &str) -> Result<Self, TemplateError> {
+        Self::parse_with_variables::<&str>(format, &[])
+    }
+
+    /// Same as `parse`, but also accepts `{name}` for each declared variable name.
+    pub fn parse_with_variables<S: AsRef<str>>(format: &str, variables: &[S]) -> Result<Self, TemplateError> {
+        Self::parse_inner(format, variables, true)
+    }
+
+    /// Parses a format stored before the parser became strict. Whatever the strict parser
+    /// would reject (unknown placeholders such as `{yaer}`, stray braces, a second sequence
+    /// placeholder) is kept as literal text, which is how such formats used to render.
+    pub fn parse_lenient<S: AsRef<str>>(format: &str, variables: &[S]) -> Self {
+        Self::parse_inner(format, variables, false).expect("lenient parsing never fails")
+    }
+
+    fn parse_inner<S: AsRef<str>>(format: &str, variables: &[S], strict: bool) -> Result<Self, TemplateError> {
+        if format.is_empty() && strict {
+            return Err(TemplateError::new("", 0, TemplateErrorReason::EmptyTemplate));
+        }
+
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut sequence_token: Option<(usize, String)> = None;
+        let mut offset = 0;
+
+        while offset < format.len() {
+            let rest = &format[offset..];
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                literal.push_str(&rest[..1]);
+                offset += 2;
+            } else if rest.starts_with('}') {
+                if strict {
+                    return Err(TemplateError::new("}", offset, TemplateErrorReason::UnbalancedBrace));
+                }
+                literal.push('}');
+                offset += 1;
+            } else if let Some(after_open) = rest.strip_prefix('{') {
+                // A placeholder runs to the next `}`; another `{` before it means this one
+                // was never closed.
+                let close = match after_open.find(['{', '}']) {
+                    Some(index) if after_open.as_bytes()[index] == b'}' => index + 1,
+                    _ if strict => return Err(TemplateError::new("{", offset, TemplateErrorReason::UnbalancedBrace)),
+                    _ => {
+                        literal.push('{');
+                        offset += 1;
+                        continue;
+                    }
+                };
+                let token = &rest[..=close];
+                let placeholder = match resolve_placeholder(&rest[1..close], variables) {
+                    Ok(Placeholder::Sequence { .. }) if !strict && sequence_token.is_some() => None,
+                    Ok(placeholder) => Some(placeholder),
+                    Err(reason) if strict => return Err(TemplateError::new(token, offset, reason)),
+                    Err(_) => None,
+                };
+                let Some(placeholder) = placeholder else {
+                    literal.push_str(token);
+                    offset += token.len();
+                    continue;
+                };
+                if matches!(placeholder, Placeholder::Sequence { .. }) {
+                    if sequence_token.is_some() {
+                        return Err(TemplateError::new(token, offset, TemplateErrorReason::DuplicateSequence));
+                    }
+                    sequence_token = Some((offset, token.to_string()));
+                }
+                if !literal.is_empty() {
+                    segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                }
+                segments.push(Segment::Placeholder(placeholder));
+                offset += token.len();
+            } else {
+                let next = rest.find(['{', '}']).unwrap_or(rest.len());
+                literal.push_str(&rest[..next]);
+                offset += next;
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        let capacity_hint = segments.iter().map(segment_len_hint).sum::<usize>() + SUFFIX_RESERVE;
+        Ok(FormatTemplate { segments, sequence_token, source_len: format.len(), capacity_hint })
+    }
+
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    pub fn placeholders(&self) -> impl Iterator<Item = &Placeholder> {
+        self.segments.iter().filter_map(|segment| match segment {
+            Segment::Placeholder(placeholder) => Some(placeholder),
+            Segment::Literal(_) => None,
+        })
+    }
+
+    /// Width declared by the sequence placeholder: `None` without a sequence placeholder,
+    /// `Some(None)` for a bare `{SEQ}`.
+    pub fn sequence_width(&self) -> Option<Option<u32>> {
+        self.placeholders().find_map(|placeholder| match placeholder {
+            Placeholder::Sequence { width, .. } => Some(*width),
+            _ => None,
+        })
+    }
+
+    /// Number of distinct values the sequence placeholder can render at its width, or
+    /// `None` when the template has no sequence or every `u64` fits.
+    pub fn sequence_capacity(&self, seq_length: u32) -> Option<u64> {
+        self.placeholders().find_map(|placeholder| match placeholder {
+            Placeholder::Sequence { width, encoding, .. } => encoding.capacity(width.unwrap_or(seq_length)),
+            _ => None,
+        })
+    }
+
+    /// Reconciles the template's sequence width with an explicitly requested
+    /// `seq_length`. The template wins when it carries a width, so `seq_length` may be
+    /// omitted; giving both is only allowed when they agree.
+    pub fn resolve_seq_length(&self, seq_length: Option<u32>) -> Result<u32, TemplateError> {
+        if self.sequence_token.is_none() {
+            return Err(self.missing_placeholder("{SEQ:N}"));
+        }
+        let error = |reason| self.sequence_error(reason);
+
+        match (self.sequence_width().flatten(), seq_length) {
+            (Some(width), None) => Ok(width),
+            (Some(width), Some(length)) if width == length => Ok(width),
+            (Some(_), Some(_)) => Err(error(TemplateErrorReason::SequenceLengthMismatch)),
+            (None, Some(length)) if length > MAX_SEQUENCE_DIGITS as u32 => {
+                Err(error(TemplateErrorReason::InvalidSequenceWidth))
+            }
+            (None, Some(length)) if length > 0 => Ok(length),
+            (None, _) => Err(error(TemplateErrorReason::MissingSequenceWidth)),
+        }
+    }
+
+    /// Error reported at the sequence placeholder, or a missing-placeholder error when the
+    /// template has none.
+    pub fn sequence_error(&self, reason: TemplateErrorReason) -> TemplateError {
+        match &self.sequence_token {
+            Some((offset, token)) => TemplateError::new(token.clone(), *offset, reason),
+            None => self.missing_placeholder("{SEQ:N}"),
+        }
+    }
+
+    /// Error for a required placeholder the template lacks, reported at the end of the format.
+    pub fn missing_placeholder(&self, token: &str) -> TemplateError {
+        TemplateError::new(token, self.source_len, TemplateErrorReason::MissingPlaceholder)
+    }
+
+    /// Whether a rendered number always shows `field`. A day of year counts as both month
+    /// and day; ISO week-based years do not count as the calendar year.
+    pub fn renders(&self, field: CalendarField) -> bool {
+        self.placeholders().any(|placeholder| match placeholder {
+            Placeholder::Date(token) => matches!(
+                (token, field),
+                (DateToken::Year | DateToken::TwoDigitYear, CalendarField::Year)
+                    | (DateToken::Month, CalendarField::Month)
+                    | (DateToken::Day, CalendarField::Day)
+                    | (DateToken::DayOfYear, CalendarField::Month | CalendarField::Day)
+            ),
+            Placeholder::Strftime(pattern) => StrftimeItems::new(pattern).any(|item| match item {
+                Item::Numeric(numeric, _) => matches!(
+                    (numeric, field),
+                    (Numeric::Year | Numeric::YearMod100, CalendarField::Year)
+                        | (Numeric::Month, CalendarField::Month)
+                        | (Numeric::Day, CalendarField::Day)
+                        | (Numeric::Ordinal, CalendarField::Month | CalendarField::Day)
+                ),
+                Item::Fixed(Fixed::ShortMonthName | Fixed::LongMonthName) => field == CalendarField::Month,
+                _ => false,
+            }),
+            _ => false,
+        })
+    }
+
+    /// Builds the template that renders a sequence scope: the placeholders `names`
+    /// (as written inside the braces) joined by `:`. Each one has to be a date token,
+    /// strftime pattern or template variable that this template renders too, so numbers
+    /// from different counters can never collide. Errors are reported at the end of the
+    /// format, like missing placeholders.
+    pub fn scope_template<S: AsRef<str>, V: AsRef<str>>(&self, names: &[S], variables: &[V]) -> Result<FormatTemplate, TemplateError> {
+        let mut segments = Vec::with_capacity(names.len() * 2);
+        for name in names {
+            let token = format!("{{{}}}", name.as_ref());
+            let placeholder = resolve_placeholder(name.as_ref(), variables)
+                .map_err(|reason| TemplateError::new(token.clone(), self.source_len, reason))?;
+            if !matches!(placeholder, Placeholder::Date(_) | Placeholder::Strftime(_) | Placeholder::Variable(_)) {
+                return Err(TemplateError::new(token, self.source_len, TemplateErrorReason::InvalidScope));
+            }
+            if !self.placeholders().any(|used| used == &placeholder) {
+                return Err(self.missing_placeholder(&token));
+            }
+            if !segments.is_empty() {
+                segments.push(Segment::Literal(":".to_string()));
+            }
+            segments.push(Segment::Placeholder(placeholder));
+        }
+        let capacity_hint = segments.iter().map(segment_len_hint).sum();
+        Ok(FormatTemplate { segments, sequence_token: None, source_len: 0, capacity_hint })
+    }
+
+    /// Checks that no variable in the scope `names` runs into another variable: with
+    /// `{region}{dept}`, the scopes (`AB`, `C`) and (`A`, `BC`) would count separately but
+    /// render the same text. Reported at the end of the format, like `scope_template`.
+    pub fn check_scope_separated<S: AsRef<str>>(&self, names: &[S]) -> Result<(), TemplateError> {
+        let scoped = |name: &str| names.iter().any(|scoped| scoped.as_ref() == name);
+        // The variable seen since the last literal, and whether it is scoped.
+        let mut previous: Option<bool> = None;
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(_) => previous = None,
+                Segment::Placeholder(Placeholder::Variable(name)) => {
+                    let is_scoped = scoped(name);
+                    if previous.is_some_and(|previous_scoped| previous_scoped || is_scoped) {
+                        let token = format!("{{{}}}", name);
+                        return Err(TemplateError::new(token, self.source_len, TemplateErrorReason::AmbiguousScope));
+                    }
+                    previous = Some(is_scoped);
+                }
+                Segment::Placeholder(_) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders a template built by `scope_template` as part of a counter key. Each
+    /// rendered value has `%` and `:` percent-encoded, so a value containing the `:`
+    /// separator cannot make two scopes share a key; other values render unchanged.
+    pub fn render_scope(&self, ctx: &RenderContext) -> Result<String, String> {
+        let mut out = String::with_capacity(self.capacity_hint);
+        let mut value = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => out.push_str(text),
+                Segment::Placeholder(_) => {
+                    value.clear();
+                    write_segment(segment, &mut value, ctx)?;
+                    for c in value.chars() {
+                        match c {
+                            '%' => out.push_str("%25"),
+                            ':' => out.push_str("%3A"),
+                            c => out.push(c),
+                        }
+                    }
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Renders the template into a freshly allocated, pre-sized `String`.
+    pub fn render(&self, ctx: &RenderContext) -> Result<String, String> {
+        let mut out = String::with_capacity(self.capacity_hint + ctx.prefix.len());
+        self.write_segments(&mut out, ctx)?;
+        Ok(out)
+    }
+
+    /// Builds the matcher `verify` and `extract_sequence` use for numbers of `prefix`.
+    /// `None` when the pattern is too large to compile, e.g. for an absurd `seq_length`.
+    pub fn verifier(&self, prefix: &str, seq_length: u32) -> Option<Verifier> {
+        let regex = Regex::new(&self.verification_pattern(prefix, seq_length)).ok()?;
+        Some(Verifier { prefix: prefix.to_string(), seq_length, regex })
+    }
+
+    /// Checks that `number` has the shape this template renders and that every check
+    /// digit in it is correct. Date fields are matched by shape only, since the moment of
+    /// issue is not known.
+    pub fn verify(&self, verifier: &Verifier, number: &str) -> bool {
+        let Some(captures) = self.capture(verifier, number) else {
+            return false;
+        };
+        captures.iter().all(|(placeholder, start, text)| match placeholder {
+            Placeholder::Check(algorithm) => algorithm.render(&number[..*start]) == *text,
+            _ => true,
+        })
+    }
+
+    /// Reads the sequence value back out of a rendered `number`, undoing the encoding
+    /// and, given the prefix's `scramble_key`, the permutation. `None` when the number
+    /// does not have the template's shape.
+    pub fn extract_sequence(&self, verifier: &Verifier, number: &str, scramble_key: Option<ScrambleKey>) -> Option<u64> {
+        let captures = self.capture(verifier, number)?;
+        let (placeholder, _, text) = captures.into_iter().find(|(p, _, _)| matches!(p, Placeholder::Sequence { .. }))?;
+        let Placeholder::Sequence { width, encoding, .. } = placeholder else {
+            return None;
+        };
+        let value = encoding.decode(text)?;
+        match scramble_key {
+            Some(key) => SequencePermutation::new(key, encoding.capacity(width.unwrap_or(verifier.seq_length))).invert(value),
+            None => Some(value),
+        }
+    }
+
+    /// Matches `number` against the template and returns the sequence and check digit
+    /// placeholders with the byte offset and text each matched, in template order.
+    fn capture<'n>(&self, verifier: &Verifier, number: &'n str) -> Option<Vec<(&Placeholder, usize, &'n str)>> {
+        let captures = verifier.regex.captures(number)?;
+        let captured = self
+            .placeholders()
+            .filter(|placeholder| matches!(placeholder, Placeholder::Sequence { .. } | Placeholder::Check(_)));
+        captured
+            .zip(captures.iter().skip(1))
+            .map(|(placeholder, group)| group.map(|m| (placeholder, m.start(), m.as_str())))
+            .collect()
+    }
+
+    /// Anchored regex matching any rendering of the template; the sequence and each
+    /// check digit are capture groups, in template order.
+    fn verification_pattern(&self, prefix: &str, seq_length: u32) -> String {
+        let mut pattern = String::from("^");
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => pattern.push_str(&regex::escape(text)),
+                Segment::Placeholder(Placeholder::Prefix) => pattern.push_str(&regex::escape(prefix)),
+                Segment::Placeholder(Placeholder::Sequence { width, encoding, lowercase }) => {
+                    let _ = write!(pattern, "({}{{{},}})", encoding.digit_class(*lowercase), width.unwrap_or(seq_length));
+                }
+                Segment::Placeholder(Placeholder::Date(token)) => pattern.push_str(token.pattern()),
+                Segment::Placeholder(Placeholder::Strftime(_) | Placeholder::Variable(_)) => pattern.push_str(".+?"),
+                Segment::Placeholder(Placeholder::Check(algorithm)) => {
+                    let _ = write!(pattern, "({})", algorithm.pattern());
+                }
+            }
+        }
+        pattern.push('$');
+        pattern
+    }
+
+    fn write_segments(&self, out: &mut String, ctx: &RenderContext) -> Result<(), String> {
+        for segment in &self.segments {
+            write_segment(segment, out, ctx)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_segment(segment: &Segment, out: &mut String, ctx: &RenderContext) -> Result<(), String> {
+    let render_error = |e: fmt::Error| format!("Failed to render number: {}", e);
+    match segment {
+        Segment::Literal(text) => out.push_str(text),
+        Segment::Placeholder(Placeholder::Prefix) => out.push_str(ctx.prefix),
+        Segment::Placeholder(Placeholder::Sequence { width, encoding, lowercase }) => {
+            let width = width.unwrap_or(ctx.seq_length);
+            let capacity = encoding.capacity(width);
+            let overflow = || format!("Sequence {} does not fit in {} {:?} digits", ctx.sequence, width, encoding);
+            // Values past the width are rendered longer; the rule's overflow policy
+            // decides beforehand whether that may happen. Scrambled values only stay
+            // unique inside the permuted domain, so they can never widen.
+            let value = match ctx.scramble_key {
+                Some(key) => SequencePermutation::new(key, capacity).permute(ctx.sequence).ok_or_else(overflow)?,
+                None => ctx.sequence,
+            };
+            encoding.write(value, width as usize, *lowercase, out);
+        }
+        Segment::Placeholder(Placeholder::Date(token)) => token.write(out, &ctx.now).map_err(render_error)?,
+        Segment::Placeholder(Placeholder::Strftime(pattern)) => {
+            write!(out, "{}", ctx.now.format(pattern)).map_err(render_error)?
+        }
+        Segment::Placeholder(Placeholder::Check(algorithm)) => {
+            let value = algorithm.compute(out);
+            algorithm.write(value, out);
+        }
+        Segment::Placeholder(Placeholder::Variable(name)) => match ctx.variables.get(name) {
+            Some(value) => out.push_str(value),
+            None => return Err(format!("Missing value for template variable `{}`", name)),
+        },
+    }
+    Ok(())
+}
+
+/// Placeholders a deployment requires every format to carry on top of the sequence
+/// placeholder, which is always required. The default requires nothing else.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FormatPolicy {
+    required: Vec<(String, Placeholder)>,
+}
+
+impl FormatPolicy {
+    /// Builds a policy from placeholder names as they appear inside the braces,
+    /// e.g. `["year"]`. Unknown names are rejected like they would be in a format.
+    pub fn require<S: AsRef<str>>(names: &[S]) -> Result<Self, TemplateError> {
+        let mut required = Vec::with_capacity(names.len());
+        for name in names {
+            let name = name.as_ref();
+            let token = format!("{{{}}}", name);
+            let placeholder = parse_placeholder(name).map_err(|reason| TemplateError::new(token.clone(), 0, reason))?;
+            required.push((token, placeholder));
+        }
+        Ok(FormatPolicy { required })
+    }
+
+    /// Reports the first required placeholder the template lacks.
+    pub fn check(&self, template: &FormatTemplate) -> Result<(), TemplateError> {
+        for (token, required) in &self.required {
+            if !template.placeholders().any(|placeholder| placeholder == required) {
+                return Err(template.missing_placeholder(token));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Interprets the text between a pair of braces.
+fn parse_placeholder(body: &str) -> Result<Placeholder, TemplateErrorReason> {
+    let (name, argument) = match body.split_once(':') {
+        Some((name, argument)) => (name, Some(argument)),
+        None => (body, None),
+    };
+
+    if let Some(encoding) = SequenceEncoding::from_placeholder_name(name) {
+        return parse_sequence(encoding, argument);
+    }
+
+    let placeholder = match name {
+        "prefix" => Placeholder::Prefix,
+        "date" => Placeholder::Strftime(validate_strftime(argument.unwrap_or_default())?),
+        "CHECK" => match argument.and_then(CheckDigitAlgorithm::from_name) {
+            Some(algorithm) => Placeholder::Check(algorithm),
+            None => return Err(TemplateErrorReason::UnknownCheckAlgorithm),
+        },
+        _ => match DateToken::from_name(name) {
+            Some(token) => Placeholder::Date(token),
+            None => return Err(TemplateErrorReason::UnknownPlaceholder),
+        },
+    };
+    if argument.is_some() && matches!(placeholder, Placeholder::Prefix | Placeholder::Date(_)) {
+        return Err(TemplateErrorReason::UnexpectedArgument);
+    }
+    Ok(placeholder)
+}
+
+/// Like `parse_placeholder`, falling back to a declared variable for unknown names.
+fn resolve_placeholder<V: AsRef<str>>(body: &str, variables: &[V]) -> Result<Placeholder, TemplateErrorReason> {
+    match parse_placeholder(body) {
+        Err(TemplateErrorReason::UnknownPlaceholder) if variables.iter().any(|v| v.as_ref() == body) => {
+            Ok(Placeholder::Variable(body.to_string()))
+        }
+        result => result,
+    }
+}
+
+/// Parses the `N` or `N:lower` / `N:upper` argument of a sequence placeholder.
+fn parse_sequence(encoding: SequenceEncoding, argument: Option<&str>) -> Result<Placeholder, TemplateErrorReason> {
+    let Some(argument) = argument else {
+        return Ok(Placeholder::Sequence { width: None, encoding, lowercase: false });
+    };
+    let (digits, case) = match argument.split_once(':') {
+        Some((digits, case)) => (digits, Some(case)),
+        None => (argument, None),
+    };
+    // Decimal digits have no case, so only the other encodings accept one.
+    let lowercase = match case {
+        None => false,
+        Some(_) if encoding == SequenceEncoding::Decimal => return Err(TemplateErrorReason::UnexpectedArgument),
+        Some("upper") => false,
+        Some("lower") => true,
+        Some(_) => return Err(TemplateErrorReason::UnexpectedArgument),
+    };
+    // Every u64 fits in 20 digits; anything wider is only zero padding, and a huge width
+    // would be allocated on every render.
+    match digits.parse::<u32>() {
+        Ok(width) if (1..=MAX_SEQUENCE_DIGITS as u32).contains(&width) && digits.bytes().all(|b| b.is_ascii_digit()) => {
+            Ok(Placeholder::Sequence { width: Some(width), encoding, lowercase })
+        }
+        _ => Err(TemplateErrorReason::InvalidSequenceWidth),
+    }
+}
+
+/// chrono panics when displaying an invalid pattern, so it is checked item by item up front.
+fn validate_strftime(pattern: &str) -> Result<String, TemplateErrorReason> {
+    if pattern.is_empty() || StrftimeItems::new(pattern).any(|item| matches!(item, Item::Error)) {
+        return Err(TemplateErrorReason::InvalidStrftime);
+    }
+    Ok(pattern.to_string())
+}
+
+fn segment_len_hint(segment: &Segment) -> usize {
+    match segment {
+        Segment::Literal(text) => text.len(),
+        Segment::Placeholder(Placeholder::Prefix) => 0,
+        Segment::Placeholder(Placeholder::Sequence { width, .. }) => {
+            MAX_SEQUENCE_DIGITS.max(width.unwrap_or_default() as usize)
+        }
+        Segment::Placeholder(Placeholder::Date(token)) => token.rendered_len(),
+        // Most strftime specifiers expand to at most a handful of characters.
+        Segment::Placeholder(Placeholder::Strftime(pattern)) => pattern.len() * 2,
+        Segment::Placeholder(Placeholder::Check(algorithm)) => algorithm.width(),
+        Segment::Placeholder(Placeholder::Variable(_)) => VARIABLE_LEN_HINT,
+    }
+}
+
+/// Whether `name` is taken by a built-in placeholder, so a variable cannot use it.
+pub fn is_reserved_name(name: &str) -> bool {
+    !matches!(parse_placeholder(name), Err(TemplateErrorReason::UnknownPlaceholder))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse_segments() {
+        let template = FormatTemplate::parse("INV-{yyyy}{MM}-{SEQ:5}").unwrap();
+        assert_eq!(
+            template.segments(),
+            &[
+                Segment::Literal("INV-".to_string()),
+                Segment::Placeholder(Placeholder::Date(DateToken::Year)),
+                Segment::Placeholder(Placeholder::Date(DateToken::Month)),
+                Segment::Literal("-".to_string()),
+                Segment::Placeholder(Placeholder::Sequence { width: Some(5), encoding: SequenceEncoding::Decimal, lowercase: false }),
+            ]
+        );
+    }
+
+    fn parse_error(format: &str) -> TemplateError {
+        FormatTemplate::parse(format).unwrap_err()
+    }
+
+    #[test]
+    fn test_parse_brace_escapes() {
+        let template = FormatTemplate::parse("{{{SEQ:2}}}").unwrap();
+        assert_eq!(
+            template.segments(),
+            &[
+                Segment::Literal("{".to_string()),
+                Segment::Placeholder(Placeholder::Sequence { width: Some(2), encoding: SequenceEncoding::Decimal, lowercase: false }),
+                Segment::Literal("}".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_empty_template() {
+        assert_eq!(parse_error(""), TemplateError::new("", 0, TemplateErrorReason::EmptyTemplate));
+    }
+
+    #[test]
+    fn test_parse_rejects_unknown_placeholder() {
+        assert_eq!(
+            parse_error("INV-{yaer}-{SEQ:4}"),
+            TemplateError::new("{yaer}", 4, TemplateErrorReason::UnknownPlaceholder)
+        );
+        assert_eq!(parse_error("A{}"), TemplateError::new("{}", 1, TemplateErrorReason::UnknownPlaceholder));
+    }
+
+    #[test]
+    fn test_parse_rejects_unbalanced_braces() {
+        assert_eq!(parse_error("A-{SEQ:4"), TemplateError::new("{", 2, TemplateErrorReason::UnbalancedBrace));
+        assert_eq!(parse_error("A-{year{SEQ:4}"), TemplateError::new("{", 2, TemplateErrorReason::UnbalancedBrace));
+        assert_eq!(parse_error("A-}{SEQ:4}"), TemplateError::new("}", 2, TemplateErrorReason::UnbalancedBrace));
+    }
+
+    #[test]
+    fn test_parse_lenient_keeps_rejected_tokens_as_literals() {
+        let template = FormatTemplate::parse_lenient::<&str>("INV-{yaer}-}{SEQ:4}{SEQ}{", &[]);
+        assert_eq!(
+            template.segments(),
+            &[
+                Segment::Literal("INV-{yaer}-}".to_string()),
+                Segment::Placeholder(Placeholder::Sequence { width: Some(4), encoding: SequenceEncoding::Decimal, lowercase: false }),
+                Segment::Literal("{SEQ}{".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_duplicate_sequence() {
+        assert_eq!(
+            parse_error("{SEQ:4}-{SEQ}"),
+            TemplateError::new("{SEQ}", 8, TemplateErrorReason::DuplicateSequence)
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_sequence_width() {
+        assert_eq!(parse_error("ERR-{SEQ:0}").reason, TemplateErrorReason::InvalidSequenceWidth);
+        assert_eq!(parse_error("ERR-{SEQ:x}").reason, TemplateErrorReason::InvalidSequenceWidth);
+        assert_eq!(parse_error("ERR-{SEQ:+4}").reason, TemplateErrorReason::InvalidSequenceWidth);
+        assert_eq!(
+            parse_error("ERR-{SEQ:4000000000}"),
+            TemplateError::new("{SEQ:4000000000}", 4, TemplateErrorReason::InvalidSequenceWidth)
+        );
+        assert_eq!(parse_error("ERR-{SEQ:21}").reason, TemplateErrorReason::InvalidSequenceWidth);
+        assert!(FormatTemplate::parse("ERR-{SEQ:20}").is_ok());
+    }
+
+    #[test]
+    fn test_parse_rejects_unexpected_argument() {
+        assert_eq!(
+            parse_error("{year:2}-{SEQ:4}"),
+            TemplateError::new("{year:2}", 0, TemplateErrorReason::UnexpectedArgument)
+        );
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_strftime() {
+        assert_eq!(
+            parse_error("{date:%Q}"),
+            TemplateError::new("{date:%Q}", 0, TemplateErrorReason::InvalidStrftime)
+        );
+        assert_eq!(parse_error("{date:}").reason, TemplateErrorReason::InvalidStrftime);
+    }
+
+    #[test]
+    fn test_parse_offsets_are_bytes() {
+        let error = parse_error("單號-{nope}");
+        assert_eq!(error.offset, "單號-".len());
+    }
+
+    fn render_sequence(format: &str, seq_length: u32, sequence: u64) -> String {
+        let ctx = RenderContext {
+            prefix: "",
+            sequence,
+            seq_length,
+            now: Tz::UTC.with_ymd_and_hms(2026, 10, 16, 0, 0, 0).unwrap(),
+            scramble_key: None,
+            variables: &HashMap::new(),
+        };
+        FormatTemplate::parse(format).unwrap().render(&ctx).unwrap()
+    }
+
+    #[test]
+    fn test_render_placeholder_width_is_authoritative() {
+        assert_eq!(render_sequence("{SEQ:6}", 4, 42), "000042");
+        assert_eq!(render_sequence("{SEQ:2}", 6, 42), "42");
+    }
+
+    #[test]
+    fn test_render_bare_sequence_uses_seq_length() {
+        assert_eq!(render_sequence("{SEQ}", 5, 42), "00042");
+    }
+
+    #[test]
+    fn test_render_sequence_boundary_widths() {
+        assert_eq!(render_sequence("{SEQ:1}", 0, 7), "7");
+        assert_eq!(render_sequence("{SEQ:20}", 0, 1), "00000000000000000001");
+        assert_eq!(render_sequence("{SEQ:20}", 0, u64::MAX), "18446744073709551615");
+    }
+
+    #[test]
+    fn test_resolve_seq_length_from_template_only() {
+        let template = FormatTemplate::parse("A-{SEQ:6}").unwrap();
+        assert_eq!(template.resolve_seq_length(None), Ok(6));
+    }
+
+    #[test]
+    fn test_resolve_seq_length_matching() {
+        let template = FormatTemplate::parse("A-{SEQ:6}").unwrap();
+        assert_eq!(template.resolve_seq_length(Some(6)), Ok(6));
+    }
+
+    #[test]
+    fn test_resolve_seq_length_mismatch() {
+        let template = FormatTemplate::parse("A-{SEQ:6}").unwrap();
+        assert_eq!(
+            template.resolve_seq_length(Some(4)),
+            Err(TemplateError::new("{SEQ:6}", 2, TemplateErrorReason::SequenceLengthMismatch))
+        );
+    }
+
+    #[test]
+    fn test_resolve_seq_length_bare_sequence() {
+        let template = FormatTemplate::parse("A-{SEQ}").unwrap();
+        assert_eq!(template.resolve_seq_length(Some(4)), Ok(4));
+        assert_eq!(
+            template.resolve_seq_length(None),
+            Err(TemplateError::new("{SEQ}", 2, TemplateErrorReason::MissingSequenceWidth))
+        );
+        assert_eq!(template.resolve_seq_length(Some(0)).unwrap_err().reason, TemplateErrorReason::MissingSequenceWidth);
+        assert_eq!(template.resolve_seq_length(Some(21)).unwrap_err().reason, TemplateErrorReason::InvalidSequenceWidth);
+    }
+
+    #[test]
+    fn test_resolve_seq_length_without_sequence() {
+        let template = FormatTemplate::parse("A-{year}").unwrap();
+        assert_eq!(template.resolve_seq_length(Some(4)).unwrap_err().reason, TemplateErrorReason::MissingPlaceholder);
+    }
+
+    #[test]
+    fn test_render_check_digit_over_preceding_characters() {
+        assert_eq!(render_sequence("799273987{SEQ:1}{CHECK:luhn}", 0, 1), "79927398713");
+        assert_eq!(render_sequence("{SEQ:3}-{CHECK:iso7064_mod97_10}", 0, 794), "794-44");
+        assert_eq!(render_sequence("0{SEQ:2}{CHECK:iso7064_mod11_2}", 0, 79), "079X");
+    }
+
+    #[test]
+    fn test_render_check_digit_covers_earlier_check_digits() {
+        // The second check runs over "57" + "2" + the first check digit "4".
+        assert_eq!(render_sequence("57{SEQ:1}{CHECK:damm}{CHECK:damm}", 0, 2), "57240");
+    }
+
+    #[test]
+    fn test_parse_rejects_unknown_check_algorithm() {
+        assert_eq!(
+            parse_error("{SEQ:4}{CHECK:crc32}"),
+            TemplateError::new("{CHECK:crc32}", 7, TemplateErrorReason::UnknownCheckAlgorithm)
+        );
+        assert_eq!(parse_error("{SEQ:4}{CHECK}").reason, TemplateErrorReason::UnknownCheckAlgorithm);
+    }
+
+    #[test]
+    fn test_verify_check_digits() {
+        let template = FormatTemplate::parse("{prefix}-{yyyy}{MM}-{SEQ:4}{CHECK:verhoeff}").unwrap();
+        let number = render_sequence("ORD-2026{MM}-{SEQ:4}{CHECK:verhoeff}", 0, 42);
+        assert!(number.starts_with("ORD-202610-0042"));
+        let verifier = template.verifier("ORD", 4).unwrap();
+        assert!(template.verify(&verifier, &number));
+
+        let mut tampered = number.clone();
+        tampered.replace_range(12..13, "1");
+        assert!(!template.verify(&verifier, &tampered));
+    }
+
+    #[test]
+    fn test_verify_rejects_wrong_shape() {
+        let template = FormatTemplate::parse("{prefix}-{SEQ:4}{CHECK:luhn}").unwrap();
+        let verifier = template.verifier("ORD", 4).unwrap();
+        assert!(!template.verify(&verifier, "ORD-42"));
+        assert!(!template.verify(&verifier, "INV-00427"));
+        assert!(template.verify(&verifier, &format!("ORD-0042{}", CheckDigitAlgorithm::Luhn.render("ORD-0042"))));
+    }
+
+    #[test]
+    fn test_default_policy_requires_nothing_extra() {
+        let template = FormatTemplate::parse("{prefix}-{SEQ:8}").unwrap();
+        assert_eq!(FormatPolicy::default().check(&template), Ok(()));
+    }
+
+    #[test]
+    fn test_policy_requiring_year() {
+        let policy = FormatPolicy::require(&["year"]).unwrap();
+        assert_eq!(policy.check(&FormatTemplate::parse("ORD-{yyyy}-{SEQ:6}").unwrap()), Ok(()));
+        assert_eq!(
+            policy.check(&FormatTemplate::parse("{prefix}-{SEQ:8}").unwrap()),
+            Err(TemplateError::new("{year}", 16, TemplateErrorReason::MissingPlaceholder))
+        );
+    }
+
+    #[test]
+    fn test_policy_rejects_unknown_names() {
+        assert_eq!(FormatPolicy::require(&["yaer"]).unwrap_err().reason, TemplateErrorReason::UnknownPlaceholder);
+    }
+
+    #[test]
+    fn test_render_fits_preallocated_buffer() {
+        let template = FormatTemplate::parse("{prefix}-{year}{MM}{dd}-{SEQ:6}").unwrap();
+        let ctx = RenderContext {
+            prefix: "ORDER",
+            sequence: 42,
+            seq_length: 6,
+            now: Tz::UTC.with_ymd_and_hms(2026, 10, 16, 0, 0, 0).unwrap(),
+            scramble_key: None,
+            variables: &HashMap::new(),
+        };
+        let rendered = template.render(&ctx).unwrap();
+        assert_eq!(rendered, "ORDER-20261016-000042");
+        assert!(rendered.capacity() >= rendered.len() + SUFFIX_RESERVE);
+    }
+
+    #[test]
+    fn test_alternative_sequence_encodings() {
+        assert_eq!(render_sequence("{SEQ36:6}", 0, 1_295), "0000ZZ");
+        assert_eq!(render_sequence("{SEQ36:6:lower}", 0, 1_295), "0000zz");
+        assert_eq!(render_sequence("{SEQ32C:7}", 0, 31), "000000Z");
+        assert_eq!(render_sequence("{SEQX:8}", 0, 255), "000000FF");
+        assert_eq!(render_sequence("{SEQX:8:upper}", 0, 255), "000000FF");
+    }
+
+    #[test]
+    fn test_sequence_capacity() {
+        assert_eq!(FormatTemplate::parse("{SEQX:2}").unwrap().sequence_capacity(0), Some(256));
+        assert_eq!(FormatTemplate::parse("{SEQ}").unwrap().sequence_capacity(4), Some(10_000));
+        assert_eq!(FormatTemplate::parse("{SEQ:20}").unwrap().sequence_capacity(0), None);
+        // Rendering itself never truncates; the overflow policy runs before it.
+        assert_eq!(render_sequence("{SEQ:2}", 0, 256), "256");
+        assert_eq!(render_sequence("{SEQX:2}", 0, 256), "100");
+    }
+
+    #[test]
+    fn test_sequence_case_argument() {
+        assert_eq!(parse_error("{SEQ:4:lower}").reason, TemplateErrorReason::UnexpectedArgument);
+        assert_eq!(parse_error("{SEQ36:4:title}").reason, TemplateErrorReason::UnexpectedArgument);
+        assert_eq!(parse_error("{SEQ36:x}").reason, TemplateErrorReason::InvalidSequenceWidth);
+        assert_eq!(parse_error("{SEQ}-{SEQX:4}").reason, TemplateErrorReason::DuplicateSequence);
+    }
+
+    #[test]
+    fn test_verify_encoded_sequence() {
+        let template = FormatTemplate::parse("{prefix}-{SEQ32C:5:lower}").unwrap();
+        let verifier = template.verifier("CPN", 5).unwrap();
+        assert!(template.verify(&verifier, "CPN-00a7z"));
+        assert!(!template.verify(&verifier, "CPN-00A7Z"));
+        assert!(!template.verify(&verifier, "CPN-00a7u"));
+    }
+
+    #[test]
+    fn test_declared_variables() {
+        let template = FormatTemplate::parse_with_variables("{region}-{SEQ:4}", &["region"]).unwrap();
+        assert_eq!(template.placeholders().next(), Some(&Placeholder::Variable("region".to_string())));
+        assert_eq!(parse_error("{region}-{SEQ:4}").reason, TemplateErrorReason::UnknownPlaceholder);
+        assert!(is_reserved_name("yyyy"));
+        assert!(is_reserved_name("SEQ36"));
+        assert!(!is_reserved_name("region"));
+    }
+
+    #[test]
+    fn test_scope_template() {
+        let template = FormatTemplate::parse_with_variables("{region}-{date:%Y%m%d}-{SEQ:4}", &["region"]).unwrap();
+        let scope = template.scope_template(&["region", "date:%Y%m%d"], &["region"]).unwrap();
+        let mut variables = HashMap::new();
+        variables.insert("region".to_string(), "TW".to_string());
+        let ctx = RenderContext {
+            prefix: "ORDER",
+            sequence: 0,
+            seq_length: 4,
+            now: Tz::UTC.with_ymd_and_hms(2026, 10, 16, 0, 0, 0).unwrap(),
+            scramble_key: None,
+            variables: &variables,
+        };
+        assert_eq!(scope.render_scope(&ctx).unwrap(), "TW:20261016");
+
+        let mut escaped = variables.clone();
+        escaped.insert("region".to_string(), "T:W%".to_string());
+        let ctx = RenderContext { variables: &escaped, ..ctx };
+        assert_eq!(scope.render_scope(&ctx).unwrap(), "T%3AW%25:20261016");
+    }
+
+    #[test]
+    fn test_scope_must_be_separated() {
+        let variables = ["region", "dept"];
+        let template = FormatTemplate::parse_with_variables("{region}{dept}-{SEQ:4}", &variables).unwrap();
+        assert_eq!(
+            template.check_scope_separated(&["region", "dept"]),
+            Err(TemplateError::new("{dept}", 22, TemplateErrorReason::AmbiguousScope))
+        );
+        // An unscoped neighbour is just as ambiguous.
+        assert_eq!(template.check_scope_separated(&["dept"]).unwrap_err().reason, TemplateErrorReason::AmbiguousScope);
+        assert!(template.check_scope_separated::<&str>(&[]).is_ok());
+
+        let template = FormatTemplate::parse_with_variables("{region}-{dept}-{SEQ:4}", &variables).unwrap();
+        assert!(template.check_scope_separated(&["region", "dept"]).is_ok());
+    }
+
+    #[test]
+    fn test_scope_template_rejects_bad_names() {
+        let template = FormatTemplate::parse("{prefix}-{yyyy}-{SEQ:4}{CHECK:luhn}").unwrap();
+        let no_variables: [&str; 0] = [];
+        assert_eq!(
+            template.scope_template(&["MM"], &no_variables),
+            Err(TemplateError::new("{MM}", 35, TemplateErrorReason::MissingPlaceholder))
+        );
+        assert_eq!(
+            template.scope_template(&["CHECK:luhn"], &no_variables).unwrap_err().reason,
+            TemplateErrorReason::InvalidScope
+        );
+        assert_eq!(
+            template.scope_template(&["region"], &no_variables).unwrap_err().reason,
+            TemplateErrorReason::UnknownPlaceholder
+        );
+    }
+
+    #[test]
+    fn test_renders_calendar_fields() {
+        let template = FormatTemplate::parse("INV-{yyyy}-{SEQ:5}").unwrap();
+        assert!(template.renders(CalendarField::Year));
+        assert!(!template.renders(CalendarField::Month));
+
+        let template = FormatTemplate::parse("{date:%F}-{SEQ:5}").unwrap();
+        assert!(template.renders(CalendarField::Year));
+        assert!(template.renders(CalendarField::Month));
+        assert!(template.renders(CalendarField::Day));
+
+        let template = FormatTemplate::parse("{yy}{DDD}-{SEQ:5}").unwrap();
+        assert!(template.renders(CalendarField::Day));
+
+        let template = FormatTemplate::parse("{date:%G-W%V}-{SEQ:5}").unwrap();
+        assert!(!template.renders(CalendarField::Year));
+    }
+}
@@ -2,4 +2,19 @@ pub mod prefix_rule;
 pub mod prefix_rule_manager;
 pub mod sequence_generator;
 pub mod number_assembler;
+pub mod format_template;
+pub mod check_digit;
+pub mod sequence_encoding;
+pub mod sequence_permutation;
+pub mod template_variable;
 pub mod redis_prefix_rule_manager;
+pub mod range_ledger;
+pub mod number_ledger;
+pub mod segment_sequence_generator;
+pub mod snowflake_sequence_generator;
+pub mod sql_sequence_generator;
+pub mod routing_sequence_generator;
+pub mod storage_backend;
+pub mod redis_storage;
+pub mod sled_storage;
+pub mod storage_prefix_rule_manager;
@@ -1,26 +1,80 @@
 use actix_web::{web, App, HttpResponse, HttpServer, Responder, Result};
+use futures_util::stream;
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc, sync::Mutex};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
 
-mod prefix_rule;
-mod prefix_rule_manager;
-mod sequence_generator;
-mod number_assembler;
-mod redis_prefix_rule_manager;
-
-use crate::redis_prefix_rule_manager::RedisPrefixRuleManager;
-use crate::sequence_generator::{SequenceGenerator, RedisSequenceGenerator};
-use crate::number_assembler::NumberAssembler;
-use crate::prefix_rule::PrefixRule;
-use crate::prefix_rule_manager::PrefixRuleManager;
+use number_generator::sequence_generator::{SequenceGenerator, SequenceGeneratorError, StorageSequenceGenerator};
+use number_generator::storage_backend::{MemoryStorage, StorageBackend, StorageError};
+use number_generator::storage_prefix_rule_manager::StoragePrefixRuleManager;
+use number_generator::redis_storage::RedisStorage;
+use number_generator::sled_storage::SledStorage;
+use number_generator::number_assembler::NumberAssembler;
+use number_generator::format_template::{FormatPolicy, TemplateError};
+use number_generator::prefix_rule::{
+    GeneratorKind, OverflowPolicy, PrefixRule, PrefixRuleError, ResetPolicy, RuleError,
+};
+use number_generator::routing_sequence_generator::RoutingSequenceGenerator;
+use number_generator::segment_sequence_generator::SegmentSequenceGenerator;
+use number_generator::snowflake_sequence_generator::SnowflakeSequenceGenerator;
+use number_generator::sql_sequence_generator::SqlSequenceGenerator;
+use number_generator::number_ledger::{
+    LedgerError, MemoryNumberLedger, NumberEntry, NumberLedger, NumberStatus, RedisNumberLedger, SledNumberLedger,
+    DEFAULT_RESERVATION_TTL_SECS,
+};
+use number_generator::range_ledger::{
+    MemoryRangeLedger, RangeLedger, RangeReservation, RedisRangeLedger, SledRangeLedger,
+};
+use number_generator::prefix_rule_manager::PrefixRuleManager;
+use number_generator::template_variable::{TemplateVariable, VariableError};
 
 #[derive(Debug, Deserialize)]
 struct PrefixConfigPayload {
     format: String,
-    #[serde(rename = "seqLength")]
-    seq_length: u32,
+    /// Optional when the format carries the width itself (`{SEQ:N}`).
+    #[serde(rename = "seqLength", default)]
+    seq_length: Option<u32>,
     #[serde(rename = "initialSeq")]
     initial_seq: u64,
+    #[serde(default)]
+    timezone: Option<String>,
+    /// Secret that scrambles the rendered sequence so numbers do not reveal volume.
+    #[serde(rename = "scrambleKey", default)]
+    scramble_key: Option<String>,
+    /// `error` (default), `wrap` or `widen` once the counter outgrows the width.
+    #[serde(rename = "overflowPolicy", default)]
+    overflow_policy: OverflowPolicy,
+    /// Caller-supplied variables the format may reference as `{name}`.
+    #[serde(default)]
+    variables: Vec<TemplateVariable>,
+    /// Variables and date tokens that partition the counter, e.g. `["region", "yyyy"]`.
+    #[serde(rename = "sequenceScope", default)]
+    sequence_scope: Vec<String>,
+    /// `never` (default), `daily`, `monthly` or `yearly`, in the rule's time zone.
+    #[serde(rename = "resetPolicy", default)]
+    reset_policy: ResetPolicy,
+    /// Distance between consecutive numbers (default 1).
+    #[serde(default)]
+    step: u64,
+    /// Residue of every issued value modulo `step`.
+    #[serde(default)]
+    offset: u64,
+    /// `counter` (default), `snowflake` or `gap_free`.
+    #[serde(default)]
+    generator: GeneratorKind,
+    /// Reissue numbers returned through the release endpoint before counting on.
+    #[serde(default)]
+    recycle: bool,
+    /// Most numbers one batch request may reserve.
+    #[serde(rename = "maxBatch", default)]
+    max_batch: Option<u64>,
+    /// Most numbers one streamed range may reserve.
+    #[serde(rename = "maxRange", default)]
+    max_range: Option<u64>,
+    /// Seconds a reserved number may stay unconfirmed (default 900).
+    #[serde(rename = "reservationTtl", default)]
+    reservation_ttl: Option<u64>,
 }
 
 impl From<PrefixConfigPayload> for PrefixRule {
@@ -28,9 +82,23 @@ impl From<PrefixConfigPayload> for PrefixRule {
         PrefixRule {
             prefix_key: String::new(), // This will be set later
             format: payload.format,
-            seq_length: payload.seq_length,
+            seq_length: payload.seq_length.unwrap_or_default(), // Resolved against the format later
             initial_seq: payload.initial_seq,
             network_partition: false,
+            timezone: payload.timezone,
+            scramble_key: payload.scramble_key,
+            overflow_policy: payload.overflow_policy,
+            variables: payload.variables,
+            sequence_scope: payload.sequence_scope,
+            reset_policy: payload.reset_policy,
+            step: payload.step,
+            offset: payload.offset,
+            generator: payload.generator,
+            recycle: payload.recycle,
+            max_batch: payload.max_batch,
+            max_range: payload.max_range,
+            reservation_ttl: payload.reservation_ttl,
+            ..Default::default()
         }
     }
 }
@@ -40,28 +108,185 @@ struct NumberResponse {
     number: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    count: u64,
+    /// Template variables, as on the single-number endpoint.
+    #[serde(default)]
+    variables: HashMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BatchResponse {
+    numbers: Vec<String>,
+}
+
+/// Numbers rendered per chunk of a streamed range.
+const STREAM_CHUNK_SIZE: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum StreamFormat {
+    /// One `{"number": ...}` object per line.
+    #[default]
+    Ndjson,
+    /// A `number` header followed by one number per line.
+    Csv,
+}
+
+impl StreamFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            StreamFormat::Ndjson => "application/x-ndjson",
+            StreamFormat::Csv => "text/csv",
+        }
+    }
+
+    fn header(self) -> &'static str {
+        match self {
+            StreamFormat::Ndjson => "",
+            StreamFormat::Csv => "number\n",
+        }
+    }
+
+    fn write_line(self, number: &str, out: &mut String) {
+        match self {
+            StreamFormat::Ndjson => {
+                out.push_str(&serde_json::json!({ "number": number }).to_string());
+            }
+            // Formats may contain literal commas or quotes.
+            StreamFormat::Csv if number.contains([',', '"', '\n', '\r']) => {
+                out.push('"');
+                out.push_str(&number.replace('"', "\"\""));
+                out.push('"');
+            }
+            StreamFormat::Csv => out.push_str(number),
+        }
+        out.push('\n');
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RangeRequest {
+    count: u64,
+    #[serde(default)]
+    format: StreamFormat,
+    #[serde(default)]
+    variables: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReleaseRequest {
+    number: String,
+    /// Template variables the number was generated with.
+    #[serde(default)]
+    variables: HashMap<String, String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ReleaseResponse {
+    number: String,
+    /// `false` when the number was already waiting to be reissued.
+    released: bool,
+}
+
+/// Attempts at writing a new reservation before the ledger is given up on.
+const LEDGER_WRITE_ATTEMPTS: u32 = 3;
+
+#[derive(Debug, Deserialize)]
+struct VoidedReportQuery {
+    /// `YYYY`, `YYYY-MM` or `YYYY-MM-DD` in the rule's time zone; all periods when absent.
+    #[serde(default)]
+    period: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VoidedReportResponse {
+    #[serde(rename = "prefixKey")]
+    prefix_key: String,
+    #[serde(default)]
+    period: Option<String>,
+    numbers: Vec<NumberEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ValidateNumberQuery {
+    number: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ValidateNumberResponse {
+    number: String,
+    valid: bool,
+}
+
+/// JSON body of a 400 caused by a rule that cannot be registered. A malformed `format`
+/// adds the offending token and its offset; every error carries a `reason` code.
+#[derive(Debug, Serialize)]
+struct RuleErrorResponse {
+    error: String,
+    #[serde(flatten)]
+    detail: Option<TemplateError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<RuleError>,
+}
+
+impl From<PrefixRuleError> for RuleErrorResponse {
+    fn from(e: PrefixRuleError) -> Self {
+        let error = e.to_string();
+        match e {
+            PrefixRuleError::Format(detail) => RuleErrorResponse { error, detail: Some(detail), reason: None },
+            PrefixRuleError::Rule(reason) => RuleErrorResponse { error, detail: None, reason: Some(reason) },
+        }
+    }
+}
+
+/// Template variables arrive as query parameters (`?region=TW`) or, on `POST`, as a flat
+/// JSON object; body values win over query values of the same name. Query parameters the
+/// rule does not declare are ignored, a body that is not such an object is a 400.
 async fn generate_number(
     prefix_key: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+    body: web::Bytes,
     prefix_rule_manager: web::Data<Arc<Mutex<dyn PrefixRuleManager + Send + Sync>>>,
-    sequence_generator: web::Data<Arc<RedisSequenceGenerator>>,
+    sequence_generator: web::Data<Arc<dyn SequenceGenerator + Send + Sync>>,
     number_assembler: web::Data<Arc<NumberAssembler>>,
 ) -> Result<impl Responder> {
     let prefix_key = prefix_key.into_inner();
+    let body = variables_body(&body)?;
 
         let prefix_rule = {
             let prefix_rule_manager_clone = prefix_rule_manager.clone();
-            let manager = prefix_rule_manager_clone.lock().unwrap();
+            let manager = prefix_rule_manager_clone.lock().await;
             manager.get_prefix_rule(prefix_key.clone()).await
-                .map_err(|e| actix_web::error::ErrorInternalServerError(e))?
+                .map_err(actix_web::error::ErrorInternalServerError)?
         };
 
     match prefix_rule {
         Some(config) => {
-            let sequence = sequence_generator.generate(&prefix_key).await
-                .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+            let variables = config.request_variables(query.into_inner(), body);
+            // Checked before generating, so a rejected request does not burn a number.
+            config.check_variables(&variables)
+                .map_err(variable_error)?;
 
-            let number = number_assembler.assemble_number(&prefix_key, &config, sequence)
-                .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+            // The counter key and the number are rendered at the same instant, so a
+            // date-scoped or periodically reset counter always matches the date printed
+            // in the number, even on a node whose clock is slightly off.
+            let now = chrono::Utc::now();
+            let sequence_key = config.sequence_key(&prefix_key, &variables, now)
+                .map_err(actix_web::error::ErrorInternalServerError)?;
+
+            // A gap-free generator keeps the value claimed until the number is built; a
+            // request that fails before then gives it back.
+            let claim = sequence_generator.claim_range(&sequence_key, &config, 1).await
+                .map_err(sequence_error)?;
+            let sequence = config.fit_sequence(claim.range.start)
+                .map_err(sequence_error)?;
+
+            let number = number_assembler
+                .assemble_number_with(&prefix_key, &config, sequence, &variables, now)
+                .map_err(actix_web::error::ErrorInternalServerError)?;
+            claim.commit().await.map_err(sequence_error)?;
 
             Ok(web::Json(NumberResponse { number }))
         }
@@ -69,19 +294,445 @@ async fn generate_number(
     }
 }
 
+/// Parses the optional JSON object of template variables sent with a single-number
+/// request. An empty body carries none.
+fn variables_body(body: &[u8]) -> Result<HashMap<String, String>> {
+    if body.trim_ascii().is_empty() {
+        return Ok(HashMap::new());
+    }
+    serde_json::from_slice(body)
+        .map_err(|e| actix_web::error::ErrorBadRequest(format!("Invalid variables: {}", e)))
+}
+
+/// Reserves `count` consecutive numbers with a single counter operation and returns
+/// them assembled, in order.
+async fn generate_batch(
+    prefix_key: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+    body: web::Json<BatchRequest>,
+    prefix_rule_manager: web::Data<Arc<Mutex<dyn PrefixRuleManager + Send + Sync>>>,
+    sequence_generator: web::Data<Arc<dyn SequenceGenerator + Send + Sync>>,
+    number_assembler: web::Data<Arc<NumberAssembler>>,
+) -> Result<impl Responder> {
+    let prefix_key = prefix_key.into_inner();
+    let BatchRequest { count, variables: body_variables } = body.into_inner();
+
+    let prefix_rule = {
+        let manager = prefix_rule_manager.lock().await;
+        manager.get_prefix_rule(prefix_key.clone()).await
+            .map_err(actix_web::error::ErrorInternalServerError)?
+    };
+
+    match prefix_rule {
+        Some(config) => {
+            config.check_batch_size(count)
+                .map_err(actix_web::error::ErrorBadRequest)?;
+            let variables = config.request_variables(query.into_inner(), body_variables);
+            config.check_variables(&variables)
+                .map_err(variable_error)?;
+
+            let now = chrono::Utc::now();
+            let sequence_key = config.sequence_key(&prefix_key, &variables, now)
+                .map_err(actix_web::error::ErrorInternalServerError)?;
+            let claim = sequence_generator.claim_range(&sequence_key, &config, count).await
+                .map_err(sequence_error)?;
+
+            let mut numbers = Vec::with_capacity(count as usize);
+            for sequence in claim.range.values() {
+                let sequence = config.fit_sequence(sequence).map_err(sequence_error)?;
+                let number = number_assembler
+                    .assemble_number_with(&prefix_key, &config, sequence, &variables, now)
+                    .map_err(actix_web::error::ErrorInternalServerError)?;
+                numbers.push(number);
+            }
+            claim.commit().await.map_err(sequence_error)?;
+
+            Ok(web::Json(BatchResponse { numbers }))
+        }
+        None => Err(actix_web::error::ErrorBadRequest("Prefix not registered")),
+    }
+}
+
+/// Reserves a block of up to `maxRange` numbers and streams them as NDJSON or CSV
+/// without buffering the whole response. The reserved block is recorded in the range
+/// ledger before the first number is sent, even when it turns out not to fit the width
+/// (unless the generator is gap-free, which takes the block back instead).
+async fn stream_range(
+    prefix_key: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+    body: web::Json<RangeRequest>,
+    prefix_rule_manager: web::Data<Arc<Mutex<dyn PrefixRuleManager + Send + Sync>>>,
+    sequence_generator: web::Data<Arc<dyn SequenceGenerator + Send + Sync>>,
+    number_assembler: web::Data<Arc<NumberAssembler>>,
+    range_ledger: web::Data<Arc<dyn RangeLedger + Send + Sync>>,
+) -> Result<HttpResponse> {
+    let prefix_key = prefix_key.into_inner();
+    let RangeRequest { count, format, variables: body_variables } = body.into_inner();
+
+    let prefix_rule = {
+        let manager = prefix_rule_manager.lock().await;
+        manager.get_prefix_rule(prefix_key.clone()).await
+            .map_err(actix_web::error::ErrorInternalServerError)?
+    };
+    let Some(config) = prefix_rule else {
+        return Err(actix_web::error::ErrorBadRequest("Prefix not registered"));
+    };
+
+    config.check_range_size(count)
+        .map_err(actix_web::error::ErrorBadRequest)?;
+    let variables = config.request_variables(query.into_inner(), body_variables);
+    config.check_variables(&variables)
+        .map_err(variable_error)?;
+
+    let now = chrono::Utc::now();
+    let sequence_key = config.sequence_key(&prefix_key, &variables, now)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    let claim = sequence_generator.claim_range(&sequence_key, &config, count).await
+        .map_err(sequence_error)?;
+    let range = claim.range;
+    let fits = config.fit_range(&range);
+
+    // A gap-free claim that does not fit is dropped, which gives its values back. Any
+    // other block is taken from the counter already, so it is recorded even when refused.
+    if fits.is_ok() || !claim.is_pending() {
+        claim.commit().await.map_err(sequence_error)?;
+        let reservation = RangeReservation {
+            prefix_key: prefix_key.clone(),
+            sequence_key,
+            range,
+            variables: variables.clone(),
+            reserved_at: now,
+        };
+        range_ledger.record(&reservation).await
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+    }
+    fits.map_err(sequence_error)?;
+
+    let number_assembler = number_assembler.get_ref().clone();
+    let chunks = (0..range.count).step_by(STREAM_CHUNK_SIZE as usize).map(move |first| {
+        let mut body = String::from(if first == 0 { format.header() } else { "" });
+        for index in first..(first + STREAM_CHUNK_SIZE).min(range.count) {
+            let sequence = config.fit_sequence(range.value_at(index)).map_err(sequence_error)?;
+            let number = number_assembler
+                .assemble_number_with(&prefix_key, &config, sequence, &variables, now)
+                .map_err(actix_web::error::ErrorInternalServerError)?;
+            format.write_line(&number, &mut body);
+        }
+        Ok::<_, actix_web::Error>(web::Bytes::from(body))
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(("X-Range-Start", range.start.to_string()))
+        .insert_header(("X-Range-Count", range.count.to_string()))
+        .streaming(stream::iter(chunks)))
+}
+
+/// Lists the ranges reserved for a prefix, oldest first.
+async fn list_ranges(
+    prefix_key: web::Path<String>,
+    range_ledger: web::Data<Arc<dyn RangeLedger + Send + Sync>>,
+) -> Result<impl Responder> {
+    let reservations = range_ledger.reservations(&prefix_key).await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(web::Json(reservations))
+}
+
+/// Issues a number like `generate_number`, but only as a reservation: it has to be
+/// confirmed (or voided) before the rule's reservation TTL runs out, or it expires.
+async fn reserve_number(
+    prefix_key: web::Path<String>,
+    query: web::Query<HashMap<String, String>>,
+    body: web::Bytes,
+    prefix_rule_manager: web::Data<Arc<Mutex<dyn PrefixRuleManager + Send + Sync>>>,
+    sequence_generator: web::Data<Arc<dyn SequenceGenerator + Send + Sync>>,
+    number_assembler: web::Data<Arc<NumberAssembler>>,
+    number_ledger: web::Data<Arc<dyn NumberLedger + Send + Sync>>,
+) -> Result<impl Responder> {
+    let prefix_key = prefix_key.into_inner();
+    let body = variables_body(&body)?;
+
+    let prefix_rule = {
+        let manager = prefix_rule_manager.lock().await;
+        manager.get_prefix_rule(prefix_key.clone()).await
+            .map_err(actix_web::error::ErrorInternalServerError)?
+    };
+    let Some(config) = prefix_rule else {
+        return Err(actix_web::error::ErrorBadRequest("Prefix not registered"));
+    };
+
+    let variables = config.request_variables(query.into_inner(), body);
+    config.check_variables(&variables)
+        .map_err(variable_error)?;
+
+    let now = chrono::Utc::now();
+    let sequence_key = config.sequence_key(&prefix_key, &variables, now)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    let claim = sequence_generator.claim_range(&sequence_key, &config, 1).await
+        .map_err(sequence_error)?;
+    let sequence = config.fit_sequence(claim.range.start)
+        .map_err(sequence_error)?;
+    let number = number_assembler
+        .assemble_number_with(&prefix_key, &config, sequence, &variables, now)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    // Committed before the ledger write, whose failure is accounted for as a void.
+    claim.commit().await.map_err(sequence_error)?;
+
+    let ttl = config.reservation_ttl.unwrap_or(DEFAULT_RESERVATION_TTL_SECS);
+    let entry = NumberEntry::reserve(number, sequence, now, ttl);
+    record_reservation(number_ledger.get_ref().as_ref(), &prefix_key, &entry).await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(web::Json(entry))
+}
+
+/// Writes a fresh reservation to the ledger. Its number is already taken from the
+/// counter, so a failed write is retried; if the ledger keeps failing, the number is
+/// recorded as voided instead, so the report still accounts for it.
+async fn record_reservation(
+    number_ledger: &(dyn NumberLedger + Send + Sync),
+    prefix_key: &str,
+    entry: &NumberEntry,
+) -> Result<(), LedgerError> {
+    let mut attempt = 1;
+    loop {
+        match number_ledger.reserve(prefix_key, entry).await {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt >= LEDGER_WRITE_ATTEMPTS => {
+                let mut voided = entry.clone();
+                // An already overdue reservation expires instead, which is reported alike.
+                let _ = voided.settle(NumberStatus::Voided, chrono::Utc::now());
+                if let Err(void_error) = number_ledger.reserve(prefix_key, &voided).await {
+                    eprintln!("Number {} of {} is not in the ledger: {}", entry.number, prefix_key, void_error);
+                }
+                return Err(e);
+            }
+            Err(_) => {
+                tokio::time::sleep(std::time::Duration::from_millis(10 * u64::from(attempt))).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+async fn confirm_number(
+    path: web::Path<(String, String)>,
+    number_ledger: web::Data<Arc<dyn NumberLedger + Send + Sync>>,
+) -> Result<impl Responder> {
+    settle_number(path.into_inner(), NumberStatus::Confirmed, number_ledger.get_ref().as_ref()).await
+}
+
+async fn void_number(
+    path: web::Path<(String, String)>,
+    number_ledger: web::Data<Arc<dyn NumberLedger + Send + Sync>>,
+) -> Result<impl Responder> {
+    settle_number(path.into_inner(), NumberStatus::Voided, number_ledger.get_ref().as_ref()).await
+}
+
+async fn settle_number(
+    (prefix_key, number): (String, String),
+    status: NumberStatus,
+    number_ledger: &(dyn NumberLedger + Send + Sync),
+) -> Result<web::Json<NumberEntry>> {
+    let entry = number_ledger.settle(&prefix_key, &number, status, chrono::Utc::now()).await
+        .map_err(ledger_error)?;
+    Ok(web::Json(entry))
+}
+
+/// Maps rejected request variables to a 400; a stored format that no longer parses is the
+/// server's fault, not the request's.
+fn variable_error(err: VariableError) -> actix_web::Error {
+    match err {
+        VariableError::Format(_) => actix_web::error::ErrorInternalServerError(err),
+        _ => actix_web::error::ErrorBadRequest(err),
+    }
+}
+
+/// Maps ledger failures to responses: an unknown number is not found, a number in the
+/// wrong state for the request a conflict.
+fn ledger_error(err: LedgerError) -> actix_web::Error {
+    match err {
+        LedgerError::NotFound(_) => actix_web::error::ErrorNotFound(err),
+        LedgerError::AlreadySettled { .. } | LedgerError::NotVoid { .. } => actix_web::error::ErrorConflict(err),
+        _ => actix_web::error::ErrorInternalServerError(err),
+    }
+}
+
+/// Lists the voided and expired numbers of a prefix, optionally limited to the period
+/// they were voided or expired in.
+async fn voided_report(
+    prefix_key: web::Path<String>,
+    query: web::Query<VoidedReportQuery>,
+    prefix_rule_manager: web::Data<Arc<Mutex<dyn PrefixRuleManager + Send + Sync>>>,
+    number_ledger: web::Data<Arc<dyn NumberLedger + Send + Sync>>,
+) -> Result<impl Responder> {
+    let prefix_key = prefix_key.into_inner();
+    let period = query.into_inner().period;
+    let period_days = period.as_deref()
+        .map(|period| report_period_days(period).ok_or_else(|| {
+            actix_web::error::ErrorBadRequest(format!("Invalid period {}: expected YYYY, YYYY-MM or YYYY-MM-DD", period))
+        }))
+        .transpose()?;
+
+    let prefix_rule = {
+        let manager = prefix_rule_manager.lock().await;
+        manager.get_prefix_rule(prefix_key.clone()).await
+            .map_err(actix_web::error::ErrorInternalServerError)?
+    };
+    let Some(config) = prefix_rule else {
+        return Err(actix_web::error::ErrorBadRequest("Prefix not registered"));
+    };
+    let tz = config.time_zone().map_err(actix_web::error::ErrorInternalServerError)?;
+
+    // The period runs from midnight to midnight in the rule's time zone.
+    let settled = period_days.map(|days| local_midnight(days.start, &tz)..local_midnight(days.end, &tz));
+
+    let numbers = number_ledger.voided(&prefix_key, chrono::Utc::now(), settled).await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(web::Json(VoidedReportResponse { prefix_key, period, numbers }))
+}
+
+/// The days a report period covers: `YYYY`, `YYYY-MM` or `YYYY-MM-DD`, naming a real
+/// year, month or day. `None` for anything else.
+fn report_period_days(period: &str) -> Option<std::ops::Range<chrono::NaiveDate>> {
+    let first_day = match period.len() {
+        4 => format!("{}-01-01", period),
+        7 => format!("{}-01", period),
+        10 => period.to_string(),
+        _ => return None,
+    };
+    let well_formed = period.bytes().enumerate().all(|(i, b)| if i == 4 || i == 7 { b == b'-' } else { b.is_ascii_digit() });
+    if !well_formed {
+        return None;
+    }
+    let first_day = chrono::NaiveDate::parse_from_str(&first_day, "%Y-%m-%d").ok()?;
+    let end = match period.len() {
+        4 => first_day.checked_add_months(chrono::Months::new(12)),
+        7 => first_day.checked_add_months(chrono::Months::new(1)),
+        _ => first_day.succ_opt(),
+    }?;
+    Some(first_day..end)
+}
+
+/// The instant `day` starts in `tz`; where a clock change skips midnight, the first
+/// instant after it.
+fn local_midnight(day: chrono::NaiveDate, tz: &chrono_tz::Tz) -> chrono::DateTime<chrono::Utc> {
+    use chrono::TimeZone;
+    let midnight = day.and_time(chrono::NaiveTime::MIN);
+    tz.from_local_datetime(&midnight)
+        .earliest()
+        .or_else(|| tz.from_local_datetime(&(midnight + chrono::Duration::hours(1))).earliest())
+        .map_or_else(|| midnight.and_utc(), |start| start.with_timezone(&chrono::Utc))
+}
+
+/// Returns an unused number of a recycling prefix, to be handed out again before the
+/// counter moves on. Only numbers of the current period and scope that the ledger shows
+/// as voided or expired can be released, and each only once: the ledger marks them
+/// recycled.
+async fn release_number(
+    prefix_key: web::Path<String>,
+    body: web::Json<ReleaseRequest>,
+    prefix_rule_manager: web::Data<Arc<Mutex<dyn PrefixRuleManager + Send + Sync>>>,
+    sequence_generator: web::Data<Arc<dyn SequenceGenerator + Send + Sync>>,
+    number_assembler: web::Data<Arc<NumberAssembler>>,
+    number_ledger: web::Data<Arc<dyn NumberLedger + Send + Sync>>,
+) -> Result<impl Responder> {
+    let prefix_key = prefix_key.into_inner();
+    let ReleaseRequest { number, variables } = body.into_inner();
+
+    let prefix_rule = {
+        let manager = prefix_rule_manager.lock().await;
+        manager.get_prefix_rule(prefix_key.clone()).await
+            .map_err(actix_web::error::ErrorInternalServerError)?
+    };
+    let Some(config) = prefix_rule else {
+        return Err(actix_web::error::ErrorBadRequest("Prefix not registered"));
+    };
+    if !config.recycle {
+        return Err(actix_web::error::ErrorBadRequest("Prefix does not recycle numbers"));
+    }
+
+    config.check_variables(&variables)
+        .map_err(variable_error)?;
+
+    let now = chrono::Utc::now();
+    let sequence = number_assembler.current_sequence(&prefix_key, &config, &number, &variables, now)
+        .map_err(actix_web::error::ErrorBadRequest)?;
+    let sequence_key = config.sequence_key(&prefix_key, &variables, now)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    // Marked first, so two releases of one number cannot both pass. If the release then
+    // fails, the mark is taken back so the number can be released again.
+    let mut entry = number_ledger.recycle(&prefix_key, &number, now).await
+        .map_err(ledger_error)?;
+    let released = match sequence_generator.release(&sequence_key, &config, sequence).await {
+        Ok(released) => released,
+        Err(e) => {
+            entry.unrecycle();
+            if let Err(restore_error) = number_ledger.reserve(&prefix_key, &entry).await {
+                eprintln!("Number {} of {} is marked recycled but was not released: {}", number, prefix_key, restore_error);
+            }
+            return Err(sequence_error(e));
+        }
+    };
+
+    Ok(web::Json(ReleaseResponse { number, released }))
+}
+
+/// Maps generator failures to responses: an exhausted counter is the caller's conflict
+/// to resolve (wider format, other policy), anything else is ours.
+fn sequence_error(err: SequenceGeneratorError) -> actix_web::Error {
+    match err {
+        SequenceGeneratorError::Overflow { .. } => actix_web::error::ErrorConflict(err.to_string()),
+        SequenceGeneratorError::NotIssued { .. } => actix_web::error::ErrorBadRequest(err.to_string()),
+        _ => actix_web::error::ErrorInternalServerError(err),
+    }
+}
+
+async fn validate_number(
+    prefix_key: web::Path<String>,
+    query: web::Query<ValidateNumberQuery>,
+    prefix_rule_manager: web::Data<Arc<Mutex<dyn PrefixRuleManager + Send + Sync>>>,
+    number_assembler: web::Data<Arc<NumberAssembler>>,
+) -> Result<impl Responder> {
+    let prefix_key = prefix_key.into_inner();
+    let number = query.into_inner().number;
+
+    let prefix_rule = {
+        let manager = prefix_rule_manager.lock().await;
+        manager.get_prefix_rule(prefix_key.clone()).await
+            .map_err(actix_web::error::ErrorInternalServerError)?
+    };
+
+    match prefix_rule {
+        Some(config) => {
+            let valid = number_assembler.validate_number(&prefix_key, &config, &number)
+                .map_err(actix_web::error::ErrorInternalServerError)?;
+            Ok(web::Json(ValidateNumberResponse { number, valid }))
+        }
+        None => Err(actix_web::error::ErrorBadRequest("Prefix not registered")),
+    }
+}
+
 async fn register_prefix(
     prefix_key: web::Path<String>,
     payload: web::Json<PrefixConfigPayload>,
     prefix_rule_manager: web::Data<Arc<Mutex<dyn PrefixRuleManager + Send + Sync>>>,
+    format_policy: web::Data<FormatPolicy>,
 ) -> Result<impl Responder> {
     let prefix_key = prefix_key.into_inner();
+    let requested_seq_length = payload.seq_length;
     let mut prefix_rule: PrefixRule = payload.into_inner().into();
     prefix_rule.prefix_key = prefix_key.clone();
 
     let prefix_rule_manager_clone = prefix_rule_manager.clone();
-    let mut manager = prefix_rule_manager_clone.lock().unwrap();
-    if !is_valid_format(&prefix_rule.format) {
-        return Err(actix_web::error::ErrorBadRequest("Invalid prefix format"));
+    let manager = prefix_rule_manager_clone.lock().await;
+    if let Err(e) = prefix_rule.validate(requested_seq_length, &format_policy) {
+        return Ok(HttpResponse::BadRequest().json(RuleErrorResponse::from(e)));
+    }
+    if let Err(e) = prefix_rule.time_zone() {
+        return Err(actix_web::error::ErrorBadRequest(e));
     }
     let result = manager.register_prefix_rule(prefix_key.clone(), prefix_rule).await;
     match result {
@@ -93,11 +744,119 @@ async fn register_prefix(
     }
 }
 
-fn is_valid_format(format: &str) -> bool {
-    // Check if the format contains {SEQ:N} where N is a number
-    let re = regex::Regex::new(r"\{SEQ:\d+\}").unwrap();
-    // Check if the format contains {SEQ:N} where N is a number
-    re.is_match(format) && format.contains("{year}")
+/// Placeholders every format must carry besides `{SEQ}`, as a comma-separated list of
+/// names (e.g. `year`), so each deployment can decide how strict registration is.
+const REQUIRED_PLACEHOLDERS_ENV: &str = "NUMBER_GENERATOR_REQUIRED_PLACEHOLDERS";
+
+fn format_policy_from_env() -> std::io::Result<FormatPolicy> {
+    let names: Vec<String> = std::env::var(REQUIRED_PLACEHOLDERS_ENV)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .collect();
+    FormatPolicy::require(&names)
+        .map_err(|e| std::io::Error::other(format!("Invalid {}: {}", REQUIRED_PLACEHOLDERS_ENV, e)))
+}
+
+/// Values each node reserves per counter and serves from memory. Unset or 0 keeps one
+/// storage round trip per number and a gap-free series per counter.
+const SEGMENT_SIZE_ENV: &str = "NUMBER_GENERATOR_SEGMENT_SIZE";
+
+fn segment_size_from_env() -> std::io::Result<Option<u64>> {
+    match std::env::var(SEGMENT_SIZE_ENV) {
+        Ok(value) => value
+            .trim()
+            .parse::<u64>()
+            .map(|size| Some(size).filter(|&size| size > 0))
+            .map_err(|e| std::io::Error::other(format!("Invalid {}: {}", SEGMENT_SIZE_ENV, e))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// This node's Snowflake worker id (0-1023), unique among nodes serving the same
+/// prefixes. While it is unset, `snowflake` rules cannot generate numbers.
+const WORKER_ID_ENV: &str = "NUMBER_GENERATOR_WORKER_ID";
+
+fn worker_id_from_env() -> std::io::Result<Option<u64>> {
+    match std::env::var(WORKER_ID_ENV) {
+        Ok(value) => value
+            .trim()
+            .parse::<u64>()
+            .map(Some)
+            .map_err(|e| std::io::Error::other(format!("Invalid {}: {}", WORKER_ID_ENV, e))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Database holding the counters of `gap_free` rules, e.g. `postgres://user@host/numbers`
+/// or `sqlite:///var/lib/numbers.db?mode=rwc`. While it is unset, `gap_free` rules cannot
+/// generate numbers.
+const DATABASE_URL_ENV: &str = "NUMBER_GENERATOR_DATABASE_URL";
+
+/// Where prefix rules and counters are kept: `redis` (the default) for a cluster of nodes,
+/// `sled:<directory>` for an embedded database on a single node, or `memory` for a node
+/// that may lose its state on restart. The range and reservation ledgers are kept in the
+/// same store as the counters.
+const STORAGE_ENV: &str = "NUMBER_GENERATOR_STORAGE";
+
+enum StorageConfig {
+    Redis,
+    Sled(String),
+    Memory,
+}
+
+fn storage_config_from_env() -> std::io::Result<StorageConfig> {
+    let storage = std::env::var(STORAGE_ENV).unwrap_or_else(|_| "redis".to_string());
+    match storage.trim() {
+        "redis" => Ok(StorageConfig::Redis),
+        "memory" => Ok(StorageConfig::Memory),
+        other => match other.strip_prefix("sled:") {
+            Some(path) => Ok(StorageConfig::Sled(path.to_string())),
+            None => Err(std::io::Error::other(format!(
+                "Invalid {}: expected redis, memory or sled:<directory>, got {}",
+                STORAGE_ENV, other
+            ))),
+        },
+    }
+}
+
+/// Everything the handlers share. `main` assembles it from the configured stores; tests
+/// run the same routes on `AppState::in_memory`.
+#[derive(Clone)]
+struct AppState {
+    prefix_rule_manager: Arc<Mutex<dyn PrefixRuleManager + Send + Sync>>,
+    sequence_generator: Arc<dyn SequenceGenerator + Send + Sync>,
+    number_assembler: Arc<NumberAssembler>,
+    range_ledger: Arc<dyn RangeLedger + Send + Sync>,
+    number_ledger: Arc<dyn NumberLedger + Send + Sync>,
+    format_policy: web::Data<FormatPolicy>,
+}
+
+impl AppState {
+    /// Registers the shared state and every route on an `App`.
+    fn configure(&self, cfg: &mut web::ServiceConfig) {
+        cfg.app_data(web::Data::new(self.prefix_rule_manager.clone()))
+            .app_data(web::Data::new(self.sequence_generator.clone()))
+            .app_data(web::Data::new(self.number_assembler.clone()))
+            .app_data(web::Data::new(self.range_ledger.clone()))
+            .app_data(web::Data::new(self.number_ledger.clone()))
+            .app_data(self.format_policy.clone())
+            .route("/api/numbers/{prefixKey}", web::get().to(generate_number))
+            .route("/api/numbers/{prefixKey}", web::post().to(generate_number))
+            .route("/api/numbers/{prefixKey}/batch", web::post().to(generate_batch))
+            .route("/api/numbers/{prefixKey}/range", web::post().to(stream_range))
+            .route("/api/numbers/{prefixKey}/ranges", web::get().to(list_ranges))
+            .route("/api/numbers/{prefixKey}/reservations", web::post().to(reserve_number))
+            .route("/api/numbers/{prefixKey}/reservations/{number}/confirm", web::post().to(confirm_number))
+            .route("/api/numbers/{prefixKey}/reservations/{number}/void", web::post().to(void_number))
+            .route("/api/numbers/{prefixKey}/voided", web::get().to(voided_report))
+            .route("/api/numbers/{prefixKey}/release", web::post().to(release_number))
+            .route("/api/numbers/{prefixKey}/validate", web::get().to(validate_number))
+            .route("/api/prefix-configs/{prefixKey}", web::put().to(register_prefix))
+            .route("/api/prefix-configs/{prefixKey}/network-partition", web::post().to(set_network_partition));
+    }
 }
 
 #[actix_web::main]
@@ -105,41 +864,73 @@ async fn main() -> std::io::Result<()> {
     // Service Statelessness:
     // The service is stateless, as it doesn't store any state within the service instance itself.
     // All state is stored in Redis. This allows for horizontal scaling and no single point of failure.
+    // Single-node deployments can keep it in an embedded store instead (see STORAGE_ENV).
     let redis_url = "redis://localhost:6379/".to_string();
+    let storage_config = storage_config_from_env()?;
+    let invalid_storage = |e: StorageError| std::io::Error::other(format!("Invalid {}: {}", STORAGE_ENV, e));
+    let invalid_ledger = |e: sled::Error| std::io::Error::other(format!("Invalid {}: {}", STORAGE_ENV, e));
+    // The ledgers live next to the counters, so a sled deployment needs no Redis at all.
+    let (storage, range_ledger, number_ledger): (
+        Arc<dyn StorageBackend>,
+        Arc<dyn RangeLedger + Send + Sync>,
+        Arc<dyn NumberLedger + Send + Sync>,
+    ) = match &storage_config {
+        StorageConfig::Redis => (
+            Arc::new(RedisStorage::new(redis_url.clone()).map_err(invalid_storage)?),
+            Arc::new(RedisRangeLedger::new(redis_url.clone()).map_err(|e| invalid_storage(e.into()))?),
+            Arc::new(RedisNumberLedger::new(redis_url.clone()).map_err(|e| invalid_storage(e.into()))?),
+        ),
+        StorageConfig::Sled(path) => {
+            let sled_storage = SledStorage::open(path).map_err(invalid_storage)?;
+            let range_ledger = SledRangeLedger::open(sled_storage.db()).map_err(invalid_ledger)?;
+            let number_ledger = SledNumberLedger::open(sled_storage.db()).map_err(invalid_ledger)?;
+            (Arc::new(sled_storage), Arc::new(range_ledger), Arc::new(number_ledger))
+        }
+        StorageConfig::Memory => {
+            (Arc::new(MemoryStorage::new()), Arc::new(MemoryRangeLedger::new()), Arc::new(MemoryNumberLedger::new()))
+        }
+    };
     let prefix_rule_manager: Arc<Mutex<dyn PrefixRuleManager + Send + Sync>> = {
-        let redis_prefix_rule_manager = RedisPrefixRuleManager::new(redis_url.clone()).unwrap();
-        Arc::new(Mutex::new(redis_prefix_rule_manager))
+        let storage_prefix_rule_manager = StoragePrefixRuleManager::with_storage(storage.clone());
+        Arc::new(Mutex::new(storage_prefix_rule_manager))
     };
-    let sequence_generator: Arc<RedisSequenceGenerator> = {
-        let redis_sequence_generator = RedisSequenceGenerator::new(redis_url.clone()).unwrap();
-        Arc::new(redis_sequence_generator)
+    let sequence_generator: Arc<dyn SequenceGenerator + Send + Sync> = {
+        let storage_sequence_generator = StorageSequenceGenerator::with_storage(storage);
+        let counter: Arc<dyn SequenceGenerator + Send + Sync> = match segment_size_from_env()? {
+            Some(segment_size) => Arc::new(SegmentSequenceGenerator::new(storage_sequence_generator, segment_size)),
+            None => Arc::new(storage_sequence_generator),
+        };
+        let mut routing_sequence_generator = RoutingSequenceGenerator::new(counter);
+        if let Some(worker_id) = worker_id_from_env()? {
+            let snowflake = SnowflakeSequenceGenerator::new(worker_id)
+                .map_err(|e| std::io::Error::other(format!("Invalid {}: {}", WORKER_ID_ENV, e)))?;
+            routing_sequence_generator = routing_sequence_generator.with(GeneratorKind::Snowflake, Arc::new(snowflake));
+        }
+        if let Ok(database_url) = std::env::var(DATABASE_URL_ENV) {
+            let gap_free = SqlSequenceGenerator::connect(&database_url)
+                .await
+                .map_err(|e| std::io::Error::other(format!("Invalid {}: {}", DATABASE_URL_ENV, e)))?;
+            routing_sequence_generator = routing_sequence_generator.with(GeneratorKind::GapFree, Arc::new(gap_free));
+        }
+        Arc::new(routing_sequence_generator)
+    };
+    let state = AppState {
+        prefix_rule_manager,
+        sequence_generator,
+        number_assembler: Arc::new(NumberAssembler::new()),
+        range_ledger,
+        number_ledger,
+        format_policy: web::Data::new(format_policy_from_env()?),
     };
-    let number_assembler = Arc::new(NumberAssembler::new());
-
-    let prefix_rule_manager_data: web::Data<Arc<Mutex<dyn PrefixRuleManager + Send + Sync>>> = web::Data::new(prefix_rule_manager.clone());
-    let sequence_generator_data = web::Data::new(sequence_generator);
-    let number_assembler_data = web::Data::new(number_assembler.clone());
 
     // Service Node Downtime:
     // Service node downtime is handled by the load balancer, which automatically
     // switches traffic to healthy nodes. Since the service is stateless, any instance
     // can handle any request.
-    HttpServer::new(move || {
-        App::new()
-            .app_data(prefix_rule_manager_data.clone())
-            .app_data(sequence_generator_data.clone())
-            .app_data(number_assembler_data.clone())
-            .app_data(sequence_generator_data.clone())
-            .app_data(number_assembler_data.clone())
-            .app_data(sequence_generator_data.clone())
-            .app_data(number_assembler_data.clone())
-            .route("/api/numbers/{prefixKey}", web::get().to(generate_number))
-            .route("/api/prefix-configs/{prefixKey}", web::put().to(register_prefix))
-            .route("/api/prefix-configs/{prefixKey}/network-partition", web::post().to(set_network_partition))
-    })
-    .bind(("0.0.0.0", 8080))?
-    .run()
-    .await
+    HttpServer::new(move || App::new().configure(|cfg| state.configure(cfg)))
+        .bind(("0.0.0.0", 8080))?
+        .run()
+        .await
 }
 
 async fn set_network_partition(
@@ -149,13 +940,13 @@ async fn set_network_partition(
     let prefix_key = prefix_key.into_inner();
 
     let prefix_rule_manager_clone = prefix_rule_manager.clone();
-    let mut manager = prefix_rule_manager_clone.lock().unwrap();
+    let manager = prefix_rule_manager_clone.lock().await;
 
     match manager.get_prefix_rule(prefix_key.clone()).await {
         Ok(Some(mut prefix_rule)) => {
             prefix_rule.network_partition = true;
             manager.register_prefix_rule(prefix_key.clone(), prefix_rule).await
-                .map_err(|e| actix_web::error::ErrorInternalServerError(e))?;
+                .map_err(actix_web::error::ErrorInternalServerError)?;
             Ok(HttpResponse::Ok().finish())
         }
         Ok(None) => Err(actix_web::error::ErrorBadRequest("Prefix not registered")),
@@ -166,40 +957,111 @@ async fn set_network_partition(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use number_generator::sequence_generator::{MemorySequenceGenerator, SequenceRange};
+    use number_generator::storage_prefix_rule_manager::MemoryPrefixRuleManager;
     use actix_web::{test, web, App};
     use actix_web::http::StatusCode;
     use serde_json::json;
 
-    #[actix_web::test]
-    async fn test_register_and_generate_number() {
-        let redis_url = "redis://localhost:6379/".to_string();
-        let client = redis::Client::open(redis_url.clone()).unwrap();
-        let mut conn = client.get_connection().unwrap();
-        let _ : () = redis::cmd("FLUSHDB").execute(&mut conn);
-
-        let prefix_rule_manager: Arc<Mutex<dyn PrefixRuleManager + Send + Sync>> = {
-            let redis_prefix_rule_manager = RedisPrefixRuleManager::new(redis_url.clone()).unwrap();
-            Arc::new(Mutex::new(redis_prefix_rule_manager))
-        };
-        let sequence_generator: Arc<RedisSequenceGenerator> = {
-            let redis_sequence_generator = RedisSequenceGenerator::new(redis_url.clone()).unwrap();
-            Arc::new(redis_sequence_generator)
-        };
-        let number_assembler = Arc::new(NumberAssembler::new());
+    impl AppState {
+        /// Keeps rules, counters and ledgers in this process.
+        fn in_memory() -> Self {
+            AppState {
+                prefix_rule_manager: Arc::new(Mutex::new(MemoryPrefixRuleManager::new())),
+                sequence_generator: Arc::new(MemorySequenceGenerator::new()),
+                number_assembler: Arc::new(NumberAssembler::new()),
+                range_ledger: Arc::new(MemoryRangeLedger::new()),
+                number_ledger: Arc::new(MemoryNumberLedger::new()),
+                format_policy: web::Data::new(FormatPolicy::default()),
+            }
+        }
 
-        let prefix_rule_manager_data = web::Data::new(prefix_rule_manager.clone());
-        let sequence_generator_data = web::Data::new(sequence_generator.clone());
-        let number_assembler_data = web::Data::new(number_assembler.clone());
+        /// Counts in `storage`, so a test can look at and move the counters.
+        fn with_storage(self, storage: &Arc<MemoryStorage>) -> Self {
+            self.with_sequence_generator(Arc::new(StorageSequenceGenerator::with_storage(storage.clone())))
+        }
 
-        let app = test::init_service(
-            App::new()
-                .app_data(prefix_rule_manager_data.clone())
-                .app_data(sequence_generator_data.clone())
-                .app_data(number_assembler_data.clone())
-                .route("/api/numbers/{prefixKey}", web::get().to(generate_number))
-                .route("/api/prefix-configs/{prefixKey}", web::put().to(register_prefix))
-        )
-        .await;
+        fn with_sequence_generator(mut self, sequence_generator: Arc<dyn SequenceGenerator + Send + Sync>) -> Self {
+            self.sequence_generator = sequence_generator;
+            self
+        }
+
+        fn with_number_ledger(mut self, number_ledger: Arc<dyn NumberLedger + Send + Sync>) -> Self {
+            self.number_ledger = number_ledger;
+            self
+        }
+    }
+
+    /// Fails the next `failures` writes, and every write of an open reservation when
+    /// `refuse_reservations` is set, to simulate a ledger outage.
+    #[derive(Default)]
+    struct FailingNumberLedger {
+        ledger: MemoryNumberLedger,
+        failures: std::sync::Mutex<u32>,
+        refuse_reservations: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl NumberLedger for FailingNumberLedger {
+        async fn reserve(&self, prefix_key: &str, entry: &NumberEntry) -> Result<(), LedgerError> {
+            let fail = {
+                let mut failures = self.failures.lock().unwrap();
+                let failing = *failures > 0;
+                *failures = failures.saturating_sub(1);
+                failing || (self.refuse_reservations && entry.status == NumberStatus::Reserved)
+            };
+            if fail {
+                return Err(LedgerError::RedisError(redis::RedisError::from((redis::ErrorKind::IoError, "ledger unavailable"))));
+            }
+            self.ledger.reserve(prefix_key, entry).await
+        }
+
+        async fn settle(
+            &self,
+            prefix_key: &str,
+            number: &str,
+            status: NumberStatus,
+            now: chrono::DateTime<chrono::Utc>,
+        ) -> Result<NumberEntry, LedgerError> {
+            self.ledger.settle(prefix_key, number, status, now).await
+        }
+
+        async fn recycle(&self, prefix_key: &str, number: &str, now: chrono::DateTime<chrono::Utc>) -> Result<NumberEntry, LedgerError> {
+            self.ledger.recycle(prefix_key, number, now).await
+        }
+
+        async fn voided(
+            &self,
+            prefix_key: &str,
+            now: chrono::DateTime<chrono::Utc>,
+            settled: Option<std::ops::Range<chrono::DateTime<chrono::Utc>>>,
+        ) -> Result<Vec<NumberEntry>, LedgerError> {
+            self.ledger.voided(prefix_key, now, settled).await
+        }
+    }
+
+    /// Issues numbers like `MemorySequenceGenerator`, but cannot take any back.
+    struct NonRecyclingGenerator(MemorySequenceGenerator);
+
+    #[async_trait::async_trait]
+    impl SequenceGenerator for NonRecyclingGenerator {
+        async fn generate(&self, sequence_key: &str, prefix_rule: &PrefixRule) -> Result<u64, SequenceGeneratorError> {
+            self.0.generate(sequence_key, prefix_rule).await
+        }
+
+        async fn generate_range(
+            &self,
+            sequence_key: &str,
+            prefix_rule: &PrefixRule,
+            count: u64,
+        ) -> Result<SequenceRange, SequenceGeneratorError> {
+            self.0.generate_range(sequence_key, prefix_rule, count).await
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_register_and_generate_number() {
+        let app = test::init_service(App::new().configure(|cfg| AppState::in_memory().configure(cfg))).await;
 
         // Register prefix
         let register_payload = json!({
@@ -209,71 +1071,70 @@ mod tests {
         });
 
         let register_request = test::TestRequest::put()
-            .uri(&"/api/prefix-configs/TEST".to_string())
+            .uri("/api/prefix-configs/TEST")
             .set_json(&register_payload)
             .to_request();
 
         let register_response = test::call_service(&app, register_request).await;
-        let status = register_response.status();
-        let body = actix_web::body::to_bytes(register_response.into_body()).await.unwrap();
-        let body_str = String::from_utf8(body.to_vec()).unwrap();
-        println!("Register response status: {}", status);
-        println!("Register response body: {}", body_str);
-        assert_eq!(status, StatusCode::OK);
+        assert_eq!(register_response.status(), StatusCode::OK);
+        assert!(test::read_body(register_response).await.is_empty());
 
         // Generate number
         let generate_request = test::TestRequest::get()
-            .uri(&"/api/numbers/TEST".to_string())
+            .uri("/api/numbers/TEST")
             .to_request();
 
         let generate_response = test::call_service(&app, generate_request).await;
         assert_eq!(generate_response.status(), StatusCode::OK);
 
-        let body = test::read_body(generate_response).await;
-        let body_str = String::from_utf8(body.to_vec()).unwrap();
-        println!("Response body: {}", body_str);
+        let number_response: NumberResponse = test::read_body_json(generate_response).await;
+        let year = chrono::Utc::now().format("%Y").to_string();
+        assert_eq!(number_response.number, format!("TEST-{}-0001", year));
+    }
 
-        let number_response: NumberResponse = serde_json::from_str(&body_str).unwrap();
-        assert!(number_response.number.starts_with("TEST"));
-        assert!(number_response.number.contains("-2025-"));
-        assert_eq!(number_response.number.len(), 14); // TEST-2025-0001
+    #[actix_web::test]
+    async fn test_validate_generated_number() {
+        let app = test::init_service(App::new().configure(|cfg| AppState::in_memory().configure(cfg))).await;
+
+        // Register prefix with a Luhn check digit
+        let register_payload = json!({
+            "format": "CHK-{SEQ:6}{CHECK:luhn}",
+            "initialSeq": 1
+        });
+
+        let register_request = test::TestRequest::put()
+            .uri("/api/prefix-configs/CHK")
+            .set_json(&register_payload)
+            .to_request();
 
-        // Clear Redis after the test
-        let client = redis::Client::open(redis_url.clone()).unwrap();
-        let mut conn = client.get_connection().unwrap();
-        redis::cmd("FLUSHDB").execute(&mut conn);
+        let register_response = test::call_service(&app, register_request).await;
+        assert_eq!(register_response.status(), StatusCode::OK);
+
+        // Generate number
+        let generate_request = test::TestRequest::get()
+            .uri("/api/numbers/CHK")
+            .to_request();
+        let number_response: NumberResponse = test::call_and_read_body_json(&app, generate_request).await;
+
+        // The generated number validates
+        let validate_request = test::TestRequest::get()
+            .uri(&format!("/api/numbers/CHK/validate?number={}", number_response.number))
+            .to_request();
+        let validate_response: ValidateNumberResponse = test::call_and_read_body_json(&app, validate_request).await;
+        assert!(validate_response.valid);
+
+        // A mistyped sequence does not
+        let mistyped = number_response.number.replace("000001", "000010");
+        let validate_request = test::TestRequest::get()
+            .uri(&format!("/api/numbers/CHK/validate?number={}", mistyped))
+            .to_request();
+        let validate_response: ValidateNumberResponse = test::call_and_read_body_json(&app, validate_request).await;
+        assert!(!validate_response.valid);
     }
 
     #[actix_web::test]
     async fn test_register_prefix_invalid_format() {
-        let redis_url = "redis://localhost:6379/".to_string();
-        let client = redis::Client::open(redis_url.clone()).unwrap();
-        let mut conn = client.get_connection().unwrap();
-        let _ : () = redis::cmd("FLUSHDB").execute(&mut conn);
-
-        let prefix_rule_manager: Arc<Mutex<dyn PrefixRuleManager + Send + Sync>> = {
-            let redis_prefix_rule_manager = RedisPrefixRuleManager::new(redis_url.clone()).unwrap();
-            Arc::new(Mutex::new(redis_prefix_rule_manager))
-        };
-        let sequence_generator: Arc<RedisSequenceGenerator> = {
-            let redis_sequence_generator = RedisSequenceGenerator::new(redis_url.clone()).unwrap();
-            Arc::new(redis_sequence_generator)
-        };
-        let number_assembler = Arc::new(NumberAssembler::new());
-
-        let prefix_rule_manager_data = web::Data::new(prefix_rule_manager.clone());
-        let sequence_generator_data = web::Data::new(sequence_generator.clone());
-        let number_assembler_data = web::Data::new(number_assembler.clone());
-
-        let app = test::init_service(
-            App::new()
-                .app_data(prefix_rule_manager_data.clone())
-                .app_data(sequence_generator_data.clone())
-                .app_data(number_assembler_data.clone())
-                .route("/api/numbers/{prefixKey}", web::get().to(generate_number))
-                .route("/api/prefix-configs/{prefixKey}", web::put().to(register_prefix))
-        )
-        .await;
+        let app = test::init_service(App::new().configure(|cfg| AppState::in_memory().configure(cfg))).await;
 
         // Register prefix with invalid format
         let register_payload = json!({
@@ -283,100 +1144,129 @@ mod tests {
         });
 
         let register_request = test::TestRequest::put()
-            .uri(&"/api/prefix-configs/INVALID".to_string())
+            .uri("/api/prefix-configs/INVALID")
+            .set_json(&register_payload)
+            .to_request();
+
+        let register_response = test::call_service(&app, register_request).await;
+        assert_eq!(register_response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_register_prefix_unknown_placeholder() {
+        let app = test::init_service(App::new().configure(|cfg| AppState::in_memory().configure(cfg))).await;
+
+        // Register prefix with a misspelled placeholder
+        let register_payload = json!({
+            "format": "INV-{yaer}-{SEQ:4}",
+            "seqLength": 4,
+            "initialSeq": 1
+        });
+
+        let register_request = test::TestRequest::put()
+            .uri("/api/prefix-configs/TYPO")
             .set_json(&register_payload)
             .to_request();
 
         let register_response = test::call_service(&app, register_request).await;
         assert_eq!(register_response.status(), StatusCode::BAD_REQUEST);
 
-        // Clear Redis after the test
-        let client = redis::Client::open(redis_url.clone()).unwrap();
-        let mut conn = client.get_connection().unwrap();
-        redis::cmd("FLUSHDB").execute(&mut conn);
+        let body: serde_json::Value = test::read_body_json(register_response).await;
+        assert_eq!(body["token"], "{yaer}");
+        assert_eq!(body["offset"], 4);
+        assert_eq!(body["reason"], "unknown_placeholder");
     }
 
     #[actix_web::test]
-    async fn test_generate_number_prefix_not_registered() {
-        let redis_url = "redis://localhost:6379/".to_string();
-        let client = redis::Client::open(redis_url.clone()).unwrap();
-        let mut conn = client.get_connection().unwrap();
-        let _ : () = redis::cmd("FLUSHDB").execute(&mut conn);
-
-        let prefix_rule_manager: Arc<Mutex<dyn PrefixRuleManager + Send + Sync>> = {
-            let redis_prefix_rule_manager = RedisPrefixRuleManager::new(redis_url.clone()).unwrap();
-            Arc::new(Mutex::new(redis_prefix_rule_manager))
-        };
-        let sequence_generator: Arc<RedisSequenceGenerator> = {
-            let redis_sequence_generator = RedisSequenceGenerator::new(redis_url.clone()).unwrap();
-            Arc::new(redis_sequence_generator)
-        };
-        let number_assembler = Arc::new(NumberAssembler::new());
+    async fn test_register_prefix_seq_length_mismatch() {
+        let app = test::init_service(App::new().configure(|cfg| AppState::in_memory().configure(cfg))).await;
 
-        let prefix_rule_manager_data = web::Data::new(prefix_rule_manager.clone());
-        let sequence_generator_data = web::Data::new(sequence_generator.clone());
-        let number_assembler_data = web::Data::new(number_assembler.clone());
+        // Register prefix whose seqLength disagrees with the {SEQ:N} width
+        let register_payload = json!({
+            "format": "INV-{year}-{SEQ:6}",
+            "seqLength": 4,
+            "initialSeq": 1
+        });
 
-        let app = test::init_service(
-            App::new()
-                .app_data(prefix_rule_manager_data.clone())
-                .app_data(sequence_generator_data.clone())
-                .app_data(number_assembler_data.clone())
-                .route("/api/numbers/{prefixKey}", web::get().to(generate_number))
-                .route("/api/prefix-configs/{prefixKey}", web::put().to(register_prefix))
-        )
-        .await;
+        let register_request = test::TestRequest::put()
+            .uri("/api/prefix-configs/MISMATCH")
+            .set_json(&register_payload)
+            .to_request();
+
+        let register_response = test::call_service(&app, register_request).await;
+        assert_eq!(register_response.status(), StatusCode::BAD_REQUEST);
+
+        let body: serde_json::Value = test::read_body_json(register_response).await;
+        assert_eq!(body["token"], "{SEQ:6}");
+        assert_eq!(body["offset"], 11);
+        assert_eq!(body["reason"], "sequence_length_mismatch");
+    }
+
+    #[actix_web::test]
+    async fn test_register_prefix_offset_out_of_step() {
+        let app = test::init_service(App::new().configure(|cfg| AppState::in_memory().configure(cfg))).await;
+
+        // The format is fine; the offset does not fit the step
+        let register_payload = json!({
+            "format": "ODD-{SEQ:6}",
+            "initialSeq": 1,
+            "step": 2,
+            "offset": 2
+        });
+
+        let register_request = test::TestRequest::put()
+            .uri("/api/prefix-configs/ODD")
+            .set_json(&register_payload)
+            .to_request();
+
+        let register_response = test::call_service(&app, register_request).await;
+        assert_eq!(register_response.status(), StatusCode::BAD_REQUEST);
+
+        let body: serde_json::Value = test::read_body_json(register_response).await;
+        assert_eq!(body["error"], "Invalid prefix rule: sequence offset must be smaller than its step");
+        assert_eq!(body["reason"], "offset_out_of_step");
+        assert!(body.get("offset").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_register_prefix_invalid_timezone() {
+        let app = test::init_service(App::new().configure(|cfg| AppState::in_memory().configure(cfg))).await;
+
+        // Register prefix with an unknown time zone
+        let register_payload = json!({
+            "format": "TZ-{year}-{SEQ:4}",
+            "seqLength": 4,
+            "initialSeq": 1,
+            "timezone": "Asia/Atlantis"
+        });
+
+        let register_request = test::TestRequest::put()
+            .uri("/api/prefix-configs/TZ")
+            .set_json(&register_payload)
+            .to_request();
+
+        let register_response = test::call_service(&app, register_request).await;
+        assert_eq!(register_response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_generate_number_prefix_not_registered() {
+        let app = test::init_service(App::new().configure(|cfg| AppState::in_memory().configure(cfg))).await;
 
         // Generate number for unregistered prefix
         let generate_request = test::TestRequest::get()
-            .uri(&"/api/numbers/UNKNOWN".to_string())
+            .uri("/api/numbers/UNKNOWN")
             .to_request();
 
         let generate_response = test::call_service(&app, generate_request).await;
         assert_eq!(generate_response.status(), StatusCode::BAD_REQUEST);
 
-        let body = test::read_body(generate_response).await;
-        let body_str = String::from_utf8(body.to_vec()).unwrap();
-        println!("Response body: {}", body_str);
-        assert_eq!(body_str, "Prefix not registered");
-
-        // Clear Redis after the test
-        let client = redis::Client::open(redis_url.clone()).unwrap();
-        let mut conn = client.get_connection().unwrap();
-        redis::cmd("FLUSHDB").execute(&mut conn);
+        assert_eq!(test::read_body(generate_response).await, "Prefix not registered");
     }
 
     #[actix_web::test]
     async fn test_generate_number_network_partition() {
-        let redis_url = "redis://localhost:6379/".to_string();
-        let client = redis::Client::open(redis_url.clone()).unwrap();
-        let mut conn = client.get_connection().unwrap();
-        let _ : () = redis::cmd("FLUSHDB").execute(&mut conn);
-
-        let prefix_rule_manager: Arc<Mutex<dyn PrefixRuleManager + Send + Sync>> = {
-            let redis_prefix_rule_manager = RedisPrefixRuleManager::new(redis_url.clone()).unwrap();
-            Arc::new(Mutex::new(redis_prefix_rule_manager))
-        };
-        let sequence_generator: Arc<RedisSequenceGenerator> = {
-            let redis_sequence_generator = RedisSequenceGenerator::new(redis_url.clone()).unwrap();
-            Arc::new(redis_sequence_generator)
-        };
-        let number_assembler = Arc::new(NumberAssembler::new());
-
-        let prefix_rule_manager_data = web::Data::new(prefix_rule_manager.clone());
-        let sequence_generator_data = web::Data::new(sequence_generator.clone());
-        let number_assembler_data = web::Data::new(number_assembler.clone());
-
-        let app = test::init_service(
-            App::new()
-                .app_data(prefix_rule_manager_data.clone())
-                .app_data(sequence_generator_data.clone())
-                .app_data(number_assembler_data.clone())
-                .route("/api/numbers/{prefixKey}", web::get().to(generate_number))
-                .route("/api/prefix-configs/{prefixKey}", web::put().to(register_prefix))
-                .route("/api/prefix-configs/{prefixKey}/network-partition", web::post().to(set_network_partition))
-        )
-        .await;
+        let app = test::init_service(App::new().configure(|cfg| AppState::in_memory().configure(cfg))).await;
 
         // Register prefix
         let register_payload = json!({
@@ -386,7 +1276,7 @@ mod tests {
         });
 
         let register_request = test::TestRequest::put()
-            .uri(&"/api/prefix-configs/TEST".to_string())
+            .uri("/api/prefix-configs/TEST")
             .set_json(&register_payload)
             .to_request();
 
@@ -396,7 +1286,7 @@ mod tests {
 
         // Set network partition
         let network_partition_request = test::TestRequest::post()
-            .uri(&"/api/prefix-configs/TEST/network-partition".to_string())
+            .uri("/api/prefix-configs/TEST/network-partition")
             .to_request();
 
         let network_partition_response = test::call_service(&app, network_partition_request).await;
@@ -404,7 +1294,7 @@ mod tests {
 
         // Generate number
         let generate_request = test::TestRequest::get()
-            .uri(&"/api/numbers/TEST".to_string())
+            .uri("/api/numbers/TEST")
             .to_request();
 
         let generate_response = test::call_service(&app, generate_request).await;
@@ -415,10 +1305,457 @@ mod tests {
 
         let number_response: NumberResponse = serde_json::from_str(&body_str).unwrap();
         assert!(number_response.number.ends_with("-NP"));
+    }
+
+    #[actix_web::test]
+    async fn test_generate_number_overflow() {
+        let storage = Arc::new(MemoryStorage::new());
+        let app = test::init_service(App::new().configure(|cfg| AppState::in_memory().with_storage(&storage).configure(cfg))).await;
+
+        let register_request = test::TestRequest::put()
+            .uri("/api/prefix-configs/TINY")
+            .set_json(json!({ "format": "{prefix}-{SEQ:1}", "initialSeq": 1 }))
+            .to_request();
+        assert_eq!(test::call_service(&app, register_request).await.status(), StatusCode::OK);
+
+        storage.set("seq:TINY", "8").await.unwrap();
+        let generate_request = test::TestRequest::get().uri("/api/numbers/TINY").to_request();
+        let number_response: NumberResponse = test::call_and_read_body_json(&app, generate_request).await;
+        assert_eq!(number_response.number, "TINY-9");
+
+        let generate_request = test::TestRequest::get().uri("/api/numbers/TINY").to_request();
+        let generate_response = test::call_service(&app, generate_request).await;
+        assert_eq!(generate_response.status(), StatusCode::CONFLICT);
+
+        // Counters stop at i64::MAX, as Redis does.
+        storage.set("seq:TINY", &i64::MAX.to_string()).await.unwrap();
+        let generate_request = test::TestRequest::get().uri("/api/numbers/TINY").to_request();
+        let generate_response = test::call_service(&app, generate_request).await;
+        assert_eq!(generate_response.status(), StatusCode::CONFLICT);
+    }
+
+    #[actix_web::test]
+    async fn test_generate_number_with_variables() {
+        let app = test::init_service(App::new().configure(|cfg| AppState::in_memory().configure(cfg))).await;
+
+        let register_request = test::TestRequest::put()
+            .uri("/api/prefix-configs/ORDER")
+            .set_json(json!({
+                "format": "{region}-{dept}-{SEQ:5}",
+                "initialSeq": 1,
+                "variables": [
+                    { "name": "region", "allowed": ["TW", "JP"] },
+                    { "name": "dept", "pattern": "[A-Z]{2}" }
+                ]
+            }))
+            .to_request();
+        assert_eq!(test::call_service(&app, register_request).await.status(), StatusCode::OK);
+
+        let generate_request = test::TestRequest::get().uri("/api/numbers/ORDER?region=TW&dept=HR").to_request();
+        let number_response: NumberResponse = test::call_and_read_body_json(&app, generate_request).await;
+        assert_eq!(number_response.number, "TW-HR-00001");
 
-        // Clear Redis after the test
-        let client = redis::Client::open(redis_url.clone()).unwrap();
-        let mut conn = client.get_connection().unwrap();
-        redis::cmd("FLUSHDB").execute(&mut conn);
+        let generate_request = test::TestRequest::post()
+            .uri("/api/numbers/ORDER")
+            .set_json(json!({ "region": "JP", "dept": "IT" }))
+            .to_request();
+        let number_response: NumberResponse = test::call_and_read_body_json(&app, generate_request).await;
+        assert_eq!(number_response.number, "JP-IT-00002");
+
+        // Query parameters meant for something else are ignored.
+        let generate_request = test::TestRequest::get().uri("/api/numbers/ORDER?region=TW&dept=HR&trace=1").to_request();
+        let number_response: NumberResponse = test::call_and_read_body_json(&app, generate_request).await;
+        assert_eq!(number_response.number, "TW-HR-00003");
+
+        for uri in ["/api/numbers/ORDER?region=US&dept=HR", "/api/numbers/ORDER?region=TW"] {
+            let generate_request = test::TestRequest::get().uri(uri).to_request();
+            assert_eq!(test::call_service(&app, generate_request).await.status(), StatusCode::BAD_REQUEST);
+        }
+
+        for body in [r#"{"region": "TW", "dept": "HR""#, r#"{"region": "TW", "dept": 7}"#, r#"{"region": "TW", "dept": "HR", "x": "1"}"#] {
+            let generate_request = test::TestRequest::post()
+                .uri("/api/numbers/ORDER")
+                .insert_header(("content-type", "application/json"))
+                .set_payload(body)
+                .to_request();
+            assert_eq!(test::call_service(&app, generate_request).await.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_sequence_scope_isolation() {
+        let storage = Arc::new(MemoryStorage::new());
+        let app = test::init_service(App::new().configure(|cfg| AppState::in_memory().with_storage(&storage).configure(cfg))).await;
+
+        let register_request = test::TestRequest::put()
+            .uri("/api/prefix-configs/ORDER")
+            .set_json(json!({
+                "format": "{region}-{date:%Y%m%d}-{SEQ:4}",
+                "initialSeq": 1,
+                "variables": [{ "name": "region", "allowed": ["TW", "JP"] }],
+                "sequenceScope": ["region", "date:%Y%m%d"]
+            }))
+            .to_request();
+        assert_eq!(test::call_service(&app, register_request).await.status(), StatusCode::OK);
+
+        for (region, expected) in [("TW", "0001"), ("JP", "0001"), ("TW", "0002"), ("JP", "0002")] {
+            let generate_request = test::TestRequest::get().uri(&format!("/api/numbers/ORDER?region={}", region)).to_request();
+            let number_response: NumberResponse = test::call_and_read_body_json(&app, generate_request).await;
+            assert!(number_response.number.starts_with(region));
+            assert!(number_response.number.ends_with(expected));
+        }
+
+        let today = chrono::Utc::now().format("%Y%m%d").to_string();
+        let counter = storage.counter(&format!("seq:ORDER:TW:{}", today)).await.unwrap();
+        assert_eq!(counter, Some(2));
+        assert_eq!(storage.get("seq:ORDER").await.unwrap(), None);
+    }
+
+    #[actix_web::test]
+    async fn test_yearly_reset_policy() {
+        let storage = Arc::new(MemoryStorage::new());
+        let app = test::init_service(App::new().configure(|cfg| AppState::in_memory().with_storage(&storage).configure(cfg))).await;
+
+        let register_request = test::TestRequest::put()
+            .uri("/api/prefix-configs/INV")
+            .set_json(json!({ "format": "INV-{MM}-{SEQ:4}", "initialSeq": 1, "resetPolicy": "yearly" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, register_request).await.status(), StatusCode::BAD_REQUEST);
+
+        let register_request = test::TestRequest::put()
+            .uri("/api/prefix-configs/INV")
+            .set_json(json!({ "format": "INV-{year}-{SEQ:4}", "initialSeq": 100, "resetPolicy": "yearly" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, register_request).await.status(), StatusCode::OK);
+
+        // Last year's counter is left alone; this year's starts at initialSeq.
+        let year = chrono::Utc::now().format("%Y").to_string();
+        let last_year = year.parse::<i32>().unwrap() - 1;
+        storage.set(&format!("seq:INV:{}", last_year), "4321").await.unwrap();
+
+        let generate_request = test::TestRequest::get().uri("/api/numbers/INV").to_request();
+        let number_response: NumberResponse = test::call_and_read_body_json(&app, generate_request).await;
+        assert_eq!(number_response.number, format!("INV-{}-0100", year));
+
+        let generate_request = test::TestRequest::get().uri("/api/numbers/INV").to_request();
+        let number_response: NumberResponse = test::call_and_read_body_json(&app, generate_request).await;
+        assert_eq!(number_response.number, format!("INV-{}-0101", year));
+    }
+
+    #[actix_web::test]
+    async fn test_generate_batch() {
+        let app = test::init_service(App::new().configure(|cfg| AppState::in_memory().configure(cfg))).await;
+
+        let register_request = test::TestRequest::put()
+            .uri("/api/prefix-configs/BULK")
+            .set_json(json!({ "format": "{prefix}-{SEQ:4}", "initialSeq": 1, "maxBatch": 5 }))
+            .to_request();
+        assert_eq!(test::call_service(&app, register_request).await.status(), StatusCode::OK);
+
+        let batch_request = test::TestRequest::post()
+            .uri("/api/numbers/BULK/batch")
+            .set_json(json!({ "count": 3 }))
+            .to_request();
+        let batch_response: BatchResponse = test::call_and_read_body_json(&app, batch_request).await;
+        assert_eq!(batch_response.numbers, vec!["BULK-0001", "BULK-0002", "BULK-0003"]);
+
+        let generate_request = test::TestRequest::get().uri("/api/numbers/BULK").to_request();
+        let number_response: NumberResponse = test::call_and_read_body_json(&app, generate_request).await;
+        assert_eq!(number_response.number, "BULK-0004");
+
+        for count in [0, 6] {
+            let batch_request = test::TestRequest::post()
+                .uri("/api/numbers/BULK/batch")
+                .set_json(json!({ "count": count }))
+                .to_request();
+            assert_eq!(test::call_service(&app, batch_request).await.status(), StatusCode::BAD_REQUEST);
+        }
+
+        // A wrapping two-digit sequence renders 100 distinct numbers; a larger batch would
+        // repeat some of them and is refused before the counter moves.
+        let register_request = test::TestRequest::put()
+            .uri("/api/prefix-configs/TINY")
+            .set_json(json!({ "format": "{prefix}-{SEQ:2}", "initialSeq": 1, "overflowPolicy": "wrap" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, register_request).await.status(), StatusCode::OK);
+        let batch_request = test::TestRequest::post()
+            .uri("/api/numbers/TINY/batch")
+            .set_json(json!({ "count": 101 }))
+            .to_request();
+        assert_eq!(test::call_service(&app, batch_request).await.status(), StatusCode::BAD_REQUEST);
+        let generate_request = test::TestRequest::get().uri("/api/numbers/TINY").to_request();
+        let number_response: NumberResponse = test::call_and_read_body_json(&app, generate_request).await;
+        assert_eq!(number_response.number, "TINY-01");
+    }
+
+    #[actix_web::test]
+    async fn test_stream_format_lines() {
+        let mut out = String::new();
+        StreamFormat::Ndjson.write_line("LAB-\"1\"", &mut out);
+        StreamFormat::Csv.write_line("LAB-0001", &mut out);
+        StreamFormat::Csv.write_line("LAB,\"1\"", &mut out);
+        assert_eq!(out, "{\"number\":\"LAB-\\\"1\\\"\"}\nLAB-0001\n\"LAB,\"\"1\"\"\"\n");
+    }
+
+    #[actix_web::test]
+    async fn test_stream_range() {
+        let app = test::init_service(App::new().configure(|cfg| AppState::in_memory().configure(cfg))).await;
+
+        let register_request = test::TestRequest::put()
+            .uri("/api/prefix-configs/LABEL")
+            .set_json(json!({ "format": "{prefix}-{SEQ:6}", "initialSeq": 1, "maxRange": 5000 }))
+            .to_request();
+        assert_eq!(test::call_service(&app, register_request).await.status(), StatusCode::OK);
+
+        let csv_request = test::TestRequest::post()
+            .uri("/api/numbers/LABEL/range")
+            .set_json(json!({ "count": 2500, "format": "csv" }))
+            .to_request();
+        let body = test::call_and_read_body(&app, csv_request).await;
+        let lines: Vec<&str> = std::str::from_utf8(&body).unwrap().lines().collect();
+        assert_eq!(lines.len(), 2501);
+        assert_eq!(lines[0], "number");
+        assert_eq!(lines[1], "LABEL-000001");
+        assert_eq!(lines[2500], "LABEL-002500");
+
+        let ndjson_request = test::TestRequest::post()
+            .uri("/api/numbers/LABEL/range")
+            .set_json(json!({ "count": 2 }))
+            .to_request();
+        let body = test::call_and_read_body(&app, ndjson_request).await;
+        assert_eq!(body, "{\"number\":\"LABEL-002501\"}\n{\"number\":\"LABEL-002502\"}\n");
+
+        let oversized_request = test::TestRequest::post()
+            .uri("/api/numbers/LABEL/range")
+            .set_json(json!({ "count": 5001 }))
+            .to_request();
+        assert_eq!(test::call_service(&app, oversized_request).await.status(), StatusCode::BAD_REQUEST);
+
+        let list_request = test::TestRequest::get().uri("/api/numbers/LABEL/ranges").to_request();
+        let reservations: Vec<RangeReservation> = test::call_and_read_body_json(&app, list_request).await;
+        let ranges: Vec<(u64, u64)> = reservations.iter().map(|r| (r.range.start, r.range.count)).collect();
+        assert_eq!(ranges, vec![(1, 2500), (2501, 2)]);
+
+        // A wrapping range that would start over partway through is refused, but the
+        // block it took from the counter stays on record.
+        let register_request = test::TestRequest::put()
+            .uri("/api/prefix-configs/LAP")
+            .set_json(json!({ "format": "{prefix}-{SEQ:2}", "initialSeq": 1, "overflowPolicy": "wrap" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, register_request).await.status(), StatusCode::OK);
+        for (count, status) in [(60, StatusCode::OK), (60, StatusCode::CONFLICT), (101, StatusCode::BAD_REQUEST)] {
+            let range_request = test::TestRequest::post()
+                .uri("/api/numbers/LAP/range")
+                .set_json(json!({ "count": count }))
+                .to_request();
+            assert_eq!(test::call_service(&app, range_request).await.status(), status);
+        }
+        let list_request = test::TestRequest::get().uri("/api/numbers/LAP/ranges").to_request();
+        let reservations: Vec<RangeReservation> = test::call_and_read_body_json(&app, list_request).await;
+        let ranges: Vec<(u64, u64)> = reservations.iter().map(|r| (r.range.start, r.range.count)).collect();
+        assert_eq!(ranges, vec![(1, 60), (61, 60)]);
+    }
+
+    #[actix_web::test]
+    async fn test_snowflake_prefix() {
+        let storage = Arc::new(MemoryStorage::new());
+        let sequence_generator: Arc<dyn SequenceGenerator + Send + Sync> = {
+            let counter = StorageSequenceGenerator::with_storage(storage.clone());
+            let snowflake = SnowflakeSequenceGenerator::new(7).unwrap();
+            Arc::new(RoutingSequenceGenerator::new(Arc::new(counter)).with(GeneratorKind::Snowflake, Arc::new(snowflake)))
+        };
+        let app = test::init_service(
+            App::new().configure(|cfg| AppState::in_memory().with_sequence_generator(sequence_generator).configure(cfg))
+        )
+        .await;
+
+        let register_request = test::TestRequest::put()
+            .uri("/api/prefix-configs/SNOW")
+            .set_json(json!({ "format": "{prefix}-{SEQ:8}", "initialSeq": 1, "generator": "snowflake" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, register_request).await.status(), StatusCode::BAD_REQUEST);
+
+        let register_request = test::TestRequest::put()
+            .uri("/api/prefix-configs/SNOW")
+            .set_json(json!({ "format": "{prefix}-{SEQ:19}", "initialSeq": 1, "generator": "snowflake" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, register_request).await.status(), StatusCode::OK);
+
+        let mut numbers = Vec::new();
+        for _ in 0..2 {
+            let generate_request = test::TestRequest::get().uri("/api/numbers/SNOW").to_request();
+            let number_response: NumberResponse = test::call_and_read_body_json(&app, generate_request).await;
+            numbers.push(number_response.number);
+        }
+        assert_eq!(numbers[0].len(), "SNOW-".len() + 19);
+        assert!(numbers[1] > numbers[0]);
+        assert_eq!(storage.counter("seq:SNOW").await.unwrap(), None);
+
+        // More than one millisecond's worth is refused up front rather than failing later.
+        for uri in ["/api/numbers/SNOW/batch", "/api/numbers/SNOW/range"] {
+            let request = test::TestRequest::post().uri(uri).set_json(json!({ "count": 4097 })).to_request();
+            assert_eq!(test::call_service(&app, request).await.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_report_period_runs_midnight_to_midnight() {
+        let day = |y, m, d| chrono::NaiveDate::from_ymd_opt(y, m, d).unwrap();
+        assert_eq!(report_period_days("2026"), Some(day(2026, 1, 1)..day(2027, 1, 1)));
+        assert_eq!(report_period_days("2026-12"), Some(day(2026, 12, 1)..day(2027, 1, 1)));
+        assert_eq!(report_period_days("2026-02-28"), Some(day(2026, 2, 28)..day(2026, 3, 1)));
+
+        let taipei: chrono_tz::Tz = "Asia/Taipei".parse().unwrap();
+        assert_eq!(local_midnight(day(2026, 10, 17), &taipei).to_rfc3339(), "2026-10-16T16:00:00+00:00");
+        // Midnight is skipped when Santiago moves its clocks forward.
+        let santiago: chrono_tz::Tz = "America/Santiago".parse().unwrap();
+        assert_eq!(local_midnight(day(2026, 9, 6), &santiago).to_rfc3339(), "2026-09-06T04:00:00+00:00");
+    }
+
+    #[actix_web::test]
+    async fn test_reserve_confirm_and_void() {
+        let app = test::init_service(App::new().configure(|cfg| AppState::in_memory().configure(cfg))).await;
+
+        let register_request = test::TestRequest::put()
+            .uri("/api/prefix-configs/INV")
+            .set_json(json!({ "format": "{prefix}-{SEQ:4}", "initialSeq": 1, "reservationTtl": 0 }))
+            .to_request();
+        assert_eq!(test::call_service(&app, register_request).await.status(), StatusCode::OK);
+
+        let mut reserved = Vec::new();
+        for _ in 0..2 {
+            let reserve_request = test::TestRequest::post().uri("/api/numbers/INV/reservations").to_request();
+            let entry: NumberEntry = test::call_and_read_body_json(&app, reserve_request).await;
+            assert_eq!(entry.status, NumberStatus::Reserved);
+            reserved.push(entry.number);
+        }
+        assert_eq!(reserved, vec!["INV-0001", "INV-0002"]);
+
+        // With a TTL of zero, both reservations are already overdue.
+        let confirm_request = test::TestRequest::post().uri("/api/numbers/INV/reservations/INV-0001/confirm").to_request();
+        assert_eq!(test::call_service(&app, confirm_request).await.status(), StatusCode::CONFLICT);
+        let void_request = test::TestRequest::post().uri("/api/numbers/INV/reservations/INV-9999/void").to_request();
+        assert_eq!(test::call_service(&app, void_request).await.status(), StatusCode::NOT_FOUND);
+
+        let period = chrono::Utc::now().format("%Y-%m").to_string();
+        let report_request = test::TestRequest::get().uri(&format!("/api/numbers/INV/voided?period={}", period)).to_request();
+        let report: VoidedReportResponse = test::call_and_read_body_json(&app, report_request).await;
+        let statuses: Vec<(String, NumberStatus)> = report.numbers.into_iter().map(|e| (e.number, e.status)).collect();
+        assert_eq!(
+            statuses,
+            vec![("INV-0001".to_string(), NumberStatus::Expired), ("INV-0002".to_string(), NumberStatus::Expired)]
+        );
+
+        let report_request = test::TestRequest::get().uri("/api/numbers/INV/voided?period=1999").to_request();
+        let report: VoidedReportResponse = test::call_and_read_body_json(&app, report_request).await;
+        assert!(report.numbers.is_empty());
+
+        for period in ["2", "2026-1", "2026-13", "2026-02-30", "2026/10", "+026"] {
+            let report_request = test::TestRequest::get().uri(&format!("/api/numbers/INV/voided?period={}", period)).to_request();
+            assert_eq!(test::call_service(&app, report_request).await.status(), StatusCode::BAD_REQUEST, "{}", period);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_reservation_survives_ledger_failures() {
+        let register = json!({ "format": "{prefix}-{SEQ:4}", "initialSeq": 1 });
+
+        // Failed writes are retried.
+        let state = AppState::in_memory().with_number_ledger(Arc::new(FailingNumberLedger { failures: 2.into(), ..Default::default() }));
+        let app = test::init_service(App::new().configure(|cfg| state.configure(cfg))).await;
+        let register_request = test::TestRequest::put().uri("/api/prefix-configs/INV").set_json(&register).to_request();
+        assert_eq!(test::call_service(&app, register_request).await.status(), StatusCode::OK);
+        let reserve_request = test::TestRequest::post().uri("/api/numbers/INV/reservations").to_request();
+        let entry: NumberEntry = test::call_and_read_body_json(&app, reserve_request).await;
+        assert_eq!(entry.status, NumberStatus::Reserved);
+
+        // A ledger that keeps refusing the reservation gets the number as voided instead.
+        let state = AppState::in_memory().with_number_ledger(Arc::new(FailingNumberLedger { refuse_reservations: true, ..Default::default() }));
+        let app = test::init_service(App::new().configure(|cfg| state.configure(cfg))).await;
+        let register_request = test::TestRequest::put().uri("/api/prefix-configs/INV").set_json(&register).to_request();
+        assert_eq!(test::call_service(&app, register_request).await.status(), StatusCode::OK);
+        let reserve_request = test::TestRequest::post().uri("/api/numbers/INV/reservations").to_request();
+        assert_eq!(test::call_service(&app, reserve_request).await.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let report_request = test::TestRequest::get().uri("/api/numbers/INV/voided").to_request();
+        let report: VoidedReportResponse = test::call_and_read_body_json(&app, report_request).await;
+        let statuses: Vec<(String, NumberStatus)> = report.numbers.into_iter().map(|e| (e.number, e.status)).collect();
+        assert_eq!(statuses, vec![("INV-0001".to_string(), NumberStatus::Voided)]);
+    }
+
+    #[actix_web::test]
+    async fn test_release_and_recycle() {
+        let app = test::init_service(App::new().configure(|cfg| AppState::in_memory().configure(cfg))).await;
+
+        let register_request = test::TestRequest::put()
+            .uri("/api/prefix-configs/FORM")
+            .set_json(json!({ "format": "{prefix}-{SEQ:4}", "initialSeq": 1, "recycle": true }))
+            .to_request();
+        assert_eq!(test::call_service(&app, register_request).await.status(), StatusCode::OK);
+
+        for _ in 0..3 {
+            let reserve_request = test::TestRequest::post().uri("/api/numbers/FORM/reservations").to_request();
+            assert_eq!(test::call_service(&app, reserve_request).await.status(), StatusCode::OK);
+        }
+        for (number, action) in [("FORM-0001", "confirm"), ("FORM-0002", "void")] {
+            let settle_request = test::TestRequest::post()
+                .uri(&format!("/api/numbers/FORM/reservations/{}/{}", number, action))
+                .to_request();
+            assert_eq!(test::call_service(&app, settle_request).await.status(), StatusCode::OK);
+        }
+        let release = |number: &str| {
+            test::TestRequest::post().uri("/api/numbers/FORM/release").set_json(json!({ "number": number })).to_request()
+        };
+
+        let release_response: ReleaseResponse = test::call_and_read_body_json(&app, release("FORM-0002")).await;
+        assert!(release_response.released);
+
+        // Confirmed and still reserved numbers are in use; an unknown one was never issued.
+        assert_eq!(test::call_service(&app, release("FORM-0001")).await.status(), StatusCode::CONFLICT);
+        assert_eq!(test::call_service(&app, release("FORM-0003")).await.status(), StatusCode::CONFLICT);
+        assert_eq!(test::call_service(&app, release("FORM-0009")).await.status(), StatusCode::NOT_FOUND);
+
+        let mut numbers = Vec::new();
+        for _ in 0..2 {
+            let reserve_request = test::TestRequest::post().uri("/api/numbers/FORM/reservations").to_request();
+            let entry: NumberEntry = test::call_and_read_body_json(&app, reserve_request).await;
+            numbers.push(entry.number);
+        }
+        assert_eq!(numbers, vec!["FORM-0002", "FORM-0004"]);
+
+        // The reissued number is reserved again, so releasing it a second time is refused
+        // instead of putting it on the free list twice.
+        assert_eq!(test::call_service(&app, release("FORM-0002")).await.status(), StatusCode::CONFLICT);
+        let generate_request = test::TestRequest::get().uri("/api/numbers/FORM").to_request();
+        let number_response: NumberResponse = test::call_and_read_body_json(&app, generate_request).await;
+        assert_eq!(number_response.number, "FORM-0005");
+    }
+
+    #[actix_web::test]
+    async fn test_failed_release_keeps_the_number_voided() {
+        let state = AppState::in_memory().with_sequence_generator(Arc::new(NonRecyclingGenerator(MemorySequenceGenerator::new())));
+        let app = test::init_service(App::new().configure(|cfg| state.configure(cfg))).await;
+
+        let register_request = test::TestRequest::put()
+            .uri("/api/prefix-configs/FORM")
+            .set_json(json!({ "format": "{prefix}-{SEQ:4}", "initialSeq": 1, "recycle": true }))
+            .to_request();
+        assert_eq!(test::call_service(&app, register_request).await.status(), StatusCode::OK);
+        let reserve_request = test::TestRequest::post().uri("/api/numbers/FORM/reservations").to_request();
+        assert_eq!(test::call_service(&app, reserve_request).await.status(), StatusCode::OK);
+        let void_request = test::TestRequest::post().uri("/api/numbers/FORM/reservations/FORM-0001/void").to_request();
+        assert_eq!(test::call_service(&app, void_request).await.status(), StatusCode::OK);
+
+        // The release fails each time, and each time the number is still there to release.
+        for _ in 0..2 {
+            let release_request = test::TestRequest::post()
+                .uri("/api/numbers/FORM/release")
+                .set_json(json!({ "number": "FORM-0001" }))
+                .to_request();
+            assert_eq!(test::call_service(&app, release_request).await.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        let report_request = test::TestRequest::get().uri("/api/numbers/FORM/voided").to_request();
+        let report: VoidedReportResponse = test::call_and_read_body_json(&app, report_request).await;
+        let statuses: Vec<(String, NumberStatus)> = report.numbers.into_iter().map(|e| (e.number, e.status)).collect();
+        assert_eq!(statuses, vec![("FORM-0001".to_string(), NumberStatus::Voided)]);
     }
 }
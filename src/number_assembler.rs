@@ -1,7 +1,10 @@
-use regex::Regex;
+use chrono::{DateTime, Utc};
 use std::collections::HashMap;
+use crate::format_template::RenderContext;
 use crate::prefix_rule::PrefixRule;
+use crate::sequence_permutation::ScrambleKey;
 
+#[derive(Default)]
 pub struct NumberAssembler {}
 
 impl NumberAssembler {
@@ -15,39 +18,150 @@ impl NumberAssembler {
         prefix_rule: &PrefixRule,
         sequence: u64,
     ) -> Result<String, String> {
-        let mut replacements: HashMap<String, String> = HashMap::new();
-        replacements.insert("prefix".to_string(), prefix.to_string());
-
-        let year = chrono::Utc::now().format("%Y").to_string();
-        replacements.insert("year".to_string(), year);
-
-        let seq_formatted = format!("{:0width$}", sequence, width = prefix_rule.seq_length as usize);
-        replacements.insert("SEQ".to_string(), seq_formatted);
+        self.assemble_number_at(prefix, prefix_rule, sequence, Utc::now())
+    }
 
-        let mut formatted_number = prefix_rule.format.clone();
+    /// Same as `assemble_number`, but renders the date/time placeholders against `now`
+    /// instead of the wall clock, so callers (and tests) can pin the moment of issue.
+    /// `now` is converted into the rule's time zone before any date token is rendered.
+    pub fn assemble_number_at(
+        &self,
+        prefix: &str,
+        prefix_rule: &PrefixRule,
+        sequence: u64,
+        now: DateTime<Utc>,
+    ) -> Result<String, String> {
+        self.assemble_number_with(prefix, prefix_rule, sequence, &HashMap::new(), now)
+    }
 
-        let re = Regex::new(r"\{([A-Za-z0-9_]+)(?::(\d+))?\}").unwrap();
-        for capture in re.captures_iter(&prefix_rule.format) {
-            let full_match = capture.get(0).unwrap().as_str();
-            let variable_name = capture.get(1).unwrap().as_str();
+    /// The general form of `assemble_number_at`, also filling in the rule's template
+    /// variables from `variables`, which are checked against their declarations first.
+    pub fn assemble_number_with(
+        &self,
+        prefix: &str,
+        prefix_rule: &PrefixRule,
+        sequence: u64,
+        variables: &HashMap<String, String>,
+        now: DateTime<Utc>,
+    ) -> Result<String, String> {
+        prefix_rule.check_variables(variables).map_err(|e| e.to_string())?;
+        // Uses the template compiled when the rule was registered or loaded; rules built
+        // by hand (e.g. in tests) are compiled on the fly.
+        let template = prefix_rule.template().map_err(|e| e.to_string())?;
+        let ctx = RenderContext {
+            prefix,
+            sequence,
+            seq_length: prefix_rule.seq_length,
+            now: now.with_timezone(&prefix_rule.time_zone()?),
+            scramble_key: prefix_rule.scramble_key.as_deref().map(ScrambleKey::from_secret),
+            variables,
+        };
 
-            if let Some(replacement_value) = replacements.get(variable_name) {
-                formatted_number = formatted_number.replace(full_match, replacement_value);
-            }
-        }
+        let mut formatted_number = template.render(&ctx)?;
 
         if prefix_rule.network_partition {
-            formatted_number = format!("{}-NP", formatted_number);
+            formatted_number.push_str("-NP");
         }
 
         Ok(formatted_number)
     }
+
+    /// Checks that `number` could have been produced by `prefix_rule` for `prefix`: it has
+    /// the template's shape and every `{CHECK:...}` digit in it is correct.
+    pub fn validate_number(&self, prefix: &str, prefix_rule: &PrefixRule, number: &str) -> Result<bool, String> {
+        let template = prefix_rule.template().map_err(|e| e.to_string())?;
+        let verifier = prefix_rule.verifier(prefix)?;
+        let number = strip_partition_suffix(prefix_rule, number);
+        Ok(template.verify(&verifier, number))
+    }
+
+    /// Recovers the counter value behind a number issued for `prefix_rule`. Meant for
+    /// support staff: for a scrambled prefix the caller has to supply the rule's secret,
+    /// and a wrong secret yields a wrong (but well-formed) value.
+    pub fn reveal_sequence(
+        &self,
+        prefix: &str,
+        prefix_rule: &PrefixRule,
+        number: &str,
+        scramble_secret: Option<&str>,
+    ) -> Result<u64, String> {
+        let template = prefix_rule.template().map_err(|e| e.to_string())?;
+        let verifier = prefix_rule.verifier(prefix)?;
+        let number = strip_partition_suffix(prefix_rule, number);
+        let key = scramble_secret.map(ScrambleKey::from_secret);
+        template
+            .extract_sequence(&verifier, number, key)
+            .ok_or_else(|| format!("{} is not a number of prefix {}", number, prefix))
+    }
+
+    /// Recovers the sequence behind `number`, provided the same sequence still renders as
+    /// `number` with `variables` at `now`. A number from another period or scope is
+    /// rejected: its sequence belongs to a different counter.
+    pub fn current_sequence(
+        &self,
+        prefix: &str,
+        prefix_rule: &PrefixRule,
+        number: &str,
+        variables: &HashMap<String, String>,
+        now: DateTime<Utc>,
+    ) -> Result<u64, String> {
+        let sequence = self.reveal_sequence(prefix, prefix_rule, number, prefix_rule.scramble_key.as_deref())?;
+        let rendered = self.assemble_number_with(prefix, prefix_rule, sequence, variables, now)?;
+        if strip_partition_suffix(prefix_rule, &rendered) != strip_partition_suffix(prefix_rule, number) {
+            return Err(format!("{} was not issued in the current period and scope", number));
+        }
+        Ok(sequence)
+    }
+}
+
+fn strip_partition_suffix<'n>(prefix_rule: &PrefixRule, number: &'n str) -> &'n str {
+    match prefix_rule.network_partition {
+        true => number.strip_suffix("-NP").unwrap_or(number),
+        false => number,
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::prefix_rule::PrefixRule;
+    use crate::template_variable::TemplateVariable;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_assemble_number_uses_compiled_template() {
+        let mut prefix_rule = rule_with_format("{prefix}-{SEQ:4}");
+        prefix_rule.compile().unwrap();
+        // The cached template wins over the raw format string once compiled.
+        prefix_rule.format = "ignored".to_string();
+
+        let assembled_number = NumberAssembler::new()
+            .assemble_number_at("TEST", &prefix_rule, 9, fixed_now())
+            .unwrap();
+        assert_eq!(assembled_number, "TEST-0009");
+    }
+
+    fn rule_with_format(format: &str) -> PrefixRule {
+        PrefixRule {
+            prefix_key: "TEST".to_string(),
+            format: format.to_string(),
+            seq_length: 4,
+            initial_seq: 1,
+            network_partition: false,
+            ..Default::default()
+        }
+    }
+
+    /// 2026-01-02 (a Friday, ISO week 1 of 2026), 09:05:07 UTC.
+    fn fixed_now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2026, 1, 2, 9, 5, 7).unwrap()
+    }
+
+    fn render_at(format: &str, now: DateTime<Utc>) -> String {
+        NumberAssembler::new()
+            .assemble_number_at("TEST", &rule_with_format(format), 7, now)
+            .unwrap()
+    }
 
     #[test]
     fn test_assemble_number() {
@@ -59,6 +173,7 @@ mod tests {
             seq_length: 4,
             initial_seq: 1,
             network_partition: false,
+            ..Default::default()
         };
         let sequence = 123;
 
@@ -78,10 +193,188 @@ mod tests {
             seq_length: 6,
             initial_seq: 1,
             network_partition: false,
+            ..Default::default()
         };
         let sequence = 456;
 
         let assembled_number = assembler.assemble_number(prefix, &prefix_rule, sequence).unwrap();
         assert_eq!(assembled_number, "ORDER-000456");
     }
+
+    #[test]
+    fn test_year_tokens() {
+        assert_eq!(render_at("{year}", fixed_now()), "2026");
+        assert_eq!(render_at("{yyyy}", fixed_now()), "2026");
+        assert_eq!(render_at("{yy}", fixed_now()), "26");
+    }
+
+    #[test]
+    fn test_month_and_day_tokens() {
+        assert_eq!(render_at("{MM}", fixed_now()), "01");
+        assert_eq!(render_at("{dd}", fixed_now()), "02");
+    }
+
+    #[test]
+    fn test_day_of_year_token() {
+        assert_eq!(render_at("{DDD}", fixed_now()), "002");
+        let new_years_eve = Utc.with_ymd_and_hms(2026, 12, 31, 0, 0, 0).unwrap();
+        assert_eq!(render_at("{DDD}", new_years_eve), "365");
+    }
+
+    #[test]
+    fn test_iso_week_token() {
+        assert_eq!(render_at("{ww}", fixed_now()), "01");
+        // 2027-01-01 is a Friday and still belongs to ISO week 53 of 2026.
+        let iso_week_53 = Utc.with_ymd_and_hms(2027, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(render_at("{ww}", iso_week_53), "53");
+    }
+
+    #[test]
+    fn test_time_tokens() {
+        assert_eq!(render_at("{HH}", fixed_now()), "09");
+        assert_eq!(render_at("{mm}", fixed_now()), "05");
+        assert_eq!(render_at("{ss}", fixed_now()), "07");
+    }
+
+    #[test]
+    fn test_quarter_token() {
+        assert_eq!(render_at("{Q}", fixed_now()), "1");
+        let q3 = Utc.with_ymd_and_hms(2026, 9, 30, 0, 0, 0).unwrap();
+        assert_eq!(render_at("{Q}", q3), "3");
+        let q4 = Utc.with_ymd_and_hms(2026, 10, 1, 0, 0, 0).unwrap();
+        assert_eq!(render_at("{Q}", q4), "4");
+    }
+
+    #[test]
+    fn test_strftime_token() {
+        assert_eq!(render_at("{date:%Y%m%d}", fixed_now()), "20260102");
+        assert_eq!(render_at("{date:%G-W%V}", fixed_now()), "2026-W01");
+    }
+
+    #[test]
+    fn test_invalid_strftime_token() {
+        let result = NumberAssembler::new().assemble_number_at("TEST", &rule_with_format("{date:%Q}"), 1, fixed_now());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_invoice_and_shipping_formats() {
+        let invoice_rule = PrefixRule { seq_length: 5, ..rule_with_format("INV-{yyyy}{MM}{dd}-{SEQ:5}") };
+        let invoice = NumberAssembler::new()
+            .assemble_number_at("INV", &invoice_rule, 42, fixed_now())
+            .unwrap();
+        assert_eq!(invoice, "INV-20260102-00042");
+
+        assert_eq!(render_at("{yy}{ww}-{SEQ:4}", fixed_now()), "2601-0007");
+    }
+
+    #[test]
+    fn test_date_tokens_use_rule_time_zone() {
+        // 2025-12-31 17:30 UTC is already New Year's Day in Taipei (UTC+8).
+        let now = Utc.with_ymd_and_hms(2025, 12, 31, 17, 30, 0).unwrap();
+        let utc_rule = rule_with_format("{year}{MM}{dd}{HH}");
+        let taipei_rule = PrefixRule { timezone: Some("Asia/Taipei".to_string()), ..utc_rule.clone() };
+
+        let assembler = NumberAssembler::new();
+        assert_eq!(assembler.assemble_number_at("TEST", &utc_rule, 1, now).unwrap(), "2025123117");
+        assert_eq!(assembler.assemble_number_at("TEST", &taipei_rule, 1, now).unwrap(), "2026010101");
+    }
+
+    #[test]
+    fn test_unknown_time_zone_is_an_error() {
+        let rule = PrefixRule { timezone: Some("Nowhere/Land".to_string()), ..rule_with_format("{year}") };
+        assert!(NumberAssembler::new().assemble_number_at("TEST", &rule, 1, fixed_now()).is_err());
+    }
+
+    #[test]
+    fn test_validate_number_round_trip() {
+        let prefix_rule = PrefixRule { seq_length: 6, ..rule_with_format("{prefix}-{yy}{MM}-{SEQ:6}{CHECK:luhn}") };
+        let assembler = NumberAssembler::new();
+        let number = assembler.assemble_number("INV", &prefix_rule, 1234).unwrap();
+
+        assert!(assembler.validate_number("INV", &prefix_rule, &number).unwrap());
+        let mistyped = number.replace("001234", "001243");
+        assert!(!assembler.validate_number("INV", &prefix_rule, &mistyped).unwrap());
+    }
+
+    #[test]
+    fn test_validate_number_with_network_partition_suffix() {
+        let prefix_rule = PrefixRule { network_partition: true, ..rule_with_format("{prefix}-{SEQ:4}{CHECK:damm}") };
+        let assembler = NumberAssembler::new();
+        let number = assembler.assemble_number("NP", &prefix_rule, 7).unwrap();
+        assert!(number.ends_with("-NP"));
+        assert!(assembler.validate_number("NP", &prefix_rule, &number).unwrap());
+    }
+
+    #[test]
+    fn test_unknown_placeholder_is_rejected() {
+        let result = NumberAssembler::new().assemble_number_at("TEST", &rule_with_format("{unknown}-{SEQ:4}"), 1, fixed_now());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_scrambled_sequence_is_fixed_width_and_reversible() {
+        let prefix_rule = PrefixRule {
+            seq_length: 8,
+            scramble_key: Some("s3cret".to_string()),
+            ..rule_with_format("{prefix}-{SEQ:8}")
+        };
+        let assembler = NumberAssembler::new();
+        let first = assembler.assemble_number("ORD", &prefix_rule, 1).unwrap();
+        let second = assembler.assemble_number("ORD", &prefix_rule, 2).unwrap();
+
+        assert_eq!(first.len(), "ORD-".len() + 8);
+        assert_ne!(first, "ORD-00000001");
+        assert_ne!(first, second);
+        assert_eq!(assembler.reveal_sequence("ORD", &prefix_rule, &second, Some("s3cret")), Ok(2));
+        assert_ne!(assembler.reveal_sequence("ORD", &prefix_rule, &second, Some("guess")), Ok(2));
+    }
+
+    #[test]
+    fn test_scrambled_sequence_outside_width_is_an_error() {
+        let prefix_rule = PrefixRule { scramble_key: Some("s3cret".to_string()), ..rule_with_format("{prefix}-{SEQ:4}") };
+        assert!(NumberAssembler::new().assemble_number("ORD", &prefix_rule, 10_000).is_err());
+    }
+
+    #[test]
+    fn test_reveal_plain_sequence() {
+        let prefix_rule = PrefixRule { network_partition: true, ..rule_with_format("{prefix}-{yy}-{SEQ36:5}{CHECK:luhn}") };
+        let assembler = NumberAssembler::new();
+        let number = assembler.assemble_number("CPN", &prefix_rule, 123_456).unwrap();
+        assert_eq!(assembler.reveal_sequence("CPN", &prefix_rule, &number, None), Ok(123_456));
+        assert!(assembler.reveal_sequence("CPN", &prefix_rule, "CPN-xx", None).is_err());
+    }
+
+    #[test]
+    fn test_current_sequence() {
+        let prefix_rule = PrefixRule { scramble_key: Some("s3cret".to_string()), ..rule_with_format("{prefix}-{yyyy}-{SEQ:6}") };
+        let assembler = NumberAssembler::new();
+        let variables = HashMap::new();
+        let number = assembler.assemble_number_with("INV", &prefix_rule, 42, &variables, fixed_now()).unwrap();
+        assert_eq!(assembler.current_sequence("INV", &prefix_rule, &number, &variables, fixed_now()), Ok(42));
+
+        // The same sequence a year later belongs to next year's number.
+        let next_year = fixed_now() + chrono::Duration::days(366);
+        assert!(assembler.current_sequence("INV", &prefix_rule, &number, &variables, next_year).is_err());
+    }
+
+    #[test]
+    fn test_template_variables() {
+        let prefix_rule = PrefixRule {
+            variables: vec![
+                TemplateVariable::new("region").allowing(["TW", "JP"]),
+                TemplateVariable::new("dept").matching("[A-Z]{2}"),
+            ],
+            ..rule_with_format("{region}-{dept}-{SEQ:5}")
+        };
+        let variables = [("region", "TW"), ("dept", "HR")]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let assembler = NumberAssembler::new();
+        let number = assembler.assemble_number_with("ORD", &prefix_rule, 42, &variables, fixed_now()).unwrap();
+        assert_eq!(number, "TW-HR-00042");
+        assert!(assembler.validate_number("ORD", &prefix_rule, &number).unwrap());
+        assert!(assembler.assemble_number_at("ORD", &prefix_rule, 42, fixed_now()).is_err());
+    }
 }
@@ -0,0 +1,616 @@
+//! Two-phase issuing: reserve, then confirm or void.
+//!
+//! Some numbers (invoices, tax receipts) have to be accounted for even when the
+//! transaction they were drawn for is aborted. A reserved number is recorded in the
+//! ledger with a deadline; the caller then confirms it, making it final, or voids it.
+//! A reservation that is neither confirmed nor voided by its deadline expires. Voided and
+//! expired numbers stay in the ledger, so every number ever issued can be reported on.
+//!
+//! A voided or expired number of a recycling rule can be released for reissue, which
+//! marks it `recycled`: it is no longer a hole in the series, and cannot be released a
+//! second time. Reserving the reissued number starts a new entry.
+//!
+//! Expiry is applied lazily: whenever a reservation is settled or the ledger is reported
+//! on, overdue reservations are moved to `expired` first, stamped with their deadline.
+//! Reports cover a period of settling: a number belongs to the period it was voided or
+//! expired in.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use redis::{AsyncCommands, Client, RedisError};
+use serde::{Deserialize, Serialize};
+use sled::{Db, Tree};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Mutex;
+use thiserror::Error;
+
+/// How long a reservation stays open when the rule does not say otherwise.
+pub const DEFAULT_RESERVATION_TTL_SECS: u64 = 15 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NumberStatus {
+    Reserved,
+    Confirmed,
+    Voided,
+    Expired,
+    /// Voided or expired, then released to be issued again.
+    Recycled,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NumberEntry {
+    pub number: String,
+    pub sequence: u64,
+    pub status: NumberStatus,
+    pub reserved_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    /// When the entry was confirmed, voided or expired.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub settled_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Error)]
+pub enum LedgerError {
+    #[error("Redis error: {0}")]
+    RedisError(#[from] RedisError),
+    #[error("Sled error: {0}")]
+    SledError(#[from] sled::Error),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+    #[error("Number {0} was never reserved")]
+    NotFound(String),
+    #[error("Number {number} is already {status:?}")]
+    AlreadySettled { number: String, status: NumberStatus },
+    #[error("Number {number} is {status:?}, not voided or expired")]
+    NotVoid { number: String, status: NumberStatus },
+}
+
+impl NumberEntry {
+    pub fn reserve(number: String, sequence: u64, now: DateTime<Utc>, ttl_secs: u64) -> Self {
+        NumberEntry {
+            number,
+            sequence,
+            status: NumberStatus::Reserved,
+            reserved_at: now,
+            expires_at: now + chrono::Duration::seconds(ttl_secs.min(i64::MAX as u64) as i64),
+            settled_at: None,
+        }
+    }
+
+    /// Moves an overdue reservation to `expired`.
+    pub fn expire_if_due(&mut self, now: DateTime<Utc>) {
+        if self.status == NumberStatus::Reserved && now >= self.expires_at {
+            self.status = NumberStatus::Expired;
+            self.settled_at = Some(self.expires_at);
+        }
+    }
+
+    /// Confirms or voids a reservation. Repeating the transition that already happened is
+    /// accepted, so a retried request is harmless; any other settled entry is final.
+    pub fn settle(&mut self, status: NumberStatus, now: DateTime<Utc>) -> Result<(), LedgerError> {
+        self.expire_if_due(now);
+        match self.status {
+            current if current == status => Ok(()),
+            NumberStatus::Reserved => {
+                self.status = status;
+                self.settled_at = Some(now);
+                Ok(())
+            }
+            current => Err(LedgerError::AlreadySettled { number: self.number.clone(), status: current }),
+        }
+    }
+
+    /// Marks a voided or expired number as released for reissue. Anything else, including
+    /// a number already recycled, is refused.
+    pub fn recycle(&mut self, now: DateTime<Utc>) -> Result<(), LedgerError> {
+        self.expire_if_due(now);
+        if !self.is_void() {
+            return Err(LedgerError::NotVoid { number: self.number.clone(), status: self.status });
+        }
+        self.status = NumberStatus::Recycled;
+        Ok(())
+    }
+
+    /// Undoes `recycle` for a number that could not be released after all. A number can
+    /// only be voided before its deadline, so one settled at the deadline had expired.
+    pub fn unrecycle(&mut self) {
+        if self.status == NumberStatus::Recycled {
+            self.status = if self.settled_at >= Some(self.expires_at) {
+                NumberStatus::Expired
+            } else {
+                NumberStatus::Voided
+            };
+        }
+    }
+
+    /// Whether the entry belongs in the voided report.
+    pub fn is_void(&self) -> bool {
+        matches!(self.status, NumberStatus::Voided | NumberStatus::Expired)
+    }
+
+    /// Whether the entry belongs in the voided report for `settled`, or the whole report
+    /// without one.
+    pub fn is_void_within(&self, settled: Option<&Range<DateTime<Utc>>>) -> bool {
+        self.is_void() && settled.is_none_or(|settled| self.settled_at.is_some_and(|at| settled.contains(&at)))
+    }
+}
+
+#[async_trait]
+pub trait NumberLedger {
+    /// Records a new entry, normally a reservation; an entry that is already settled is
+    /// stored as it is.
+    async fn reserve(&self, prefix_key: &str, entry: &NumberEntry) -> Result<(), LedgerError>;
+
+    /// Applies `status` (`Confirmed` or `Voided`) to a reservation; see `NumberEntry::settle`.
+    async fn settle(
+        &self,
+        prefix_key: &str,
+        number: &str,
+        status: NumberStatus,
+        now: DateTime<Utc>,
+    ) -> Result<NumberEntry, LedgerError>;
+
+    /// Marks a voided or expired number as recycled and returns the updated entry; see
+    /// `NumberEntry::recycle`. The mark is undone by storing the entry back with `reserve`
+    /// after `NumberEntry::unrecycle`.
+    async fn recycle(&self, prefix_key: &str, number: &str, now: DateTime<Utc>) -> Result<NumberEntry, LedgerError>;
+
+    /// Voided and expired numbers of `prefix_key`, in issuing order; with `settled`, only
+    /// those voided or expired within it.
+    async fn voided(
+        &self,
+        prefix_key: &str,
+        now: DateTime<Utc>,
+        settled: Option<Range<DateTime<Utc>>>,
+    ) -> Result<Vec<NumberEntry>, LedgerError>;
+}
+
+/// Keeps each prefix's entries in a Redis hash keyed by number, with open reservations
+/// indexed by deadline and voided or expired numbers by settling time, each in a sorted
+/// set. Entries are updated under `WATCH`, so a confirmation racing a void or an expiry
+/// sweep is applied at most once.
+pub struct RedisNumberLedger {
+    redis_client: Client,
+}
+
+impl RedisNumberLedger {
+    pub fn new(redis_url: String) -> Result<Self, RedisError> {
+        let redis_client = Client::open(redis_url)?;
+        Ok(RedisNumberLedger { redis_client })
+    }
+
+    fn entries_key(prefix_key: &str) -> String {
+        format!("number_ledger:{}", prefix_key)
+    }
+
+    fn pending_key(prefix_key: &str) -> String {
+        format!("number_ledger:{}:pending", prefix_key)
+    }
+
+    fn voided_key(prefix_key: &str) -> String {
+        format!("number_ledger:{}:voided", prefix_key)
+    }
+
+    /// Adds the commands that keep the voided index in step with `entry` to `pipe`.
+    fn index_voided(pipe: &mut redis::Pipeline, prefix_key: &str, entry: &NumberEntry) {
+        match entry.settled_at.filter(|_| entry.is_void()) {
+            Some(settled_at) => pipe.zadd(Self::voided_key(prefix_key), &entry.number, settled_at.timestamp_millis()),
+            None => pipe.zrem(Self::voided_key(prefix_key), &entry.number),
+        }
+        .ignore();
+    }
+
+    /// Reads an entry, lets `update` change it and writes it back unless another client
+    /// changed it in between, in which case the whole step is retried.
+    async fn update<F>(&self, prefix_key: &str, number: &str, update: F) -> Result<NumberEntry, LedgerError>
+    where
+        F: Fn(&mut NumberEntry) -> Result<(), LedgerError> + Send,
+    {
+        let mut conn = self.redis_client.get_async_connection().await?;
+        let entries_key = Self::entries_key(prefix_key);
+        loop {
+            redis::cmd("WATCH").arg(&entries_key).query_async::<_, ()>(&mut conn).await?;
+            let json: Option<String> = conn.hget(&entries_key, number).await?;
+            let Some(json) = json else {
+                redis::cmd("UNWATCH").query_async::<_, ()>(&mut conn).await?;
+                return Err(LedgerError::NotFound(number.to_string()));
+            };
+            let original: NumberEntry = serde_json::from_str(&json)?;
+            let mut entry = original.clone();
+            let result = update(&mut entry);
+            if entry == original {
+                redis::cmd("UNWATCH").query_async::<_, ()>(&mut conn).await?;
+                return result.map(|_| entry);
+            }
+            let mut pipe = redis::pipe();
+            pipe.atomic()
+                .hset(&entries_key, number, serde_json::to_string(&entry)?)
+                .ignore()
+                .zrem(Self::pending_key(prefix_key), number)
+                .ignore();
+            Self::index_voided(&mut pipe, prefix_key, &entry);
+            let committed: Option<()> = pipe.query_async(&mut conn).await?;
+            if committed.is_some() {
+                return result.map(|_| entry);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl NumberLedger for RedisNumberLedger {
+    async fn reserve(&self, prefix_key: &str, entry: &NumberEntry) -> Result<(), LedgerError> {
+        let mut conn = self.redis_client.get_async_connection().await?;
+        let mut pipe = redis::pipe();
+        pipe.atomic().hset(Self::entries_key(prefix_key), &entry.number, serde_json::to_string(entry)?).ignore();
+        // Only open reservations wait for their deadline.
+        if entry.status == NumberStatus::Reserved {
+            pipe.zadd(Self::pending_key(prefix_key), &entry.number, entry.expires_at.timestamp_millis()).ignore();
+        }
+        Self::index_voided(&mut pipe, prefix_key, entry);
+        pipe.query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+
+    async fn settle(
+        &self,
+        prefix_key: &str,
+        number: &str,
+        status: NumberStatus,
+        now: DateTime<Utc>,
+    ) -> Result<NumberEntry, LedgerError> {
+        self.update(prefix_key, number, |entry| entry.settle(status, now)).await
+    }
+
+    async fn recycle(&self, prefix_key: &str, number: &str, now: DateTime<Utc>) -> Result<NumberEntry, LedgerError> {
+        self.update(prefix_key, number, |entry| entry.recycle(now)).await
+    }
+
+    async fn voided(
+        &self,
+        prefix_key: &str,
+        now: DateTime<Utc>,
+        settled: Option<Range<DateTime<Utc>>>,
+    ) -> Result<Vec<NumberEntry>, LedgerError> {
+        let mut conn = self.redis_client.get_async_connection().await?;
+        let overdue: Vec<String> = conn
+            .zrangebyscore(Self::pending_key(prefix_key), "-inf", now.timestamp_millis())
+            .await?;
+        for number in overdue {
+            self.update(prefix_key, &number, |entry| {
+                entry.expire_if_due(now);
+                Ok(())
+            })
+            .await?;
+        }
+
+        let (min, max) = match &settled {
+            Some(settled) => (settled.start.timestamp_millis().to_string(), format!("({}", settled.end.timestamp_millis())),
+            None => ("-inf".to_string(), "+inf".to_string()),
+        };
+        let numbers: Vec<String> = conn.zrangebyscore(Self::voided_key(prefix_key), min, max).await?;
+        if numbers.is_empty() {
+            return Ok(Vec::new());
+        }
+        let entries: Vec<Option<String>> = redis::cmd("HMGET")
+            .arg(Self::entries_key(prefix_key))
+            .arg(&numbers)
+            .query_async(&mut conn)
+            .await?;
+        let mut voided = Vec::new();
+        for json in entries.into_iter().flatten() {
+            let entry: NumberEntry = serde_json::from_str(&json)?;
+            if entry.is_void_within(settled.as_ref()) {
+                voided.push(entry);
+            }
+        }
+        voided.sort_by_key(|entry| (entry.reserved_at, entry.sequence));
+        Ok(voided)
+    }
+}
+
+/// Keeps entries in a tree of the embedded database `SledStorage` uses, keyed by prefix, a
+/// zero byte and the number. Entries are swapped in with compare-and-swap, so settling is
+/// as race-free as in Redis.
+pub struct SledNumberLedger {
+    entries: Tree,
+}
+
+impl SledNumberLedger {
+    pub fn open(db: &Db) -> Result<Self, sled::Error> {
+        Ok(SledNumberLedger { entries: db.open_tree("number_ledger")? })
+    }
+
+    fn prefix(prefix_key: &str) -> Vec<u8> {
+        let mut prefix = prefix_key.as_bytes().to_vec();
+        prefix.push(0);
+        prefix
+    }
+
+    fn entry_key(prefix_key: &str, number: &str) -> Vec<u8> {
+        let mut key = Self::prefix(prefix_key);
+        key.extend_from_slice(number.as_bytes());
+        key
+    }
+
+    /// Reads an entry, lets `update` change it and swaps it in unless another caller
+    /// changed it in between, in which case the whole step is retried.
+    async fn update<F>(&self, key: &[u8], number: &str, update: F) -> Result<NumberEntry, LedgerError>
+    where
+        F: Fn(&mut NumberEntry) -> Result<(), LedgerError> + Send,
+    {
+        loop {
+            let Some(json) = self.entries.get(key)? else {
+                return Err(LedgerError::NotFound(number.to_string()));
+            };
+            let original: NumberEntry = serde_json::from_slice(&json)?;
+            let mut entry = original.clone();
+            let result = update(&mut entry);
+            if entry == original {
+                return result.map(|_| entry);
+            }
+            let swapped = self.entries.compare_and_swap(key, Some(json), Some(serde_json::to_vec(&entry)?))?;
+            if swapped.is_ok() {
+                self.entries.flush_async().await?;
+                return result.map(|_| entry);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl NumberLedger for SledNumberLedger {
+    async fn reserve(&self, prefix_key: &str, entry: &NumberEntry) -> Result<(), LedgerError> {
+        self.entries.insert(Self::entry_key(prefix_key, &entry.number), serde_json::to_vec(entry)?)?;
+        self.entries.flush_async().await?;
+        Ok(())
+    }
+
+    async fn settle(
+        &self,
+        prefix_key: &str,
+        number: &str,
+        status: NumberStatus,
+        now: DateTime<Utc>,
+    ) -> Result<NumberEntry, LedgerError> {
+        self.update(&Self::entry_key(prefix_key, number), number, |entry| entry.settle(status, now)).await
+    }
+
+    async fn recycle(&self, prefix_key: &str, number: &str, now: DateTime<Utc>) -> Result<NumberEntry, LedgerError> {
+        self.update(&Self::entry_key(prefix_key, number), number, |entry| entry.recycle(now)).await
+    }
+
+    async fn voided(
+        &self,
+        prefix_key: &str,
+        now: DateTime<Utc>,
+        settled: Option<Range<DateTime<Utc>>>,
+    ) -> Result<Vec<NumberEntry>, LedgerError> {
+        let entries = self
+            .entries
+            .scan_prefix(Self::prefix(prefix_key))
+            .map(|item| {
+                let (key, json) = item?;
+                Ok((key, serde_json::from_slice::<NumberEntry>(&json)?))
+            })
+            .collect::<Result<Vec<_>, LedgerError>>()?;
+
+        let mut voided = Vec::new();
+        for (key, mut entry) in entries {
+            if entry.status == NumberStatus::Reserved && entry.expires_at <= now {
+                entry = self
+                    .update(&key, &entry.number.clone(), |entry| {
+                        entry.expire_if_due(now);
+                        Ok(())
+                    })
+                    .await?;
+            }
+            if entry.is_void_within(settled.as_ref()) {
+                voided.push(entry);
+            }
+        }
+        voided.sort_by_key(|entry| (entry.reserved_at, entry.sequence));
+        Ok(voided)
+    }
+}
+
+/// Keeps each prefix's entries in the process, for tests and deployments that run without
+/// Redis. Entries are updated under one lock, so settling is as race-free as in Redis.
+#[derive(Debug, Default)]
+pub struct MemoryNumberLedger {
+    entries: Mutex<HashMap<String, HashMap<String, NumberEntry>>>,
+}
+
+impl MemoryNumberLedger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, HashMap<String, HashMap<String, NumberEntry>>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[async_trait]
+impl NumberLedger for MemoryNumberLedger {
+    async fn reserve(&self, prefix_key: &str, entry: &NumberEntry) -> Result<(), LedgerError> {
+        self.entries().entry(prefix_key.to_string()).or_default().insert(entry.number.clone(), entry.clone());
+        Ok(())
+    }
+
+    async fn settle(
+        &self,
+        prefix_key: &str,
+        number: &str,
+        status: NumberStatus,
+        now: DateTime<Utc>,
+    ) -> Result<NumberEntry, LedgerError> {
+        let mut entries = self.entries();
+        let entry = entries
+            .get_mut(prefix_key)
+            .and_then(|entries| entries.get_mut(number))
+            .ok_or_else(|| LedgerError::NotFound(number.to_string()))?;
+        entry.settle(status, now)?;
+        Ok(entry.clone())
+    }
+
+    async fn recycle(&self, prefix_key: &str, number: &str, now: DateTime<Utc>) -> Result<NumberEntry, LedgerError> {
+        let mut entries = self.entries();
+        let entry = entries
+            .get_mut(prefix_key)
+            .and_then(|entries| entries.get_mut(number))
+            .ok_or_else(|| LedgerError::NotFound(number.to_string()))?;
+        entry.recycle(now)?;
+        Ok(entry.clone())
+    }
+
+    async fn voided(
+        &self,
+        prefix_key: &str,
+        now: DateTime<Utc>,
+        settled: Option<Range<DateTime<Utc>>>,
+    ) -> Result<Vec<NumberEntry>, LedgerError> {
+        let mut entries = self.entries();
+        let mut voided = Vec::new();
+        for entry in entries.get_mut(prefix_key).into_iter().flat_map(|entries| entries.values_mut()) {
+            entry.expire_if_due(now);
+            if entry.is_void_within(settled.as_ref()) {
+                voided.push(entry.clone());
+            }
+        }
+        voided.sort_by_key(|entry| (entry.reserved_at, entry.sequence));
+        Ok(voided)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn reserved_at_noon() -> NumberEntry {
+        let noon = Utc.with_ymd_and_hms(2026, 10, 16, 12, 0, 0).unwrap();
+        NumberEntry::reserve("INV-0001".to_string(), 1, noon, 60)
+    }
+
+    #[test]
+    fn test_confirm_and_void() {
+        let mut entry = reserved_at_noon();
+        let now = entry.reserved_at + chrono::Duration::seconds(10);
+        entry.settle(NumberStatus::Confirmed, now).unwrap();
+        assert_eq!(entry.status, NumberStatus::Confirmed);
+        assert_eq!(entry.settled_at, Some(now));
+
+        // Retrying is harmless, changing the outcome is not.
+        assert!(entry.settle(NumberStatus::Confirmed, now).is_ok());
+        assert!(matches!(
+            entry.settle(NumberStatus::Voided, now),
+            Err(LedgerError::AlreadySettled { status: NumberStatus::Confirmed, .. })
+        ));
+
+        let mut entry = reserved_at_noon();
+        entry.settle(NumberStatus::Voided, now).unwrap();
+        assert!(entry.is_void());
+    }
+
+    #[test]
+    fn test_overdue_reservation_expires() {
+        let mut entry = reserved_at_noon();
+        let late = entry.expires_at + chrono::Duration::seconds(1);
+        assert!(matches!(
+            entry.settle(NumberStatus::Confirmed, late),
+            Err(LedgerError::AlreadySettled { status: NumberStatus::Expired, .. })
+        ));
+        assert_eq!(entry.settled_at, Some(entry.expires_at));
+        assert!(entry.is_void());
+    }
+
+    #[test]
+    fn test_only_void_numbers_are_recycled() {
+        let mut entry = reserved_at_noon();
+        let now = entry.reserved_at + chrono::Duration::seconds(10);
+        assert!(matches!(entry.recycle(now), Err(LedgerError::NotVoid { status: NumberStatus::Reserved, .. })));
+        entry.settle(NumberStatus::Confirmed, now).unwrap();
+        assert!(matches!(entry.recycle(now), Err(LedgerError::NotVoid { status: NumberStatus::Confirmed, .. })));
+
+        let mut entry = reserved_at_noon();
+        entry.settle(NumberStatus::Voided, now).unwrap();
+        entry.recycle(now).unwrap();
+        assert!(!entry.is_void());
+        // A number goes back to the free list once.
+        assert!(matches!(entry.recycle(now), Err(LedgerError::NotVoid { status: NumberStatus::Recycled, .. })));
+
+        let mut entry = reserved_at_noon();
+        entry.recycle(entry.expires_at).unwrap();
+        assert_eq!(entry.status, NumberStatus::Recycled);
+    }
+
+    // The same checks run on the sled ledger and, with `cargo test -- --ignored`, on a
+    // Redis server at localhost:6379.
+
+    async fn check_settles_and_reports(ledger: &(impl NumberLedger + Sync)) {
+        let first = reserved_at_noon();
+        let second = NumberEntry::reserve("INV-0002".to_string(), 2, first.reserved_at, 60);
+        ledger.reserve("INV", &first).await.unwrap();
+        ledger.reserve("INV", &second).await.unwrap();
+        ledger.reserve("INV2", &second).await.unwrap();
+
+        let now = first.reserved_at + chrono::Duration::seconds(10);
+        ledger.settle("INV", "INV-0001", NumberStatus::Voided, now).await.unwrap();
+        assert!(matches!(
+            ledger.settle("INV", "INV-0001", NumberStatus::Confirmed, now).await,
+            Err(LedgerError::AlreadySettled { status: NumberStatus::Voided, .. })
+        ));
+        assert!(matches!(ledger.settle("INV", "INV-0009", NumberStatus::Confirmed, now).await, Err(LedgerError::NotFound(_))));
+
+        // The second reservation is left to expire.
+        let late = second.expires_at + chrono::Duration::seconds(1);
+        let voided: Vec<String> = ledger.voided("INV", late, None).await.unwrap().into_iter().map(|e| e.number).collect();
+        assert_eq!(voided, vec!["INV-0001", "INV-0002"]);
+
+        // A period holds what was voided or expired within it.
+        let voided_in = |settled: Range<DateTime<Utc>>| async move {
+            ledger.voided("INV", late, Some(settled)).await.unwrap().into_iter().map(|e| e.number).collect::<Vec<_>>()
+        };
+        assert_eq!(voided_in(first.reserved_at..second.expires_at).await, vec!["INV-0001"]);
+        assert_eq!(voided_in(second.expires_at..late).await, vec!["INV-0002"]);
+        assert!(voided_in(late..late + chrono::Duration::days(1)).await.is_empty());
+
+        let mut recycled = ledger.recycle("INV", "INV-0002", late).await.unwrap();
+        recycled.unrecycle();
+        assert_eq!(recycled.status, NumberStatus::Expired);
+        ledger.reserve("INV", &recycled).await.unwrap();
+
+        let mut recycled = ledger.recycle("INV", "INV-0001", late).await.unwrap();
+        let voided: Vec<String> = ledger.voided("INV", late, None).await.unwrap().into_iter().map(|e| e.number).collect();
+        assert_eq!(voided, vec!["INV-0002"]);
+        recycled.unrecycle();
+        assert_eq!(recycled.status, NumberStatus::Voided);
+        assert!(ledger.voided("INV2", now, None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sled_ledger_settles_and_reports() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        check_settles_and_reports(&SledNumberLedger::open(&db).unwrap()).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at localhost:6379"]
+    async fn test_redis_ledger_settles_and_reports() {
+        let keys = ["INV", "INV2"].iter().flat_map(|prefix_key| {
+            [RedisNumberLedger::entries_key(prefix_key), RedisNumberLedger::pending_key(prefix_key), RedisNumberLedger::voided_key(prefix_key)]
+        });
+        let keys: Vec<String> = keys.collect();
+        let delete_keys = || {
+            let mut conn = Client::open("redis://localhost:6379/").unwrap().get_connection().unwrap();
+            let _: () = redis::cmd("DEL").arg(&keys).query(&mut conn).unwrap();
+        };
+        delete_keys();
+        check_settles_and_reports(&RedisNumberLedger::new("redis://localhost:6379/".to_string()).unwrap()).await;
+        delete_keys();
+    }
+}
@@ -1,10 +1,991 @@
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use thiserror::Error;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+use crate::format_template::{
+    CalendarField, FormatPolicy, FormatTemplate, Placeholder, RenderContext, TemplateError, TemplateErrorReason,
+    Verifier,
+};
+use crate::sequence_generator::{SequenceGeneratorError, SequenceRange};
+use crate::snowflake_sequence_generator::{COUNTER_CAPACITY, SNOWFLAKE_MAX};
+use crate::template_variable::{TemplateVariable, VariableError};
+
+/// What happens once the counter no longer fits the sequence placeholder's width.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OverflowPolicy {
+    /// Refuse to issue the number, keeping every number fixed-width.
+    #[default]
+    Error,
+    /// Start over from zero, reusing the width's values (modulo its capacity).
+    Wrap,
+    /// Render the longer value, as numbers were rendered before policies existed.
+    Widen,
+}
+
+impl OverflowPolicy {
+    /// The policy of a stored rule that has none.
+    fn stored_default() -> Self {
+        OverflowPolicy::Widen
+    }
+}
+
+/// A rule setting that does not fit the rest of the rule, although its format is fine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleError {
+    #[error("sequence offset must be smaller than its step")]
+    OffsetOutOfStep,
+    #[error("wrapping sequence width must hold a multiple of the step")]
+    WrapOutOfStep,
+    #[error("step and offset only apply to the counter generator")]
+    StepNotSupported,
+    #[error("sequence width cannot hold every value of the generator")]
+    SequenceTooNarrow,
+    #[error("only the counter generator can recycle numbers")]
+    RecycleNotSupported,
+    #[error("a scrambled sequence cannot widen; use the error or wrap overflow policy")]
+    ScrambleWidens,
+    #[error("prefix key must not contain `:`")]
+    InvalidPrefixKey,
+}
+
+/// Why a rule was rejected at registration.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum PrefixRuleError {
+    #[error("Invalid prefix format: {0}")]
+    Format(#[from] TemplateError),
+    #[error("Invalid prefix rule: {0}")]
+    Rule(#[from] RuleError),
+}
+
+/// Algorithm a rule's sequence values come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GeneratorKind {
+    /// A shared counter per sequence key (Redis), honouring `initial_seq`, step and offset.
+    #[default]
+    Counter,
+    /// Time-based Snowflake values (see `snowflake_sequence_generator`); no round trip,
+    /// but values are 63 bits wide and ignore `initial_seq`, step and offset.
+    Snowflake,
+    /// A counter row in a SQL database (see `sql_sequence_generator`), with the same
+    /// semantics as `Counter` but no value ever skipped.
+    GapFree,
+}
+
+impl GeneratorKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GeneratorKind::Counter => "counter",
+            GeneratorKind::Snowflake => "snowflake",
+            GeneratorKind::GapFree => "gap_free",
+        }
+    }
+
+    /// Largest value the generator can issue, when it is not up to the counter.
+    pub fn max_value(self) -> Option<u64> {
+        match self {
+            GeneratorKind::Counter | GeneratorKind::GapFree => None,
+            GeneratorKind::Snowflake => Some(SNOWFLAKE_MAX),
+        }
+    }
+
+    /// Most values one batch or range can take from the generator, when it is limited.
+    fn max_count(self) -> Option<u64> {
+        match self {
+            GeneratorKind::Counter | GeneratorKind::GapFree => None,
+            // A range comes from a single millisecond's counter.
+            GeneratorKind::Snowflake => Some(COUNTER_CAPACITY),
+        }
+    }
+
+    fn honours_step(self) -> bool {
+        self != GeneratorKind::Snowflake
+    }
+}
+
+/// Largest batch a rule without its own `max_batch` may request at once.
+pub const DEFAULT_MAX_BATCH: u64 = 1000;
+
+/// Largest streamed range a rule without its own `max_range` may reserve at once.
+pub const DEFAULT_MAX_RANGE: u64 = 10_000_000;
+
+/// How long a period's counter outlives the longest the period can last, so a node whose
+/// clock lags behind still finds it.
+pub const COUNTER_GRACE_SECS: u64 = 24 * 3600;
+
+/// When the counter starts over at `initial_seq`. Periods follow the rule's time zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResetPolicy {
+    #[default]
+    Never,
+    Daily,
+    Monthly,
+    Yearly,
+}
+
+impl ResetPolicy {
+    /// strftime pattern naming the period `now` falls in; each period counts in its
+    /// own counter.
+    fn period_pattern(self) -> Option<&'static str> {
+        match self {
+            ResetPolicy::Never => None,
+            ResetPolicy::Daily => Some("%Y%m%d"),
+            ResetPolicy::Monthly => Some("%Y%m"),
+            ResetPolicy::Yearly => Some("%Y"),
+        }
+    }
+
+    /// How long a period's counter is kept after its first move: the longest the period
+    /// can last (DST days run 25 hours) plus `COUNTER_GRACE_SECS`. It is therefore only
+    /// dropped once its period is over, and the store does not fill up with counters of
+    /// past periods. `None` for rules that never reset.
+    pub fn counter_ttl_secs(self) -> Option<u64> {
+        const HOUR: u64 = 3600;
+        const DAY: u64 = 24 * HOUR;
+        let longest = match self {
+            ResetPolicy::Never => return None,
+            ResetPolicy::Daily => DAY + HOUR,
+            ResetPolicy::Monthly => 31 * DAY + HOUR,
+            ResetPolicy::Yearly => 366 * DAY + HOUR,
+        };
+        Some(longest + COUNTER_GRACE_SECS)
+    }
+
+    /// Calendar fields a format has to print so numbers from different periods differ.
+    fn required_fields(self) -> &'static [(CalendarField, &'static str)] {
+        const YEAR: (CalendarField, &str) = (CalendarField::Year, "{year}");
+        const MONTH: (CalendarField, &str) = (CalendarField::Month, "{MM}");
+        const DAY: (CalendarField, &str) = (CalendarField::Day, "{dd}");
+        match self {
+            ResetPolicy::Never => &[],
+            ResetPolicy::Daily => &[YEAR, MONTH, DAY],
+            ResetPolicy::Monthly => &[YEAR, MONTH],
+            ResetPolicy::Yearly => &[YEAR],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct PrefixRule {
     pub prefix_key: String,
     pub format: String,
     pub seq_length: u32,
     pub initial_seq: u64,
     pub network_partition: bool,
+    /// IANA time zone name (e.g. `Asia/Taipei`) the date placeholders are rendered in.
+    /// Rules stored before this field existed have none and keep rendering in UTC.
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// Secret for scrambling the rendered sequence (see `sequence_permutation`). Rules
+    /// without one render the counter as-is.
+    #[serde(default)]
+    pub scramble_key: Option<String>,
+    /// Newly registered rules default to `Error`; rules stored before policies existed
+    /// have none and keep widening, as they always did.
+    #[serde(default = "OverflowPolicy::stored_default")]
+    pub overflow_policy: OverflowPolicy,
+    /// Variables the caller supplies on each generate request, referenced as `{name}`.
+    #[serde(default)]
+    pub variables: Vec<TemplateVariable>,
+    /// Placeholder names (template variables, date tokens or `date:<strftime>`) whose
+    /// rendered values partition the counter, e.g. `["region", "date:%Y%m%d"]` counts
+    /// in `seq:ORDER:TW:20261016`. Empty keeps a single counter per prefix.
+    #[serde(default)]
+    pub sequence_scope: Vec<String>,
+    #[serde(default)]
+    pub reset_policy: ResetPolicy,
+    /// Distance between consecutive numbers; 0 (rules stored before steps existed) means 1.
+    #[serde(default)]
+    pub step: u64,
+    /// Residue every issued value has modulo `step`, e.g. odd numbers in one data center
+    /// (`step` 2, `offset` 1) and even ones in the other (`offset` 0). Below `step`.
+    #[serde(default)]
+    pub offset: u64,
+    #[serde(default)]
+    pub generator: GeneratorKind,
+    /// Reissue released numbers (smallest first) before counting on, so a series has no
+    /// holes where numbers went unused. Counter generator only.
+    #[serde(default)]
+    pub recycle: bool,
+    /// Most numbers one batch request may reserve; `DEFAULT_MAX_BATCH` when unset.
+    #[serde(default)]
+    pub max_batch: Option<u64>,
+    /// Most numbers one streamed range may reserve; `DEFAULT_MAX_RANGE` when unset.
+    #[serde(default)]
+    pub max_range: Option<u64>,
+    /// Seconds a reserved number may stay unconfirmed before it expires;
+    /// `DEFAULT_RESERVATION_TTL_SECS` when unset.
+    #[serde(default)]
+    pub reservation_ttl: Option<u64>,
+    /// `format` parsed once by `compile`, so assembling a number does not re-parse it.
+    #[serde(skip)]
+    pub compiled_format: Option<Arc<FormatTemplate>>,
+    /// `sequence_scope` compiled alongside `format`.
+    #[serde(skip)]
+    pub compiled_scope: Option<Arc<FormatTemplate>>,
+    /// The format's verification regex for `prefix_key` and `seq_length`, built alongside it.
+    #[serde(skip)]
+    pub compiled_verifier: Option<Arc<Verifier>>,
+}
+
+impl PrefixRule {
+    /// Parses `format` and caches the result alongside the rule. Called whenever a rule
+    /// is registered.
+    pub fn compile(&mut self) -> Result<(), TemplateError> {
+        self.compile_variables()?;
+        let template = self.parse_format()?;
+        self.cache_template(template)
+    }
+
+    /// Like `compile`, for a rule loaded from storage. Its format was accepted when it was
+    /// registered, possibly by the older, tolerant parser, so it is parsed leniently: a
+    /// stored `{yaer}` keeps rendering as literal text instead of failing every request.
+    pub fn compile_stored(&mut self) -> Result<(), TemplateError> {
+        self.compile_variables()?;
+        let template = FormatTemplate::parse_lenient(&self.format, &self.variable_names());
+        self.cache_template(template)
+    }
+
+    fn compile_variables(&mut self) -> Result<(), TemplateError> {
+        for variable in &mut self.variables {
+            // Declarations are not part of the format, so they are reported at its end.
+            variable.compile().map_err(|_| {
+                TemplateError::new(format!("{{{}}}", variable.name), self.format.len(), TemplateErrorReason::InvalidVariable)
+            })?;
+        }
+        Ok(())
+    }
+
+    fn cache_template(&mut self, template: FormatTemplate) -> Result<(), TemplateError> {
+        self.compiled_scope = Some(Arc::new(template.scope_template(&self.sequence_scope, &self.variable_names())?));
+        self.compiled_verifier = template.verifier(&self.prefix_key, self.seq_length).map(Arc::new);
+        self.compiled_format = Some(Arc::new(template));
+        Ok(())
+    }
+
+    /// Validates a rule about to be registered: compiles `format` with the strict parser,
+    /// settles `seq_length` against the `{SEQ:N}` width (the template wins; an explicit
+    /// `requested_seq_length` must agree with it or stand in for a bare `{SEQ}`), and
+    /// checks the deployment's `policy`. The sequence placeholder is the only placeholder
+    /// every rule must have, unless a reset policy needs the period printed as well.
+    /// Counter keys join the prefix key and scope with `:`, so the prefix key may not
+    /// contain one, and scoped variables have to be told apart in the rendered number.
+    pub fn validate(&mut self, requested_seq_length: Option<u32>, policy: &FormatPolicy) -> Result<(), PrefixRuleError> {
+        if self.prefix_key.contains(':') {
+            return Err(RuleError::InvalidPrefixKey.into());
+        }
+        self.compile()?;
+        let template = self.template()?;
+        template.check_scope_separated(&self.sequence_scope)?;
+        self.seq_length = template.resolve_seq_length(requested_seq_length)?;
+        self.compiled_verifier = template.verifier(&self.prefix_key, self.seq_length).map(Arc::new);
+        for (field, token) in self.reset_policy.required_fields() {
+            if !template.renders(*field) {
+                return Err(template.missing_placeholder(token).into());
+            }
+        }
+        self.validate_step(&template)?;
+        self.validate_generator(&template)?;
+        Ok(policy.check(&template)?)
+    }
+
+    /// Steps keep numbers unique as long as every issued value stays in its residue class:
+    /// `offset` has to be a residue of `step`, and wrapping at the width's capacity may
+    /// only happen when that capacity is a multiple of `step`.
+    fn validate_step(&self, template: &FormatTemplate) -> Result<(), RuleError> {
+        let step = self.step();
+        if self.offset >= step {
+            Err(RuleError::OffsetOutOfStep)
+        } else if self.overflow_policy == OverflowPolicy::Wrap
+            && template.sequence_capacity(self.seq_length).is_some_and(|capacity| capacity % step != 0)
+        {
+            Err(RuleError::WrapOutOfStep)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Generators other than the counter issue values of their own: the sequence width
+    /// has to hold all of them (or widen), there is no step to honour, and only the
+    /// counter keeps a free list to recycle from. A scrambled sequence is permuted within
+    /// its width, so it can never widen past it.
+    fn validate_generator(&self, template: &FormatTemplate) -> Result<(), PrefixRuleError> {
+        if self.scramble_key.is_some() && self.overflow_policy == OverflowPolicy::Widen {
+            Err(RuleError::ScrambleWidens.into())
+        } else if !self.generator.honours_step() && (self.step() != 1 || self.offset != 0) {
+            Err(RuleError::StepNotSupported.into())
+        } else if self.recycle && self.generator != GeneratorKind::Counter {
+            Err(RuleError::RecycleNotSupported.into())
+        } else if self.generator.max_value().is_some_and(|max| {
+            self.overflow_policy != OverflowPolicy::Widen
+                && template.sequence_capacity(self.seq_length).is_some_and(|capacity| capacity <= max)
+        }) {
+            Err(RuleError::SequenceTooNarrow.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn step(&self) -> u64 {
+        self.step.max(1)
+    }
+
+    /// The first value a fresh counter issues: the smallest value of the rule's residue
+    /// class that is not below `initial_seq`.
+    pub fn first_sequence(&self) -> u64 {
+        let step = self.step();
+        let behind = (self.offset % step + step - self.initial_seq % step) % step;
+        self.initial_seq.saturating_add(behind)
+    }
+
+    /// Returns the cached template, or parses `format` when the rule was never compiled.
+    pub fn template(&self) -> Result<Arc<FormatTemplate>, TemplateError> {
+        match &self.compiled_format {
+            Some(template) => Ok(template.clone()),
+            None => self.parse_format().map(Arc::new),
+        }
+    }
+
+    /// Returns the cached verifier when it was built for `prefix`, or builds one.
+    pub fn verifier(&self, prefix: &str) -> Result<Arc<Verifier>, String> {
+        match &self.compiled_verifier {
+            Some(verifier) if verifier.is_for(prefix, self.seq_length) => Ok(verifier.clone()),
+            _ => {
+                let template = self.template().map_err(|e| e.to_string())?;
+                let verifier = template.verifier(prefix, self.seq_length);
+                verifier.map(Arc::new).ok_or_else(|| format!("Format of prefix {} is too large to verify", prefix))
+            }
+        }
+    }
+
+    fn parse_format(&self) -> Result<FormatTemplate, TemplateError> {
+        FormatTemplate::parse_with_variables(&self.format, &self.variable_names())
+    }
+
+    fn variable_names(&self) -> Vec<&str> {
+        self.variables.iter().map(|variable| variable.name.as_str()).collect()
+    }
+
+    /// Key of the counter a number issued at `now` with `variables` is drawn from: the
+    /// prefix key, followed by the rendered `sequence_scope` components (with `:` escaped)
+    /// and, under a reset policy, the current period. A new period therefore starts a fresh counter, which
+    /// every node switches to on its own without any coordination.
+    pub fn sequence_key(
+        &self,
+        prefix_key: &str,
+        variables: &HashMap<String, String>,
+        now: DateTime<Utc>,
+    ) -> Result<String, String> {
+        let now = now.with_timezone(&self.time_zone()?);
+        let mut key = prefix_key.to_string();
+        if !self.sequence_scope.is_empty() {
+            let scope = match &self.compiled_scope {
+                Some(scope) => scope.clone(),
+                None => {
+                    let template = self.template().map_err(|e| e.to_string())?;
+                    Arc::new(template.scope_template(&self.sequence_scope, &self.variable_names()).map_err(|e| e.to_string())?)
+                }
+            };
+            let ctx = RenderContext {
+                prefix: prefix_key,
+                sequence: 0,
+                seq_length: self.seq_length,
+                now,
+                scramble_key: None,
+                variables,
+            };
+            key.push(':');
+            key.push_str(&scope.render_scope(&ctx)?);
+        }
+        if let Some(pattern) = self.reset_policy.period_pattern() {
+            key.push(':');
+            key.push_str(&now.format(pattern).to_string());
+        }
+        Ok(key)
+    }
+
+    /// Collects the variables of a request. Query strings often carry parameters meant for
+    /// something else (`?trace=1`), so only declared names are taken from `query`; every
+    /// `body` value is taken, and wins over a query value of the same name.
+    pub fn request_variables(
+        &self,
+        mut query: HashMap<String, String>,
+        body: HashMap<String, String>,
+    ) -> HashMap<String, String> {
+        query.retain(|name, _| self.variables.iter().any(|variable| &variable.name == name));
+        query.extend(body);
+        query
+    }
+
+    /// Checks the variables supplied with a generate request: each one has to be declared
+    /// and accepted by its declaration, and every variable the format uses is required.
+    pub fn check_variables(&self, values: &HashMap<String, String>) -> Result<(), VariableError> {
+        for (name, value) in values {
+            match self.variables.iter().find(|variable| &variable.name == name) {
+                Some(variable) => variable.check(value)?,
+                None => return Err(VariableError::Unknown(name.clone())),
+            }
+        }
+        let template = self.template()?;
+        for placeholder in template.placeholders() {
+            if let Placeholder::Variable(name) = placeholder {
+                if !values.contains_key(name) {
+                    return Err(VariableError::Missing(name.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the overflow policy to a freshly generated counter value, returning the
+    /// value to render.
+    pub fn fit_sequence(&self, sequence: u64) -> Result<u64, SequenceGeneratorError> {
+        let template = self.template().map_err(|e| SequenceGeneratorError::Other(e.to_string()))?;
+        let Some(capacity) = template.sequence_capacity(self.seq_length) else {
+            return Ok(sequence);
+        };
+        match self.overflow_policy {
+            _ if sequence < capacity => Ok(sequence),
+            OverflowPolicy::Error => Err(SequenceGeneratorError::Overflow { limit: self.last_in_class(capacity - 1) }),
+            OverflowPolicy::Wrap => Ok(sequence % capacity),
+            OverflowPolicy::Widen => Ok(sequence),
+        }
+    }
+
+    /// Checks that a reserved range renders in order: its last value fits (values only
+    /// grow), and under `Wrap` the range does not run past the width and start over midway.
+    pub fn fit_range(&self, range: &SequenceRange) -> Result<(), SequenceGeneratorError> {
+        self.fit_sequence(range.end())?;
+        if self.overflow_policy != OverflowPolicy::Wrap {
+            return Ok(());
+        }
+        let template = self.template().map_err(|e| SequenceGeneratorError::Other(e.to_string()))?;
+        let Some(capacity) = template.sequence_capacity(self.seq_length) else {
+            return Ok(());
+        };
+        let lap_end = (range.start / capacity).saturating_add(1).saturating_mul(capacity) - 1;
+        if range.end() > lap_end {
+            return Err(SequenceGeneratorError::Overflow { limit: self.last_in_class(lap_end) });
+        }
+        Ok(())
+    }
+
+    /// The largest value of the rule's residue class not above `value`.
+    fn last_in_class(&self, value: u64) -> u64 {
+        let step = self.step();
+        value - (value + step - self.offset % step) % step
+    }
+
+    /// Checks a requested batch size against the rule's limit, against the number of
+    /// distinct values its sequence width can render, so a batch cannot repeat a wrapped
+    /// number, and against what its generator reserves at once. Checked before any value
+    /// is reserved.
+    pub fn check_batch_size(&self, count: u64) -> Result<(), String> {
+        check_count(count, self.count_limit(self.max_batch.unwrap_or(DEFAULT_MAX_BATCH)))
+    }
+
+    /// Checks a requested streamed range size against the rule's limit and, like
+    /// `check_batch_size`, against its sequence capacity and generator.
+    pub fn check_range_size(&self, count: u64) -> Result<(), String> {
+        check_count(count, self.count_limit(self.max_range.unwrap_or(DEFAULT_MAX_RANGE)))
+    }
+
+    fn count_limit(&self, max: u64) -> u64 {
+        [self.sequence_capacity(), self.generator.max_count()].into_iter().flatten().fold(max, u64::min)
+    }
+
+    /// How many values of the rule's residue class fit the sequence width; `None` when the
+    /// width is unbounded or the rule widens past it.
+    pub fn sequence_capacity(&self) -> Option<u64> {
+        Some(self.width_capacity()?.div_ceil(self.step()))
+    }
+
+    /// How many more values a counter whose last value was `last` can issue before it runs
+    /// past the width (`None` for a counter not used yet). Wrapping rules start over, so
+    /// they always have a whole width's worth left.
+    pub fn remaining_capacity(&self, last: Option<u64>) -> Option<u64> {
+        match (self.overflow_policy, last) {
+            (OverflowPolicy::Error, Some(last)) => Some(self.width_capacity()?.saturating_sub(last + 1) / self.step()),
+            _ => self.sequence_capacity(),
+        }
+    }
+
+    /// Values the sequence width renders, unless the rule widens past it.
+    fn width_capacity(&self) -> Option<u64> {
+        if self.overflow_policy == OverflowPolicy::Widen {
+            return None;
+        }
+        self.template().ok()?.sequence_capacity(self.seq_length)
+    }
+
+    /// Whether `sequence_key` counts in a reset period that is over at `now`, so its
+    /// counter will not be asked for values again. Always `false` for rules that never
+    /// reset.
+    pub fn period_is_over(&self, sequence_key: &str, now: DateTime<Utc>) -> bool {
+        let (Some(pattern), Ok(time_zone)) = (self.reset_policy.period_pattern(), self.time_zone()) else {
+            return false;
+        };
+        // Period keys are fixed-width digits, so they order like the periods they name.
+        let current = now.with_timezone(&time_zone).format(pattern).to_string();
+        sequence_key.rsplit(':').next().is_some_and(|period| period < current.as_str())
+    }
+
+    /// Resolves the rule's time zone, falling back to UTC when none is configured.
+    pub fn time_zone(&self) -> Result<Tz, String> {
+        match &self.timezone {
+            Some(name) => name
+                .parse::<Tz>()
+                .map_err(|_| format!("Unknown time zone: {}", name)),
+            None => Ok(Tz::UTC),
+        }
+    }
+}
+
+fn check_count(count: u64, max: u64) -> Result<(), String> {
+    match count {
+        0 => Err("count must be at least 1".to_string()),
+        _ if count > max => Err(format!("count must not exceed {}", max)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format_error(rule: &mut PrefixRule) -> TemplateError {
+        match rule.validate(None, &FormatPolicy::default()) {
+            Err(PrefixRuleError::Format(e)) => e,
+            other => panic!("expected a format error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_time_zone_defaults_to_utc() {
+        let rule = PrefixRule::default();
+        assert_eq!(rule.time_zone().unwrap(), Tz::UTC);
+    }
+
+    #[test]
+    fn test_time_zone_parses_iana_name() {
+        let rule = PrefixRule { timezone: Some("Asia/Taipei".to_string()), ..Default::default() };
+        assert_eq!(rule.time_zone().unwrap(), Tz::Asia__Taipei);
+    }
+
+    #[test]
+    fn test_time_zone_rejects_unknown_name() {
+        let rule = PrefixRule { timezone: Some("Mars/Olympus_Mons".to_string()), ..Default::default() };
+        assert!(rule.time_zone().is_err());
+    }
+
+    #[test]
+    fn test_validate_accepts_format_without_year() {
+        let mut rule = PrefixRule { format: "{prefix}-{SEQ:8}".to_string(), ..Default::default() };
+        rule.validate(None, &FormatPolicy::default()).unwrap();
+        assert_eq!(rule.seq_length, 8);
+        assert!(rule.compiled_format.is_some());
+    }
+
+    #[test]
+    fn test_validate_requires_sequence() {
+        let mut rule = PrefixRule { format: "INVALID-2024".to_string(), ..Default::default() };
+        assert!(rule.validate(Some(4), &FormatPolicy::default()).is_err());
+    }
+
+    #[test]
+    fn test_validate_applies_policy() {
+        let policy = FormatPolicy::require(&["year"]).unwrap();
+        let mut rule = PrefixRule { format: "{prefix}-{SEQ:8}".to_string(), ..Default::default() };
+        assert!(rule.validate(None, &policy).is_err());
+    }
+
+    #[test]
+    fn test_overflow_policies() {
+        let rule = |overflow_policy| PrefixRule { format: "{prefix}-{SEQ:4}".to_string(), overflow_policy, ..Default::default() };
+        assert_eq!(rule(OverflowPolicy::Error).fit_sequence(9_999).unwrap(), 9_999);
+        assert!(matches!(
+            rule(OverflowPolicy::Error).fit_sequence(10_000),
+            Err(SequenceGeneratorError::Overflow { limit: 9_999 })
+        ));
+        assert_eq!(rule(OverflowPolicy::Wrap).fit_sequence(10_002).unwrap(), 2);
+        assert_eq!(rule(OverflowPolicy::Widen).fit_sequence(10_002).unwrap(), 10_002);
+    }
+
+    #[test]
+    fn test_overflow_policy_uses_encoded_capacity() {
+        let rule = PrefixRule { format: "{SEQX:2}".to_string(), overflow_policy: OverflowPolicy::Wrap, ..Default::default() };
+        assert_eq!(rule.fit_sequence(257).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_fit_range() {
+        let rule = |overflow_policy| PrefixRule { format: "{SEQ:2}".to_string(), overflow_policy, ..Default::default() };
+        let range = |start, count| SequenceRange { start, count, step: 1 };
+        assert!(rule(OverflowPolicy::Wrap).fit_range(&range(100, 100)).is_ok());
+        // A wrapping range may not start over partway through.
+        assert!(matches!(
+            rule(OverflowPolicy::Wrap).fit_range(&range(195, 10)),
+            Err(SequenceGeneratorError::Overflow { limit: 199 })
+        ));
+        assert!(matches!(
+            rule(OverflowPolicy::Error).fit_range(&range(95, 10)),
+            Err(SequenceGeneratorError::Overflow { limit: 99 })
+        ));
+        assert!(rule(OverflowPolicy::Widen).fit_range(&range(95, 10)).is_ok());
+    }
+
+    fn rule_with_variables() -> PrefixRule {
+        let mut rule = PrefixRule {
+            format: "{region}-{dept}-{SEQ:5}".to_string(),
+            variables: vec![
+                TemplateVariable::new("region").allowing(["TW", "JP"]),
+                TemplateVariable::new("dept").matching("[A-Z]{2,4}"),
+                TemplateVariable::new("channel"),
+            ],
+            ..Default::default()
+        };
+        rule.validate(None, &FormatPolicy::default()).unwrap();
+        rule
+    }
+
+    fn values(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn test_check_variables() {
+        let rule = rule_with_variables();
+        assert_eq!(rule.check_variables(&values(&[("region", "TW"), ("dept", "HR")])), Ok(()));
+        // Declared but unused variables are optional.
+        assert_eq!(rule.check_variables(&values(&[("region", "TW"), ("dept", "HR"), ("channel", "web")])), Ok(()));
+        assert_eq!(
+            rule.check_variables(&values(&[("region", "TW")])),
+            Err(VariableError::Missing("dept".to_string()))
+        );
+        assert!(matches!(
+            rule.check_variables(&values(&[("region", "US"), ("dept", "HR")])),
+            Err(VariableError::NotAllowed { .. })
+        ));
+        assert_eq!(
+            rule.check_variables(&values(&[("region", "TW"), ("dept", "HR"), ("regoin", "TW")])),
+            Err(VariableError::Unknown("regoin".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_request_variables_ignore_undeclared_query_keys() {
+        let rule = rule_with_variables();
+        let variables = rule.request_variables(
+            values(&[("region", "US"), ("dept", "HR"), ("trace", "1")]),
+            values(&[("region", "TW"), ("regoin", "TW")]),
+        );
+        assert_eq!(variables, values(&[("region", "TW"), ("dept", "HR"), ("regoin", "TW")]));
+        // A typo in the body is still reported.
+        assert_eq!(rule.check_variables(&variables), Err(VariableError::Unknown("regoin".to_string())));
+    }
+
+    #[test]
+    fn test_undeclared_variable_is_unknown_placeholder() {
+        let mut rule = PrefixRule { format: "{region}-{SEQ:5}".to_string(), ..Default::default() };
+        assert_eq!(
+            format_error(&mut rule).reason,
+            TemplateErrorReason::UnknownPlaceholder
+        );
+    }
+
+    #[test]
+    fn test_variable_shadowing_builtin_is_rejected() {
+        let mut rule = PrefixRule {
+            format: "{year}-{SEQ:5}".to_string(),
+            variables: vec![TemplateVariable::new("year")],
+            ..Default::default()
+        };
+        assert_eq!(
+            rule.validate(None, &FormatPolicy::default()),
+            Err(TemplateError::new("{year}", 14, TemplateErrorReason::InvalidVariable).into())
+        );
+    }
+
+    #[test]
+    fn test_variables_survive_storage() {
+        let json = serde_json::to_string(&rule_with_variables()).unwrap();
+        let mut loaded: PrefixRule = serde_json::from_str(&json).unwrap();
+        loaded.compile().unwrap();
+        assert_eq!(loaded.check_variables(&values(&[("region", "JP"), ("dept", "OPS")])), Ok(()));
+        assert!(loaded.check_variables(&values(&[("region", "JP"), ("dept", "ops")])).is_err());
+    }
+
+    #[test]
+    fn test_sequence_key() {
+        use chrono::TimeZone;
+
+        let mut rule = rule_with_variables();
+        let now = Utc.with_ymd_and_hms(2026, 10, 16, 0, 0, 0).unwrap();
+        let variables = values(&[("region", "TW"), ("dept", "HR")]);
+        assert_eq!(rule.sequence_key("ORDER", &variables, now).unwrap(), "ORDER");
+
+        rule.format = "{region}-{date:%Y%m%d}-{dept}-{SEQ:5}".to_string();
+        rule.sequence_scope = vec!["region".to_string(), "date:%Y%m%d".to_string()];
+        rule.validate(None, &FormatPolicy::default()).unwrap();
+        assert_eq!(rule.sequence_key("ORDER", &variables, now).unwrap(), "ORDER:TW:20261016");
+        let variables = values(&[("region", "JP"), ("dept", "HR")]);
+        assert_eq!(rule.sequence_key("ORDER", &variables, now).unwrap(), "ORDER:JP:20261016");
+    }
+
+    #[test]
+    fn test_sequence_keys_do_not_collide() {
+        use chrono::TimeZone;
+
+        let now = Utc.with_ymd_and_hms(2026, 10, 16, 0, 0, 0).unwrap();
+        let mut rule = PrefixRule {
+            prefix_key: "ORDER".to_string(),
+            format: "{region}-{SEQ:5}".to_string(),
+            variables: vec![TemplateVariable::new("region")],
+            sequence_scope: vec!["region".to_string()],
+            ..Default::default()
+        };
+        rule.validate(None, &FormatPolicy::default()).unwrap();
+        // A value holding the separator cannot pose as the key of another scope or period.
+        let key = rule.sequence_key("ORDER", &values(&[("region", "TW:2026")]), now).unwrap();
+        assert_eq!(key, "ORDER:TW%3A2026");
+
+        // Nor can a prefix key pose as a scoped key of another prefix.
+        rule.prefix_key = "ORDER:TW".to_string();
+        assert_eq!(rule.validate(None, &FormatPolicy::default()), Err(RuleError::InvalidPrefixKey.into()));
+    }
+
+    #[test]
+    fn test_scoped_variables_must_be_separated() {
+        let mut rule = rule_with_variables();
+        rule.format = "{region}{dept}-{SEQ:5}".to_string();
+        rule.sequence_scope = vec!["region".to_string(), "dept".to_string()];
+        assert_eq!(format_error(&mut rule).reason, TemplateErrorReason::AmbiguousScope);
+
+        rule.format = "{region}/{dept}-{SEQ:5}".to_string();
+        assert!(rule.validate(None, &FormatPolicy::default()).is_ok());
+    }
+
+    #[test]
+    fn test_sequence_scope_must_be_rendered_by_format() {
+        let mut rule = PrefixRule {
+            format: "{prefix}-{SEQ:5}".to_string(),
+            sequence_scope: vec!["yyyy".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            format_error(&mut rule).reason,
+            TemplateErrorReason::MissingPlaceholder
+        );
+    }
+
+    #[test]
+    fn test_counter_ttl_outlasts_the_period() {
+        assert_eq!(ResetPolicy::Never.counter_ttl_secs(), None);
+        assert_eq!(ResetPolicy::Daily.counter_ttl_secs(), Some(25 * 3600 + COUNTER_GRACE_SECS));
+        assert!(ResetPolicy::Monthly.counter_ttl_secs().unwrap() > 31 * 24 * 3600);
+        assert!(ResetPolicy::Yearly.counter_ttl_secs().unwrap() > 366 * 24 * 3600);
+    }
+
+    #[test]
+    fn test_reset_policy_period_keys() {
+        use chrono::TimeZone;
+
+        let rule = |reset_policy| PrefixRule {
+            format: "INV-{yyyy}{MM}{dd}-{SEQ:5}".to_string(),
+            reset_policy,
+            timezone: Some("Asia/Taipei".to_string()),
+            ..Default::default()
+        };
+        // Already 2026-01-01 in Taipei.
+        let now = Utc.with_ymd_and_hms(2025, 12, 31, 17, 0, 0).unwrap();
+        let key = |policy| rule(policy).sequence_key("INV", &HashMap::new(), now).unwrap();
+        assert_eq!(key(ResetPolicy::Never), "INV");
+        assert_eq!(key(ResetPolicy::Yearly), "INV:2026");
+        assert_eq!(key(ResetPolicy::Monthly), "INV:202601");
+        assert_eq!(key(ResetPolicy::Daily), "INV:20260101");
+    }
+
+    #[test]
+    fn test_period_is_over() {
+        use chrono::TimeZone;
+        let now = Utc.with_ymd_and_hms(2026, 10, 16, 23, 30, 0).unwrap();
+        let monthly = PrefixRule { reset_policy: ResetPolicy::Monthly, ..Default::default() };
+        assert!(monthly.period_is_over("INV:202609", now));
+        assert!(!monthly.period_is_over("INV:202610", now));
+        let taipei = PrefixRule { reset_policy: ResetPolicy::Daily, timezone: Some("Asia/Taipei".to_string()), ..Default::default() };
+        assert!(taipei.period_is_over("INV:20261016", now));
+        assert!(!PrefixRule::default().period_is_over("INV:202609", now));
+    }
+
+    #[test]
+    fn test_reset_policy_requires_period_in_format() {
+        let mut rule = PrefixRule {
+            format: "INV-{yyyy}-{SEQ:5}".to_string(),
+            reset_policy: ResetPolicy::Monthly,
+            ..Default::default()
+        };
+        assert_eq!(
+            rule.validate(None, &FormatPolicy::default()),
+            Err(TemplateError::new("{MM}", 18, TemplateErrorReason::MissingPlaceholder).into())
+        );
+        rule.reset_policy = ResetPolicy::Yearly;
+        assert!(rule.validate(None, &FormatPolicy::default()).is_ok());
+    }
+
+    #[test]
+    fn test_first_sequence_respects_offset() {
+        let rule = |initial_seq, step, offset| PrefixRule { initial_seq, step, offset, ..Default::default() };
+        assert_eq!(rule(1, 0, 0).first_sequence(), 1);
+        assert_eq!(rule(1, 2, 1).first_sequence(), 1);
+        assert_eq!(rule(1, 2, 0).first_sequence(), 2);
+        assert_eq!(rule(1000, 10, 0).first_sequence(), 1000);
+        assert_eq!(rule(1001, 10, 0).first_sequence(), 1010);
+        assert_eq!(rule(1001, 10, 3).first_sequence(), 1003);
+    }
+
+    #[test]
+    fn test_step_validation() {
+        let mut rule = PrefixRule { format: "{prefix}-{SEQ:4}".to_string(), step: 2, offset: 2, ..Default::default() };
+        assert_eq!(rule.validate(None, &FormatPolicy::default()), Err(RuleError::OffsetOutOfStep.into()));
+
+        rule.offset = 1;
+        rule.overflow_policy = OverflowPolicy::Wrap;
+        assert!(rule.validate(None, &FormatPolicy::default()).is_ok());
+        rule.step = 3;
+        assert_eq!(rule.validate(None, &FormatPolicy::default()), Err(RuleError::WrapOutOfStep.into()));
+    }
+
+    #[test]
+    fn test_overflow_limit_accounts_for_step() {
+        let rule = PrefixRule { format: "{prefix}-{SEQ:2}".to_string(), step: 10, offset: 3, ..Default::default() };
+        assert_eq!(rule.fit_sequence(93).unwrap(), 93);
+        assert!(matches!(rule.fit_sequence(103), Err(SequenceGeneratorError::Overflow { limit: 93 })));
+    }
+
+    #[test]
+    fn test_snowflake_validation() {
+        let mut rule = PrefixRule {
+            format: "{prefix}-{SEQ:12}".to_string(),
+            generator: GeneratorKind::Snowflake,
+            ..Default::default()
+        };
+        assert_eq!(rule.validate(None, &FormatPolicy::default()), Err(RuleError::SequenceTooNarrow.into()));
+
+        rule.overflow_policy = OverflowPolicy::Widen;
+        assert!(rule.validate(None, &FormatPolicy::default()).is_ok());
+
+        rule.format = "{prefix}-{SEQ:19}".to_string();
+        rule.overflow_policy = OverflowPolicy::Error;
+        assert!(rule.validate(None, &FormatPolicy::default()).is_ok());
+
+        rule.step = 2;
+        assert_eq!(rule.validate(None, &FormatPolicy::default()), Err(RuleError::StepNotSupported.into()));
+
+        rule.step = 0;
+        rule.recycle = true;
+        assert_eq!(rule.validate(None, &FormatPolicy::default()), Err(RuleError::RecycleNotSupported.into()));
+    }
+
+    #[test]
+    fn test_check_variables_reports_unparsable_format() {
+        let rule = PrefixRule { format: "{yaer}-{SEQ:4}".to_string(), ..Default::default() };
+        assert!(matches!(
+            rule.check_variables(&HashMap::new()),
+            Err(VariableError::Format(TemplateError { reason: TemplateErrorReason::UnknownPlaceholder, .. }))
+        ));
+    }
+
+    #[test]
+    fn test_scrambled_rule_cannot_widen() {
+        let mut rule = PrefixRule {
+            format: "{prefix}-{SEQ:6}".to_string(),
+            scramble_key: Some("secret".to_string()),
+            overflow_policy: OverflowPolicy::Widen,
+            ..Default::default()
+        };
+        assert_eq!(rule.validate(None, &FormatPolicy::default()), Err(RuleError::ScrambleWidens.into()));
+
+        rule.overflow_policy = OverflowPolicy::Wrap;
+        assert!(rule.validate(None, &FormatPolicy::default()).is_ok());
+        rule.overflow_policy = OverflowPolicy::Error;
+        assert!(rule.validate(None, &FormatPolicy::default()).is_ok());
+    }
+
+    #[test]
+    fn test_batch_size_limit() {
+        let rule = PrefixRule::default();
+        assert!(rule.check_batch_size(0).is_err());
+        assert!(rule.check_batch_size(DEFAULT_MAX_BATCH).is_ok());
+        assert!(rule.check_batch_size(DEFAULT_MAX_BATCH + 1).is_err());
+
+        let rule = PrefixRule { max_batch: Some(10), ..Default::default() };
+        assert!(rule.check_batch_size(11).is_err());
+        assert!(rule.check_range_size(11).is_ok());
+        // A batch never holds more numbers than the width can render.
+        let rule = |overflow_policy| PrefixRule {
+            format: "{SEQ:2}".to_string(),
+            seq_length: 2,
+            step: 2,
+            overflow_policy,
+            ..Default::default()
+        };
+        assert!(rule(OverflowPolicy::Wrap).check_batch_size(50).is_ok());
+        assert_eq!(rule(OverflowPolicy::Wrap).check_batch_size(51), Err("count must not exceed 50".to_string()));
+        assert!(rule(OverflowPolicy::Error).check_batch_size(51).is_err());
+        assert!(rule(OverflowPolicy::Widen).check_batch_size(51).is_ok());
+
+        assert_eq!(rule(OverflowPolicy::Error).remaining_capacity(None), Some(50));
+        assert_eq!(rule(OverflowPolicy::Error).remaining_capacity(Some(90)), Some(4));
+        assert_eq!(rule(OverflowPolicy::Error).remaining_capacity(Some(98)), Some(0));
+        assert_eq!(rule(OverflowPolicy::Wrap).remaining_capacity(Some(98)), Some(50));
+        assert_eq!(rule(OverflowPolicy::Widen).remaining_capacity(Some(98)), None);
+
+        // Snowflake ranges come from one millisecond, whatever the configured limits say.
+        let snowflake = PrefixRule { generator: GeneratorKind::Snowflake, max_batch: Some(10_000), ..Default::default() };
+        assert!(snowflake.check_batch_size(COUNTER_CAPACITY).is_ok());
+        assert!(snowflake.check_batch_size(COUNTER_CAPACITY + 1).is_err());
+        assert!(snowflake.check_range_size(COUNTER_CAPACITY + 1).is_err());
+    }
+
+    #[test]
+    fn test_compiled_format_is_not_serialized() {
+        let mut rule = PrefixRule { format: "{prefix}-{SEQ:4}".to_string(), ..Default::default() };
+        rule.compile().unwrap();
+        let json = serde_json::to_string(&rule).unwrap();
+        assert!(!json.contains("compiled_format"));
+
+        let loaded: PrefixRule = serde_json::from_str(&json).unwrap();
+        assert!(loaded.compiled_format.is_none());
+    }
+
+    #[test]
+    fn test_verifier_is_compiled_once() {
+        let mut rule = PrefixRule {
+            prefix_key: "ORD".to_string(),
+            format: "{prefix}-{SEQ}".to_string(),
+            ..Default::default()
+        };
+        rule.validate(Some(4), &FormatPolicy::default()).unwrap();
+        let verifier = rule.verifier("ORD").unwrap();
+        assert!(Arc::ptr_eq(&verifier, &rule.verifier("ORD").unwrap()));
+        assert!(verifier.is_for("ORD", 4));
+        // Another prefix gets a verifier of its own.
+        assert!(!Arc::ptr_eq(&verifier, &rule.verifier("INV").unwrap()));
+    }
+
+    #[test]
+    fn test_rule_without_timezone_deserializes() {
+        let json = r#"{"prefix_key":"A","format":"A-{SEQ:3}","seq_length":3,"initial_seq":1,"network_partition":false}"#;
+        let rule: PrefixRule = serde_json::from_str(json).unwrap();
+        assert!(rule.timezone.is_none());
+        // Numbers of rules stored before overflow policies existed keep widening.
+        assert_eq!(rule.overflow_policy, OverflowPolicy::Widen);
+        assert_eq!(PrefixRule::default().overflow_policy, OverflowPolicy::Error);
+    }
 }
@@ -0,0 +1,188 @@
+//! Audit trail of reserved ranges.
+//!
+//! Large allocations (pre-printed labels, bulk exports) are streamed straight to the
+//! caller, so the numbers themselves are never stored. What is recorded instead is the
+//! block of counter values handed out, which is enough to tell afterwards who was issued
+//! which numbers and to re-render them.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use redis::{AsyncCommands, Client, RedisError};
+use serde::{Deserialize, Serialize};
+use sled::{Db, Tree};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use thiserror::Error;
+
+use crate::sequence_generator::SequenceRange;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RangeReservation {
+    #[serde(rename = "prefixKey")]
+    pub prefix_key: String,
+    /// Counter the range was taken from (see `PrefixRule::sequence_key`).
+    #[serde(rename = "sequenceKey")]
+    pub sequence_key: String,
+    pub range: SequenceRange,
+    /// Template variables the numbers were rendered with.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub variables: HashMap<String, String>,
+    #[serde(rename = "reservedAt")]
+    pub reserved_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Error)]
+pub enum RangeLedgerError {
+    #[error("Redis error: {0}")]
+    RedisError(#[from] RedisError),
+    #[error("Sled error: {0}")]
+    SledError(#[from] sled::Error),
+    #[error("Serialization error: {0}")]
+    SerializationError(#[from] serde_json::Error),
+}
+
+#[async_trait]
+pub trait RangeLedger {
+    async fn record(&self, reservation: &RangeReservation) -> Result<(), RangeLedgerError>;
+
+    /// Reservations made for `prefix_key`, oldest first.
+    async fn reservations(&self, prefix_key: &str) -> Result<Vec<RangeReservation>, RangeLedgerError>;
+}
+
+/// Keeps each prefix's reservations in a Redis list, appended in reservation order.
+pub struct RedisRangeLedger {
+    redis_client: Client,
+}
+
+impl RedisRangeLedger {
+    pub fn new(redis_url: String) -> Result<Self, RedisError> {
+        let redis_client = Client::open(redis_url)?;
+        Ok(RedisRangeLedger { redis_client })
+    }
+
+    fn get_redis_key(prefix_key: &str) -> String {
+        format!("range_reservations:{}", prefix_key)
+    }
+}
+
+#[async_trait]
+impl RangeLedger for RedisRangeLedger {
+    async fn record(&self, reservation: &RangeReservation) -> Result<(), RangeLedgerError> {
+        let mut conn = self.redis_client.get_async_connection().await?;
+        let json = serde_json::to_string(reservation)?;
+        conn.rpush::<_, _, ()>(Self::get_redis_key(&reservation.prefix_key), json).await?;
+        Ok(())
+    }
+
+    async fn reservations(&self, prefix_key: &str) -> Result<Vec<RangeReservation>, RangeLedgerError> {
+        let mut conn = self.redis_client.get_async_connection().await?;
+        let entries: Vec<String> = conn.lrange(Self::get_redis_key(prefix_key), 0, -1).await?;
+        entries
+            .iter()
+            .map(|json| serde_json::from_str(json).map_err(RangeLedgerError::from))
+            .collect()
+    }
+}
+
+/// Keeps reservations in a tree of the embedded database `SledStorage` uses, for a single
+/// node running without Redis. Each one is stored under its prefix, a zero byte and an id
+/// from the database's monotonic generator, so a prefix's reservations scan in order.
+pub struct SledRangeLedger {
+    db: Db,
+    reservations: Tree,
+}
+
+impl SledRangeLedger {
+    pub fn open(db: &Db) -> Result<Self, sled::Error> {
+        Ok(SledRangeLedger { db: db.clone(), reservations: db.open_tree("range_reservations")? })
+    }
+
+    fn prefix(prefix_key: &str) -> Vec<u8> {
+        let mut prefix = prefix_key.as_bytes().to_vec();
+        prefix.push(0);
+        prefix
+    }
+}
+
+#[async_trait]
+impl RangeLedger for SledRangeLedger {
+    async fn record(&self, reservation: &RangeReservation) -> Result<(), RangeLedgerError> {
+        let mut key = Self::prefix(&reservation.prefix_key);
+        key.extend_from_slice(&self.db.generate_id()?.to_be_bytes());
+        self.reservations.insert(key, serde_json::to_vec(reservation)?)?;
+        self.reservations.flush_async().await?;
+        Ok(())
+    }
+
+    async fn reservations(&self, prefix_key: &str) -> Result<Vec<RangeReservation>, RangeLedgerError> {
+        self.reservations
+            .scan_prefix(Self::prefix(prefix_key))
+            .values()
+            .map(|json| Ok(serde_json::from_slice(&json?)?))
+            .collect()
+    }
+}
+
+/// Keeps reservations in the process, for tests and deployments that run without Redis.
+#[derive(Debug, Default)]
+pub struct MemoryRangeLedger {
+    reservations: Mutex<HashMap<String, Vec<RangeReservation>>>,
+}
+
+impl MemoryRangeLedger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl RangeLedger for MemoryRangeLedger {
+    async fn record(&self, reservation: &RangeReservation) -> Result<(), RangeLedgerError> {
+        let mut reservations = self.reservations.lock().unwrap_or_else(|e| e.into_inner());
+        reservations.entry(reservation.prefix_key.clone()).or_default().push(reservation.clone());
+        Ok(())
+    }
+
+    async fn reservations(&self, prefix_key: &str) -> Result<Vec<RangeReservation>, RangeLedgerError> {
+        let reservations = self.reservations.lock().unwrap_or_else(|e| e.into_inner());
+        Ok(reservations.get(prefix_key).cloned().unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reservation_round_trip() {
+        let reservation = RangeReservation {
+            prefix_key: "LABEL".to_string(),
+            sequence_key: "LABEL:2026".to_string(),
+            range: SequenceRange { start: 1, count: 5_000_000, step: 1 },
+            variables: HashMap::new(),
+            reserved_at: Utc::now(),
+        };
+        let json = serde_json::to_string(&reservation).unwrap();
+        assert!(json.contains("\"sequenceKey\":\"LABEL:2026\""));
+        assert!(!json.contains("variables"));
+        assert_eq!(serde_json::from_str::<RangeReservation>(&json).unwrap(), reservation);
+    }
+
+    #[tokio::test]
+    async fn test_sled_ledger_keeps_reservations_in_order() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let ledger = SledRangeLedger::open(&db).unwrap();
+        let reservation = |prefix_key: &str, start| RangeReservation {
+            prefix_key: prefix_key.to_string(),
+            sequence_key: prefix_key.to_string(),
+            range: SequenceRange { start, count: 10, step: 1 },
+            variables: HashMap::new(),
+            reserved_at: Utc::now(),
+        };
+        for (prefix_key, start) in [("LABEL", 1), ("LABEL2", 1), ("LABEL", 11)] {
+            ledger.record(&reservation(prefix_key, start)).await.unwrap();
+        }
+        let starts: Vec<u64> = ledger.reservations("LABEL").await.unwrap().iter().map(|r| r.range.start).collect();
+        assert_eq!(starts, vec![1, 11]);
+    }
+}
@@ -0,0 +1,83 @@
+use async_trait::async_trait;
+use redis::{AsyncCommands, Client, Script};
+
+use crate::storage_backend::{CounterStep, StorageBackend, StorageError};
+
+/// Applies a `CounterStep` in a single script, so concurrent first calls (e.g. right after
+/// a period rollover on several nodes) cannot both seed the counter.
+///
+/// The expiry of a period's counter is set on its first move (and again should a reseed
+/// have cleared it), so the counters of past periods do not pile up.
+///
+/// KEYS[1]: the counter. ARGV: seed, step, offset, increment, seconds to keep the counter
+/// (0 keeps it for good).
+const ADVANCE_COUNTER_SCRIPT: &str = r"
+local current = redis.call('GET', KEYS[1])
+if not current or tonumber(current) < tonumber(ARGV[1]) then
+    redis.call('SET', KEYS[1], ARGV[1])
+else
+    local misalignment = (tonumber(current) - tonumber(ARGV[3])) % tonumber(ARGV[2])
+    if misalignment ~= 0 then
+        redis.call('DECRBY', KEYS[1], misalignment)
+    end
+end
+local value = redis.call('INCRBY', KEYS[1], ARGV[4])
+if tonumber(ARGV[5]) > 0 and redis.call('TTL', KEYS[1]) < 0 then
+    redis.call('EXPIRE', KEYS[1], ARGV[5])
+end
+return value
+";
+
+/// Shared storage for a cluster of nodes: rules are string keys, counters Redis integers
+/// and free lists sorted sets scored by their values.
+pub struct RedisStorage {
+    redis_client: Client,
+    advance_counter: Script,
+}
+
+impl RedisStorage {
+    pub fn new(redis_url: String) -> Result<Self, StorageError> {
+        let redis_client = Client::open(redis_url)?;
+        Ok(RedisStorage { redis_client, advance_counter: Script::new(ADVANCE_COUNTER_SCRIPT) })
+    }
+}
+
+#[async_trait]
+impl StorageBackend for RedisStorage {
+    async fn get(&self, key: &str) -> Result<Option<String>, StorageError> {
+        let mut conn = self.redis_client.get_async_connection().await?;
+        Ok(conn.get(key).await?)
+    }
+
+    async fn set(&self, key: &str, value: &str) -> Result<(), StorageError> {
+        let mut conn = self.redis_client.get_async_connection().await?;
+        conn.set::<_, _, ()>(key, value).await?;
+        Ok(())
+    }
+
+    async fn advance_counter(&self, key: &str, counter_step: &CounterStep) -> Result<i64, StorageError> {
+        let mut conn = self.redis_client.get_async_connection().await?;
+        Ok(self
+            .advance_counter
+            .key(key)
+            .arg(counter_step.seed)
+            .arg(counter_step.step)
+            .arg(counter_step.offset)
+            .arg(counter_step.increment)
+            .arg(counter_step.ttl_secs.unwrap_or(0))
+            .invoke_async(&mut conn)
+            .await?)
+    }
+
+    async fn add_member(&self, key: &str, value: u64) -> Result<bool, StorageError> {
+        let mut conn = self.redis_client.get_async_connection().await?;
+        let added: i64 = redis::cmd("ZADD").arg(key).arg("NX").arg(value).arg(value).query_async(&mut conn).await?;
+        Ok(added == 1)
+    }
+
+    async fn pop_min(&self, key: &str) -> Result<Option<u64>, StorageError> {
+        let mut conn = self.redis_client.get_async_connection().await?;
+        let popped: Vec<u64> = redis::cmd("ZPOPMIN").arg(key).query_async(&mut conn).await?;
+        Ok(popped.first().copied())
+    }
+}
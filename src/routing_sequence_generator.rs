@@ -0,0 +1,109 @@
+//! Dispatches each request to the generator its prefix rule selects.
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::prefix_rule::{GeneratorKind, PrefixRule};
+use crate::sequence_generator::{Claim, SequenceGenerator, SequenceGeneratorError, SequenceRange};
+
+pub struct RoutingSequenceGenerator {
+    generators: HashMap<GeneratorKind, Arc<dyn SequenceGenerator + Send + Sync>>,
+}
+
+impl RoutingSequenceGenerator {
+    /// Starts with the generator serving `GeneratorKind::Counter` rules.
+    pub fn new(counter: Arc<dyn SequenceGenerator + Send + Sync>) -> Self {
+        RoutingSequenceGenerator { generators: HashMap::from([(GeneratorKind::Counter, counter)]) }
+    }
+
+    pub fn with(mut self, kind: GeneratorKind, generator: Arc<dyn SequenceGenerator + Send + Sync>) -> Self {
+        self.generators.insert(kind, generator);
+        self
+    }
+
+    fn route(&self, prefix_rule: &PrefixRule) -> Result<&(dyn SequenceGenerator + Send + Sync), SequenceGeneratorError> {
+        self.generators
+            .get(&prefix_rule.generator)
+            .map(|generator| generator.as_ref())
+            .ok_or_else(|| {
+                SequenceGeneratorError::Other(format!("No {} generator is configured", prefix_rule.generator.as_str()))
+            })
+    }
+}
+
+#[async_trait]
+impl SequenceGenerator for RoutingSequenceGenerator {
+    async fn generate(&self, sequence_key: &str, prefix_rule: &PrefixRule) -> Result<u64, SequenceGeneratorError> {
+        self.route(prefix_rule)?.generate(sequence_key, prefix_rule).await
+    }
+
+    async fn generate_range(
+        &self,
+        sequence_key: &str,
+        prefix_rule: &PrefixRule,
+        count: u64,
+    ) -> Result<SequenceRange, SequenceGeneratorError> {
+        self.route(prefix_rule)?.generate_range(sequence_key, prefix_rule, count).await
+    }
+
+    async fn claim_range(
+        &self,
+        sequence_key: &str,
+        prefix_rule: &PrefixRule,
+        count: u64,
+    ) -> Result<Claim, SequenceGeneratorError> {
+        self.route(prefix_rule)?.claim_range(sequence_key, prefix_rule, count).await
+    }
+
+    async fn release(
+        &self,
+        sequence_key: &str,
+        prefix_rule: &PrefixRule,
+        sequence: u64,
+    ) -> Result<bool, SequenceGeneratorError> {
+        self.route(prefix_rule)?.release(sequence_key, prefix_rule, sequence).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Always issues the same value, so tests can tell generators apart.
+    struct FixedGenerator(u64);
+
+    #[async_trait]
+    impl SequenceGenerator for FixedGenerator {
+        async fn generate(&self, _sequence_key: &str, _prefix_rule: &PrefixRule) -> Result<u64, SequenceGeneratorError> {
+            Ok(self.0)
+        }
+
+        async fn generate_range(
+            &self,
+            _sequence_key: &str,
+            _prefix_rule: &PrefixRule,
+            count: u64,
+        ) -> Result<SequenceRange, SequenceGeneratorError> {
+            Ok(SequenceRange { start: self.0, count, step: 1 })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_routes_by_rule() {
+        let generator = RoutingSequenceGenerator::new(Arc::new(FixedGenerator(1)))
+            .with(GeneratorKind::Snowflake, Arc::new(FixedGenerator(2)));
+        let counter = PrefixRule::default();
+        let snowflake = PrefixRule { generator: GeneratorKind::Snowflake, ..Default::default() };
+        assert_eq!(generator.generate("KEY", &counter).await.unwrap(), 1);
+        assert_eq!(generator.generate("KEY", &snowflake).await.unwrap(), 2);
+        assert_eq!(generator.generate_range("KEY", &snowflake, 3).await.unwrap().start, 2);
+    }
+
+    #[tokio::test]
+    async fn test_unconfigured_generator() {
+        let generator = RoutingSequenceGenerator::new(Arc::new(FixedGenerator(1)));
+        let snowflake = PrefixRule { generator: GeneratorKind::Snowflake, ..Default::default() };
+        assert!(matches!(generator.generate("KEY", &snowflake).await, Err(SequenceGeneratorError::Other(_))));
+    }
+}
@@ -0,0 +1,521 @@
+//! Local segment (hi/lo) allocation on top of another sequence generator.
+//!
+//! Instead of one round trip per number, `SegmentSequenceGenerator` reserves a block of
+//! values per counter with a single `generate_range` call (one `INCRBY` for Redis) and
+//! hands them out from memory. Each counter is double-buffered, as in Meituan's Leaf:
+//! once the current block runs below the prefetch threshold, the next one is fetched in
+//! the background, and the caller that exhausts the current block just switches over.
+//! If the store is unreachable the buffered values keep being served; only a counter
+//! with nothing left surfaces the error.
+//!
+//! Block sizes adapt to each counter's throughput. A block used up within the target
+//! duration doubles the next one (up to the maximum), one that lasted more than twice as
+//! long halves it (down to the configured size), so hot prefixes refill rarely and cold
+//! ones do not burn many values on restart.
+//!
+//! The trade-offs are the usual ones for hi/lo: values still unused when the process
+//! stops are skipped for good, and nodes sharing a counter issue interleaved blocks rather
+//! than a single increasing series. Rule changes apply from the next block onwards, except
+//! a changed `step`, which drops the blocks reserved under the old one.
+//!
+//! Counters are forgotten once they are of no more use: those of a reset period that is
+//! over (their buffered values could never be issued) and those that are used up and
+//! have been idle for a while (`with_idle_timeout`), so a long-running node does not
+//! keep one entry for every period and scope it ever served.
+
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex as StdMutex;
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, Notify};
+
+use crate::prefix_rule::PrefixRule;
+use crate::sequence_generator::{SequenceGenerator, SequenceGeneratorError, SequenceRange};
+
+/// Values reserved per block when no size is configured.
+pub const DEFAULT_SEGMENT_SIZE: u64 = 1000;
+
+/// Largest block a busy counter grows to unless configured otherwise.
+pub const DEFAULT_MAX_SEGMENT_SIZE: u64 = 10_000;
+
+/// How long a block should last; faster consumption grows the next one.
+pub const DEFAULT_SEGMENT_DURATION: Duration = Duration::from_secs(15 * 60);
+
+/// Percentage of a block left when the next one is prefetched.
+pub const DEFAULT_PREFETCH_PERCENT: u64 = 20;
+
+/// How long a used-up counter is kept after its last value was handed out.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// Longest time between two looks for counters to forget.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Both buffers of one counter.
+#[derive(Debug, Default)]
+struct Segment {
+    current: Option<SequenceRange>,
+    /// Index of the next value in `current`.
+    next_index: u64,
+    /// Prefetched block, switched to once `current` is used up.
+    next: Option<SequenceRange>,
+    /// Whether a fetch for this counter is in flight.
+    loading: bool,
+    /// Size of the next block requested.
+    size: u64,
+    last_fetch: Option<Instant>,
+    last_used: Option<Instant>,
+    /// Rule the counter was first used with, to tell when its reset period is over.
+    rule: Option<PrefixRule>,
+}
+
+impl Segment {
+    fn remaining(&self) -> u64 {
+        self.current.map_or(0, |block| block.count - self.next_index)
+    }
+
+    /// Whether the counter can be forgotten at `now`: its reset period is over, or it is
+    /// used up and was last used at least `idle_timeout` ago. Never while a fetch is in
+    /// flight, which would store its block in a new entry.
+    fn is_stale(&self, sequence_key: &str, now: Instant, idle_timeout: Duration) -> bool {
+        if self.loading {
+            return false;
+        }
+        let period_over = self.rule.as_ref().is_some_and(|rule| rule.period_is_over(sequence_key, chrono::Utc::now()));
+        let idle = self.last_used.is_none_or(|last_used| now.duration_since(last_used) >= idle_timeout);
+        period_over || (idle && self.remaining() == 0 && self.next.is_none())
+    }
+
+    fn take(&mut self, step: u64) -> Option<u64> {
+        if self.current.is_some_and(|block| block.step != step) {
+            self.current = None;
+            self.next = None;
+        }
+        if self.remaining() == 0 {
+            self.current = self.next.take();
+            self.next_index = 0;
+        }
+        let block = self.current.filter(|block| self.next_index < block.count)?;
+        self.next_index += 1;
+        Some(block.value_at(self.next_index - 1))
+    }
+}
+
+/// What a caller does after looking at a counter's buffers.
+enum Next {
+    Value { value: u64, prefetch: Option<u64> },
+    Load(u64),
+    Wait,
+}
+
+pub struct SegmentSequenceGenerator<G> {
+    inner: Arc<G>,
+    min_segment_size: u64,
+    max_segment_size: u64,
+    segment_duration: Duration,
+    prefetch_percent: u64,
+    idle_timeout: Duration,
+    segments: Arc<Mutex<HashMap<String, Segment>>>,
+    last_eviction: StdMutex<Instant>,
+    /// Signalled whenever a fetch finishes, successfully or not.
+    loaded: Arc<Notify>,
+}
+
+impl<G> SegmentSequenceGenerator<G>
+where
+    G: SequenceGenerator + Send + Sync + 'static,
+{
+    pub fn new(inner: G, segment_size: u64) -> Self {
+        let segment_size = segment_size.max(1);
+        SegmentSequenceGenerator {
+            inner: Arc::new(inner),
+            min_segment_size: segment_size,
+            max_segment_size: DEFAULT_MAX_SEGMENT_SIZE.max(segment_size),
+            segment_duration: DEFAULT_SEGMENT_DURATION,
+            prefetch_percent: DEFAULT_PREFETCH_PERCENT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            segments: Arc::new(Mutex::new(HashMap::new())),
+            last_eviction: StdMutex::new(Instant::now()),
+            loaded: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Caps how large a busy counter's blocks grow; the configured size disables growth.
+    pub fn with_max_segment_size(mut self, max_segment_size: u64) -> Self {
+        self.max_segment_size = max_segment_size.max(self.min_segment_size);
+        self
+    }
+
+    pub fn with_segment_duration(mut self, segment_duration: Duration) -> Self {
+        self.segment_duration = segment_duration;
+        self
+    }
+
+    pub fn with_prefetch_percent(mut self, prefetch_percent: u64) -> Self {
+        self.prefetch_percent = prefetch_percent.min(100);
+        self
+    }
+
+    /// How long a used-up counter is kept after its last value before it is forgotten.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Forgets stale counters other than `in_use`, looking at most once per idle timeout
+    /// (and at least once per `EVICTION_INTERVAL`) so busy callers do not scan the map
+    /// every time.
+    fn evict_stale(&self, segments: &mut HashMap<String, Segment>, in_use: &str) {
+        let now = Instant::now();
+        {
+            let mut last_eviction = self.last_eviction.lock().unwrap_or_else(|e| e.into_inner());
+            if now.duration_since(*last_eviction) < self.idle_timeout.min(EVICTION_INTERVAL) {
+                return;
+            }
+            *last_eviction = now;
+        }
+        segments.retain(|sequence_key, segment| {
+            sequence_key == in_use || !segment.is_stale(sequence_key, now, self.idle_timeout)
+        });
+    }
+
+    /// Marks the counter as loading and picks the size of the block to request, growing
+    /// or shrinking it by how long the previous block lasted. The request never exceeds
+    /// what the rule's sequence width has left, so a block near the end of a narrow
+    /// format does not reserve values that could never be issued.
+    fn start_load(&self, segment: &mut Segment, prefix_rule: &PrefixRule) -> u64 {
+        let now = Instant::now();
+        let size = match segment.last_fetch.map(|last| now - last) {
+            None => self.min_segment_size,
+            Some(elapsed) if elapsed < self.segment_duration => segment.size.saturating_mul(2),
+            Some(elapsed) if elapsed < self.segment_duration * 2 => segment.size,
+            Some(_) => segment.size / 2,
+        };
+        segment.size = size.clamp(self.min_segment_size, self.max_segment_size);
+        segment.last_fetch = Some(now);
+        segment.loading = true;
+        let last = segment.next.or(segment.current).map(|block| block.end());
+        // At least one value, so a counter past the width still reports its overflow.
+        prefix_rule.remaining_capacity(last).map_or(segment.size, |remaining| segment.size.min(remaining.max(1)))
+    }
+
+    fn next(&self, segment: &mut Segment, prefix_rule: &PrefixRule) -> Next {
+        if let Some(value) = segment.take(prefix_rule.step()) {
+            let threshold = segment.current.map_or(0, |block| block.count * self.prefetch_percent / 100);
+            let prefetch = (segment.next.is_none() && !segment.loading && segment.remaining() <= threshold)
+                .then(|| self.start_load(segment, prefix_rule));
+            return Next::Value { value, prefetch };
+        }
+        if segment.loading {
+            Next::Wait
+        } else {
+            Next::Load(self.start_load(segment, prefix_rule))
+        }
+    }
+
+    /// Fetches a block and stores it in whichever buffer is free.
+    async fn load(
+        inner: &G,
+        segments: &Mutex<HashMap<String, Segment>>,
+        loaded: &Notify,
+        sequence_key: &str,
+        prefix_rule: &PrefixRule,
+        size: u64,
+    ) -> Result<(), SequenceGeneratorError> {
+        let block = inner.generate_range(sequence_key, prefix_rule, size).await;
+        {
+            let mut segments = segments.lock().await;
+            let segment = segments.entry(sequence_key.to_string()).or_default();
+            segment.loading = false;
+            if let Ok(block) = block {
+                if segment.remaining() == 0 {
+                    segment.current = Some(block);
+                    segment.next_index = 0;
+                } else {
+                    segment.next = Some(block);
+                }
+            }
+        }
+        loaded.notify_waiters();
+        block.map(|_| ())
+    }
+
+    /// Prefetches the next block without holding up the caller. A failed prefetch is
+    /// retried by the next call that finds the counter running low.
+    fn spawn_prefetch(&self, sequence_key: &str, prefix_rule: &PrefixRule, size: u64) {
+        let inner = self.inner.clone();
+        let segments = self.segments.clone();
+        let loaded = self.loaded.clone();
+        let sequence_key = sequence_key.to_string();
+        let prefix_rule = prefix_rule.clone();
+        tokio::spawn(async move {
+            if let Err(e) = Self::load(&inner, &segments, &loaded, &sequence_key, &prefix_rule, size).await {
+                eprintln!("Error prefetching segment for {}: {}", sequence_key, e);
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl<G> SequenceGenerator for SegmentSequenceGenerator<G>
+where
+    G: SequenceGenerator + Send + Sync + 'static,
+{
+    async fn generate(&self, sequence_key: &str, prefix_rule: &PrefixRule) -> Result<u64, SequenceGeneratorError> {
+        // Released values live in the store's free list, which blocks would bypass.
+        if prefix_rule.recycle {
+            return self.inner.generate(sequence_key, prefix_rule).await;
+        }
+        loop {
+            // Registered before the lock is released, so a fetch finishing in between
+            // still wakes this caller.
+            let loaded = self.loaded.notified();
+            let next = {
+                let mut segments = self.segments.lock().await;
+                self.evict_stale(&mut segments, sequence_key);
+                let segment = segments.entry(sequence_key.to_string()).or_default();
+                segment.rule.get_or_insert_with(|| prefix_rule.clone());
+                segment.last_used = Some(Instant::now());
+                self.next(segment, prefix_rule)
+            };
+            match next {
+                Next::Value { value, prefetch } => {
+                    if let Some(size) = prefetch {
+                        self.spawn_prefetch(sequence_key, prefix_rule, size);
+                    }
+                    return Ok(value);
+                }
+                // Both buffers are empty: this caller has to wait for a block.
+                Next::Load(size) => {
+                    Self::load(&self.inner, &self.segments, &self.loaded, sequence_key, prefix_rule, size).await?;
+                }
+                Next::Wait => loaded.await,
+            }
+        }
+    }
+
+    /// Batches must be contiguous, which values pieced together from blocks are not, so
+    /// they are reserved from the inner generator directly.
+    async fn generate_range(
+        &self,
+        sequence_key: &str,
+        prefix_rule: &PrefixRule,
+        count: u64,
+    ) -> Result<SequenceRange, SequenceGeneratorError> {
+        self.inner.generate_range(sequence_key, prefix_rule, count).await
+    }
+
+    async fn release(
+        &self,
+        sequence_key: &str,
+        prefix_rule: &PrefixRule,
+        sequence: u64,
+    ) -> Result<bool, SequenceGeneratorError> {
+        self.inner.release(sequence_key, prefix_rule, sequence).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prefix_rule::ResetPolicy;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+    /// Single in-process counter that can be switched off to simulate an outage.
+    #[derive(Default)]
+    struct CountingGenerator {
+        counter: AtomicU64,
+        calls: AtomicU64,
+        down: AtomicBool,
+    }
+
+    #[async_trait]
+    impl SequenceGenerator for CountingGenerator {
+        async fn generate(&self, sequence_key: &str, prefix_rule: &PrefixRule) -> Result<u64, SequenceGeneratorError> {
+            Ok(self.generate_range(sequence_key, prefix_rule, 1).await?.start)
+        }
+
+        async fn generate_range(
+            &self,
+            _sequence_key: &str,
+            prefix_rule: &PrefixRule,
+            count: u64,
+        ) -> Result<SequenceRange, SequenceGeneratorError> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(SequenceGeneratorError::Other("store unavailable".to_string()));
+            }
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let step = prefix_rule.step();
+            let start = self.counter.fetch_add(step * count, Ordering::SeqCst) + step;
+            Ok(SequenceRange { start, count, step })
+        }
+    }
+
+    /// Generator whose block size stays fixed, so values are predictable.
+    fn fixed(segment_size: u64) -> SegmentSequenceGenerator<CountingGenerator> {
+        SegmentSequenceGenerator::new(CountingGenerator::default(), segment_size).with_max_segment_size(segment_size)
+    }
+
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_serves_blocks_from_memory() {
+        let generator = fixed(10);
+        let rule = PrefixRule::default();
+        let mut values = Vec::new();
+        for _ in 0..25 {
+            values.push(generator.generate("SEG", &rule).await.unwrap());
+            settle().await;
+        }
+        assert_eq!(values, (1..=25).collect::<Vec<_>>());
+        assert_eq!(generator.inner.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_next_block_is_ready_before_current_runs_out() {
+        let generator = fixed(10);
+        let rule = PrefixRule::default();
+        for _ in 0..8 {
+            generator.generate("SEG", &rule).await.unwrap();
+        }
+        settle().await;
+        let segments = generator.segments.lock().await;
+        assert_eq!(segments["SEG"].next, Some(SequenceRange { start: 11, count: 10, step: 1 }));
+    }
+
+    #[tokio::test]
+    async fn test_keeps_serving_while_store_is_down() {
+        let generator = fixed(10);
+        let rule = PrefixRule::default();
+        generator.generate("SEG", &rule).await.unwrap();
+        generator.inner.down.store(true, Ordering::SeqCst);
+
+        for expected in 2..=10 {
+            assert_eq!(generator.generate("SEG", &rule).await.unwrap(), expected);
+            settle().await;
+        }
+        assert!(generator.generate("SEG", &rule).await.is_err());
+
+        generator.inner.down.store(false, Ordering::SeqCst);
+        assert_eq!(generator.generate("SEG", &rule).await.unwrap(), 11);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_callers_get_distinct_values() {
+        let generator = Arc::new(fixed(7));
+        let rule = PrefixRule::default();
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let generator = generator.clone();
+                let rule = rule.clone();
+                tokio::spawn(async move {
+                    let mut values = Vec::new();
+                    for _ in 0..50 {
+                        values.push(generator.generate("SEG", &rule).await.unwrap());
+                    }
+                    values
+                })
+            })
+            .collect();
+        let mut seen = HashSet::new();
+        for task in tasks {
+            for value in task.await.unwrap() {
+                assert!(seen.insert(value), "duplicate value {}", value);
+            }
+        }
+        assert_eq!(seen.len(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_hot_counter_grows_its_blocks() {
+        let generator = SegmentSequenceGenerator::new(CountingGenerator::default(), 10)
+            .with_max_segment_size(40)
+            .with_segment_duration(Duration::from_secs(3600));
+        let rule = PrefixRule::default();
+        for _ in 0..100 {
+            generator.generate("SEG", &rule).await.unwrap();
+            settle().await;
+        }
+        let segments = generator.segments.lock().await;
+        assert_eq!(segments["SEG"].size, 40);
+    }
+
+    #[tokio::test]
+    async fn test_cold_counter_keeps_the_configured_size() {
+        let generator = SegmentSequenceGenerator::new(CountingGenerator::default(), 10)
+            .with_segment_duration(Duration::ZERO);
+        let rule = PrefixRule::default();
+        for _ in 0..30 {
+            generator.generate("SEG", &rule).await.unwrap();
+            settle().await;
+        }
+        let segments = generator.segments.lock().await;
+        assert_eq!(segments["SEG"].size, 10);
+    }
+
+    #[tokio::test]
+    async fn test_blocks_stop_at_the_sequence_width() {
+        let generator = fixed(40);
+        let rule = PrefixRule { format: "{SEQ:2}".to_string(), ..Default::default() };
+        let mut values = Vec::new();
+        for _ in 0..100 {
+            values.push(generator.generate("SEG", &rule).await.unwrap());
+            settle().await;
+        }
+        assert_eq!(values, (1..=100).collect::<Vec<_>>());
+        // 40 + 40 + 19 values fit; past the width only single values are reserved (100
+        // and the prefetched 101), for the caller to report the overflow.
+        assert_eq!(generator.inner.counter.load(Ordering::SeqCst), 101);
+    }
+
+    #[tokio::test]
+    async fn test_used_up_idle_counters_are_forgotten() {
+        let generator = fixed(1).with_idle_timeout(Duration::ZERO);
+        let rule = PrefixRule::default();
+        generator.generate("SEG", &rule).await.unwrap();
+        // The prefetch fails, leaving the counter used up.
+        generator.inner.down.store(true, Ordering::SeqCst);
+        settle().await;
+        generator.inner.down.store(false, Ordering::SeqCst);
+
+        generator.generate("OTHER", &rule).await.unwrap();
+        let segments = generator.segments.lock().await;
+        assert!(!segments.contains_key("SEG"));
+        assert!(segments.contains_key("OTHER"));
+    }
+
+    #[tokio::test]
+    async fn test_counters_of_past_periods_are_forgotten() {
+        let generator = fixed(10).with_idle_timeout(Duration::ZERO);
+        let rule = PrefixRule { reset_policy: ResetPolicy::Yearly, ..Default::default() };
+        generator.generate("SEG:2000", &rule).await.unwrap();
+        settle().await;
+
+        let this_year = format!("SEG:{}", chrono::Utc::now().format("%Y"));
+        generator.generate(&this_year, &rule).await.unwrap();
+        settle().await;
+        generator.generate(&this_year, &rule).await.unwrap();
+        let segments = generator.segments.lock().await;
+        // Gone although it still held values: nothing asks for them any more.
+        assert!(!segments.contains_key("SEG:2000"));
+        assert!(segments.contains_key(&this_year));
+    }
+
+    #[tokio::test]
+    async fn test_changed_step_drops_old_blocks() {
+        let generator = fixed(10);
+        let rule = PrefixRule::default();
+        assert_eq!(generator.generate("SEG", &rule).await.unwrap(), 1);
+        settle().await;
+
+        let rule = PrefixRule { step: 5, ..Default::default() };
+        let first = generator.generate("SEG", &rule).await.unwrap();
+        let second = generator.generate("SEG", &rule).await.unwrap();
+        assert_eq!(second - first, 5);
+    }
+}
@@ -0,0 +1,170 @@
+//! Alphabets the sequence placeholder can be rendered in.
+//!
+//! `{SEQ:N}` is plain decimal. `{SEQ36:N}`, `{SEQ32C:N}` and `{SEQX:N}` render the same
+//! counter in base 36, Crockford base 32 and hexadecimal, which keeps short human-facing
+//! codes (coupons, pickup codes) compact. All encodings are zero-padded to the width.
+
+const BASE36_ALPHABET: &[u8; 36] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// Crockford's base 32 drops `I`, `L`, `O` and `U` so codes survive being read aloud.
+const CROCKFORD_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+const HEX_ALPHABET: &[u8; 16] = b"0123456789ABCDEF";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SequenceEncoding {
+    #[default]
+    Decimal,
+    Base36,
+    Crockford32,
+    Hex,
+}
+
+impl SequenceEncoding {
+    /// Maps a placeholder name (`SEQ`, `SEQ36`, `SEQ32C`, `SEQX`) to its encoding.
+    pub fn from_placeholder_name(name: &str) -> Option<Self> {
+        let encoding = match name {
+            "SEQ" => SequenceEncoding::Decimal,
+            "SEQ36" => SequenceEncoding::Base36,
+            "SEQ32C" => SequenceEncoding::Crockford32,
+            "SEQX" => SequenceEncoding::Hex,
+            _ => return None,
+        };
+        Some(encoding)
+    }
+
+    pub fn base(self) -> u64 {
+        self.alphabet().len() as u64
+    }
+
+    fn alphabet(self) -> &'static [u8] {
+        match self {
+            SequenceEncoding::Decimal => &BASE36_ALPHABET[..10],
+            SequenceEncoding::Base36 => BASE36_ALPHABET,
+            SequenceEncoding::Crockford32 => CROCKFORD_ALPHABET,
+            SequenceEncoding::Hex => HEX_ALPHABET,
+        }
+    }
+
+    /// Number of distinct values `width` digits can hold, or `None` when that exceeds
+    /// `u64` (every counter value fits).
+    pub fn capacity(self, width: u32) -> Option<u64> {
+        self.base().checked_pow(width)
+    }
+
+    /// Regex character class matching one digit of this encoding, in the given case.
+    pub fn digit_class(self, lowercase: bool) -> &'static str {
+        match (self, lowercase) {
+            (SequenceEncoding::Decimal, _) => "[0-9]",
+            (SequenceEncoding::Base36, false) => "[0-9A-Z]",
+            (SequenceEncoding::Base36, true) => "[0-9a-z]",
+            (SequenceEncoding::Crockford32, false) => "[0-9A-HJKMNP-TV-Z]",
+            (SequenceEncoding::Crockford32, true) => "[0-9a-hjkmnp-tv-z]",
+            (SequenceEncoding::Hex, false) => "[0-9A-F]",
+            (SequenceEncoding::Hex, true) => "[0-9a-f]",
+        }
+    }
+
+    /// Appends `value` zero-padded to `width` digits.
+    pub fn write(self, value: u64, width: usize, lowercase: bool, out: &mut String) {
+        let alphabet = self.alphabet();
+        let base = self.base();
+        // u64::MAX needs 64 binary digits at most; no supported base is smaller than 10.
+        let mut digits = [0u8; 20];
+        let mut len = 0;
+        let mut rest = value;
+        loop {
+            digits[len] = alphabet[(rest % base) as usize];
+            len += 1;
+            rest /= base;
+            if rest == 0 {
+                break;
+            }
+        }
+        for _ in len..width {
+            out.push('0');
+        }
+        for &digit in digits[..len].iter().rev() {
+            out.push(if lowercase { digit.to_ascii_lowercase() } else { digit } as char);
+        }
+    }
+
+    /// Reads a rendered value back. Case-insensitive; Crockford's look-alikes (`O` for
+    /// `0`, `I`/`L` for `1`) are accepted as the alphabet intends.
+    pub fn decode(self, text: &str) -> Option<u64> {
+        if text.is_empty() {
+            return None;
+        }
+        text.chars().try_fold(0u64, |value, c| {
+            let c = match (self, c.to_ascii_uppercase()) {
+                (SequenceEncoding::Crockford32, 'O') => '0',
+                (SequenceEncoding::Crockford32, 'I' | 'L') => '1',
+                (_, c) => c,
+            };
+            let digit = self.alphabet().iter().position(|&d| d as char == c)? as u64;
+            value.checked_mul(self.base())?.checked_add(digit)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(encoding: SequenceEncoding, value: u64, width: usize, lowercase: bool) -> String {
+        let mut out = String::new();
+        encoding.write(value, width, lowercase, &mut out);
+        out
+    }
+
+    #[test]
+    fn test_decimal() {
+        assert_eq!(encode(SequenceEncoding::Decimal, 42, 6, false), "000042");
+        assert_eq!(encode(SequenceEncoding::Decimal, u64::MAX, 1, false), "18446744073709551615");
+    }
+
+    #[test]
+    fn test_base36() {
+        assert_eq!(encode(SequenceEncoding::Base36, 35, 3, false), "00Z");
+        assert_eq!(encode(SequenceEncoding::Base36, 36 * 36, 3, false), "100");
+        assert_eq!(encode(SequenceEncoding::Base36, 1_295, 6, true), "0000zz");
+    }
+
+    #[test]
+    fn test_crockford32_skips_ambiguous_letters() {
+        assert_eq!(encode(SequenceEncoding::Crockford32, 18, 2, false), "0J");
+        assert_eq!(encode(SequenceEncoding::Crockford32, 31, 2, false), "0Z");
+        let all: String = (0..32).map(|v| encode(SequenceEncoding::Crockford32, v, 1, false)).collect();
+        assert!(!all.contains(['I', 'L', 'O', 'U']));
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(encode(SequenceEncoding::Hex, 255, 8, false), "000000FF");
+        assert_eq!(encode(SequenceEncoding::Hex, u64::MAX, 16, true), "ffffffffffffffff");
+    }
+
+    #[test]
+    fn test_capacity() {
+        assert_eq!(SequenceEncoding::Decimal.capacity(4), Some(10_000));
+        assert_eq!(SequenceEncoding::Hex.capacity(2), Some(256));
+        assert_eq!(SequenceEncoding::Hex.capacity(16), None);
+    }
+
+    #[test]
+    fn test_decode_round_trip() {
+        for encoding in [SequenceEncoding::Decimal, SequenceEncoding::Base36, SequenceEncoding::Crockford32, SequenceEncoding::Hex] {
+            for value in [0, 1, 35, 1_000_000, u64::MAX] {
+                let rendered = encode(encoding, value, 8, true);
+                assert_eq!(encoding.decode(&rendered), Some(value));
+            }
+        }
+    }
+
+    #[test]
+    fn test_decode_crockford_look_alikes() {
+        assert_eq!(SequenceEncoding::Crockford32.decode("1O"), SequenceEncoding::Crockford32.decode("10"));
+        assert_eq!(SequenceEncoding::Crockford32.decode("IL"), SequenceEncoding::Crockford32.decode("11"));
+        assert_eq!(SequenceEncoding::Crockford32.decode("U"), None);
+    }
+}
@@ -1,63 +1,455 @@
 use async_trait::async_trait;
-use redis::{Client, RedisError, AsyncCommands};
-use std::{sync::Arc, fmt,sync::Mutex};
+use redis::{ErrorKind, RedisError};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
 
-use crate::prefix_rule_manager::PrefixRuleManager;
+use crate::prefix_rule::PrefixRule;
+use crate::redis_storage::RedisStorage;
+use crate::storage_backend::{CounterStep, MemoryStorage, StorageBackend, StorageError};
+
+/// Largest value a counter holds in any store; Redis `INCR` past it fails instead of
+/// wrapping, and the other stores follow suit.
+pub const REDIS_COUNTER_MAX: u64 = i64::MAX as u64;
 
 #[async_trait]
 pub trait SequenceGenerator {
-    async fn generate(&self, prefix_key: &str) -> Result<u64, SequenceGeneratorError>;
+    /// Returns the next value of the counter `sequence_key`: the prefix key, extended by
+    /// the rule's sequence scope and reset period (see `PrefixRule::sequence_key`). A
+    /// counter that does not exist yet, or is still below the rule's `initial_seq`, issues
+    /// `initial_seq` next.
+    async fn generate(&self, sequence_key: &str, prefix_rule: &PrefixRule) -> Result<u64, SequenceGeneratorError>;
+
+    /// Reserves `count` consecutive values of the counter in one operation, so a batch
+    /// is contiguous even while other callers generate from the same counter.
+    async fn generate_range(
+        &self,
+        sequence_key: &str,
+        prefix_rule: &PrefixRule,
+        count: u64,
+    ) -> Result<SequenceRange, SequenceGeneratorError>;
+
+    /// Takes `count` values like `generate_range`, but leaves them claimed rather than
+    /// issued: the caller commits the claim once the numbers are assembled, and a claim
+    /// dropped before then gives its values back. Only gap-free generators hold a claim
+    /// open; the others return one that is committed already.
+    async fn claim_range(
+        &self,
+        sequence_key: &str,
+        prefix_rule: &PrefixRule,
+        count: u64,
+    ) -> Result<Claim, SequenceGeneratorError> {
+        Ok(Claim::committed(self.generate_range(sequence_key, prefix_rule, count).await?))
+    }
+
+    /// Returns an issued but unused value to the counter's free list, for rules that
+    /// `recycle`: the next `generate` hands out the smallest free value before counting
+    /// on. Returns whether the value was added (`false` if it was already free).
+    ///
+    /// The generator only knows the value was counted, not whether the number is still in
+    /// use: callers check that first (the HTTP endpoint asks the number ledger), or a
+    /// value could be handed out twice.
+    async fn release(
+        &self,
+        _sequence_key: &str,
+        _prefix_rule: &PrefixRule,
+        _sequence: u64,
+    ) -> Result<bool, SequenceGeneratorError> {
+        Err(SequenceGeneratorError::Other("This generator cannot recycle numbers".to_string()))
+    }
+}
+
+/// `count` values starting at `start`, `step` apart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SequenceRange {
+    pub start: u64,
+    pub count: u64,
+    pub step: u64,
+}
+
+impl SequenceRange {
+    pub fn values(&self) -> impl Iterator<Item = u64> {
+        let SequenceRange { start, count, step } = *self;
+        (0..count).map(move |i| start + i * step)
+    }
+
+    /// The value at `index` (below `count`).
+    pub fn value_at(&self, index: u64) -> u64 {
+        self.start + index * self.step
+    }
+
+    /// The last value of the range; `start` for an empty one.
+    pub fn end(&self) -> u64 {
+        self.start + self.count.saturating_sub(1) * self.step
+    }
+}
+
+/// Values taken by `SequenceGenerator::claim_range`, kept from other callers until the
+/// claim is committed or dropped.
+pub struct Claim {
+    pub range: SequenceRange,
+    pending: Option<Box<dyn PendingClaim>>,
+}
+
+/// The open half of a claim that has not been committed yet; dropping it without
+/// committing gives the claimed values back to the counter.
+#[async_trait]
+pub trait PendingClaim: Send {
+    async fn commit(self: Box<Self>) -> Result<(), SequenceGeneratorError>;
+}
+
+impl Claim {
+    pub fn committed(range: SequenceRange) -> Self {
+        Claim { range, pending: None }
+    }
+
+    pub fn pending(range: SequenceRange, pending: Box<dyn PendingClaim>) -> Self {
+        Claim { range, pending: Some(pending) }
+    }
+
+    /// Whether dropping the claim would still give its values back.
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Makes the claimed values issued for good.
+    pub async fn commit(self) -> Result<SequenceRange, SequenceGeneratorError> {
+        if let Some(pending) = self.pending {
+            pending.commit().await?;
+        }
+        Ok(self.range)
+    }
 }
 
 #[derive(Debug)]
 pub enum SequenceGeneratorError {
     RedisError(RedisError),
+    DatabaseError(sqlx::Error),
+    StorageError(StorageError),
     PrefixNotFound,
+    /// The counter ran past `limit`, either the largest value the sequence placeholder's
+    /// width can render or the largest value the store can count to.
+    Overflow { limit: u64 },
+    /// A value released for recycling was never issued by the counter.
+    NotIssued { sequence: u64 },
+    /// The wall clock is `millis` behind values already issued by a time-based generator.
+    ClockMovedBackwards { millis: u64 },
     Other(String),
 }
 
 impl From<RedisError> for SequenceGeneratorError {
     fn from(err: RedisError) -> Self {
+        // Redis reports `ERR increment or decrement would overflow` past i64::MAX.
+        if err.kind() == ErrorKind::ResponseError && err.detail().is_some_and(|d| d.contains("would overflow")) {
+            return SequenceGeneratorError::Overflow { limit: REDIS_COUNTER_MAX };
+        }
         SequenceGeneratorError::RedisError(err)
     }
 }
 
+impl From<sqlx::Error> for SequenceGeneratorError {
+    fn from(err: sqlx::Error) -> Self {
+        SequenceGeneratorError::DatabaseError(err)
+    }
+}
+
+impl From<StorageError> for SequenceGeneratorError {
+    fn from(err: StorageError) -> Self {
+        match err {
+            StorageError::Redis(err) => err.into(),
+            StorageError::Overflow { limit } => SequenceGeneratorError::Overflow { limit },
+            err => SequenceGeneratorError::StorageError(err),
+        }
+    }
+}
+
 impl fmt::Display for SequenceGeneratorError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             SequenceGeneratorError::RedisError(e) => write!(f, "Redis error: {}", e),
+            SequenceGeneratorError::DatabaseError(e) => write!(f, "Database error: {}", e),
+            SequenceGeneratorError::StorageError(e) => write!(f, "Storage error: {}", e),
             SequenceGeneratorError::PrefixNotFound => write!(f, "Prefix not found"),
+            SequenceGeneratorError::Overflow { limit } => {
+                write!(f, "Sequence overflow: the counter is past its maximum of {}", limit)
+            }
+            SequenceGeneratorError::NotIssued { sequence } => {
+                write!(f, "Sequence {} has not been issued and cannot be released", sequence)
+            }
+            SequenceGeneratorError::ClockMovedBackwards { millis } => {
+                write!(f, "Clock moved backwards by {} ms; refusing to issue duplicate values", millis)
+            }
             SequenceGeneratorError::Other(msg) => write!(f, "{}", msg),
         }
     }
 }
 
-pub struct RedisSequenceGenerator {
-    redis_client: Client,
-    // prefix_rule_manager: Arc<Mutex<dyn PrefixRuleManager + Send + Sync>>, // Not used in this implementation
+/// Reserves `count` values after a counter whose last issued value is `current`, with
+/// the same rules every `StorageBackend` applies, for generators that update the counter
+/// themselves. The counter's new value is the range's `end()`.
+pub fn next_range(current: Option<u64>, prefix_rule: &PrefixRule, count: u64) -> Result<SequenceRange, SequenceGeneratorError> {
+    let counter_step = CounterStep::for_rule(prefix_rule, count)?;
+    let current = current.map(|current| i64::try_from(current).unwrap_or(i64::MAX));
+    Ok(counter_step.range(counter_step.apply(current)?))
+}
+
+/// Counters kept in a `StorageBackend`: `seq:{sequence_key}` holds the last value issued
+/// and, for recycling rules, `seq_free:{sequence_key}` the values released back to it.
+pub struct StorageSequenceGenerator<S: ?Sized> {
+    storage: Arc<S>,
 }
 
+impl<S: StorageBackend + ?Sized> StorageSequenceGenerator<S> {
+    pub fn with_storage(storage: Arc<S>) -> Self {
+        StorageSequenceGenerator { storage }
+    }
+}
+
+pub type RedisSequenceGenerator = StorageSequenceGenerator<RedisStorage>;
+
 impl RedisSequenceGenerator {
     pub fn new(redis_url: String) -> Result<Self, SequenceGeneratorError> {
-        let redis_client = Client::open(redis_url).map_err(|e| SequenceGeneratorError::Other(format!("Failed to connect to Redis: {}", e)))?;
-        Ok(RedisSequenceGenerator {
-            redis_client,
-            // prefix_rule_manager,
-        })
+        let storage = RedisStorage::new(redis_url).map_err(|e| SequenceGeneratorError::Other(format!("Failed to connect to Redis: {}", e)))?;
+        Ok(Self::with_storage(Arc::new(storage)))
     }
 }
 
+/// Counters in this process, with the same semantics as the shared stores; see
+/// `MemoryStorage`.
+pub type MemorySequenceGenerator = StorageSequenceGenerator<MemoryStorage>;
+
+impl MemorySequenceGenerator {
+    pub fn new() -> Self {
+        Self::with_storage(Arc::new(MemoryStorage::new()))
+    }
+}
+
+impl Default for MemorySequenceGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 #[async_trait]
-impl SequenceGenerator for RedisSequenceGenerator {
-    async fn generate(&self, prefix_key: &str) -> Result<u64, SequenceGeneratorError> {
+impl<S: StorageBackend + ?Sized> SequenceGenerator for StorageSequenceGenerator<S> {
+    async fn generate(&self, sequence_key: &str, prefix_rule: &PrefixRule) -> Result<u64, SequenceGeneratorError> {
+        Ok(self.generate_range(sequence_key, prefix_rule, 1).await?.start)
+    }
+
+    async fn generate_range(
+        &self,
+        sequence_key: &str,
+        prefix_rule: &PrefixRule,
+        count: u64,
+    ) -> Result<SequenceRange, SequenceGeneratorError> {
         // Concurrency Control Strategy:
-        // This implementation uses Redis atomic INCR operation for concurrency control.
-        // Redis INCR provides atomic increment, ensuring that sequence numbers are generated
-        // uniquely and continuously even under high concurrency. This strategy prioritizes
-        // low latency and high throughput, but allows for slight number skipping in case of Redis failures.
-        let mut conn = self.redis_client.get_async_connection().await?;
-        let next_sequence: u64 = conn.incr(format!("seq:{}", prefix_key), 1).await?;
-        Ok(next_sequence)
+        // Each reservation is a single atomic counter move in the store (an INCRBY in
+        // Redis), ensuring that sequence numbers are generated uniquely and continuously
+        // even under high concurrency. This strategy prioritizes low latency and high
+        // throughput, but allows for slight number skipping in case of store failures.
+        // A range is reserved with a single move of `step * count`.
+        let counter_step = CounterStep::for_rule(prefix_rule, count)?;
+        // Free values are handed out one at a time; a range has to be contiguous.
+        if prefix_rule.recycle && count == 1 {
+            if let Some(free) = self.storage.pop_min(&format!("seq_free:{}", sequence_key)).await? {
+                return Ok(SequenceRange { start: free, count, step: prefix_rule.step() });
+            }
+        }
+        let end = self.storage.advance_counter(&format!("seq:{}", sequence_key), &counter_step).await?;
+        Ok(counter_step.range(end))
+    }
+
+    async fn release(
+        &self,
+        sequence_key: &str,
+        prefix_rule: &PrefixRule,
+        sequence: u64,
+    ) -> Result<bool, SequenceGeneratorError> {
+        let step = prefix_rule.step();
+        if sequence < prefix_rule.first_sequence() || sequence % step != prefix_rule.offset % step {
+            return Err(SequenceGeneratorError::NotIssued { sequence });
+        }
+        // Counters only move forward, so a value at or below the counter stays issued.
+        let current = self.storage.counter(&format!("seq:{}", sequence_key)).await?;
+        if current.is_none_or(|current| i128::from(sequence) > i128::from(current)) {
+            return Err(SequenceGeneratorError::NotIssued { sequence });
+        }
+        Ok(self.storage.add_member(&format!("seq_free:{}", sequence_key), sequence).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redis_increment_overflow_maps_to_overflow() {
+        let err = RedisError::from((
+            ErrorKind::ResponseError,
+            "An error was signalled by the server",
+            "increment or decrement would overflow".to_string(),
+        ));
+        assert!(matches!(
+            SequenceGeneratorError::from(err),
+            SequenceGeneratorError::Overflow { limit: REDIS_COUNTER_MAX }
+        ));
+    }
+
+    #[test]
+    fn test_sequence_range_values() {
+        let range = SequenceRange { start: 11, count: 3, step: 10 };
+        assert_eq!(range.values().collect::<Vec<_>>(), vec![11, 21, 31]);
+        assert_eq!(range.end(), 31);
+    }
+
+    #[test]
+    fn test_next_range() {
+        let rule = PrefixRule { initial_seq: 1000, ..Default::default() };
+        assert_eq!(next_range(None, &rule, 1).unwrap(), SequenceRange { start: 1000, count: 1, step: 1 });
+        assert_eq!(next_range(Some(5), &rule, 2).unwrap(), SequenceRange { start: 1000, count: 2, step: 1 });
+        assert_eq!(next_range(Some(1001), &rule, 1).unwrap().start, 1002);
+
+        let rule = PrefixRule { initial_seq: 1, step: 2, offset: 1, ..Default::default() };
+        assert_eq!(next_range(None, &rule, 3).unwrap().values().collect::<Vec<_>>(), vec![1, 3, 5]);
+        // A counter left in another residue class is realigned first.
+        assert_eq!(next_range(Some(6), &rule, 1).unwrap().start, 7);
+
+        assert!(matches!(
+            next_range(Some(REDIS_COUNTER_MAX), &PrefixRule::default(), 1),
+            Err(SequenceGeneratorError::Overflow { .. })
+        ));
+    }
+
+    #[test]
+    fn test_other_redis_errors_are_kept() {
+        let err = RedisError::from((ErrorKind::ResponseError, "An error was signalled by the server", "WRONGTYPE".to_string()));
+        assert!(matches!(SequenceGeneratorError::from(err), SequenceGeneratorError::RedisError(_)));
+    }
+
+    // The same checks run on the in-memory generator and, with `cargo test -- --ignored`,
+    // on a Redis server at localhost:6379, so both stores are held to one set of semantics.
+
+    const REDIS_URL: &str = "redis://localhost:6379/";
+
+    fn delete_redis_keys(keys: &[&str]) {
+        let client = redis::Client::open(REDIS_URL).unwrap();
+        let mut conn = client.get_connection().unwrap();
+        let _ : () = redis::cmd("DEL").arg(keys).query(&mut conn).unwrap();
+    }
+
+    async fn next_values(generator: &(impl SequenceGenerator + Sync), key: &str, rule: &PrefixRule, count: usize) -> Vec<u64> {
+        let mut values = Vec::with_capacity(count);
+        for _ in 0..count {
+            values.push(generator.generate(key, rule).await.unwrap());
+        }
+        values
+    }
+
+    async fn check_initial_sequence_processing(generator: &(impl SequenceGenerator + Sync)) {
+        let rule = |initial_seq| PrefixRule { initial_seq, ..Default::default() };
+
+        // The first call issues initial_seq itself.
+        assert_eq!(next_values(generator, "INITIAL_TEST", &rule(1000), 3).await, vec![1000, 1001, 1002]);
+        // Re-registering with a higher initial_seq skips ahead...
+        assert_eq!(next_values(generator, "INITIAL_TEST", &rule(5000), 1).await, vec![5000]);
+        // ...while a lower one never rewinds a counter in use.
+        assert_eq!(next_values(generator, "INITIAL_TEST", &rule(1), 1).await, vec![5001]);
+    }
+
+    async fn check_step_and_offset(generator: &(impl SequenceGenerator + Sync)) {
+        let odd = PrefixRule { initial_seq: 1, step: 2, offset: 1, ..Default::default() };
+        assert_eq!(next_values(generator, "STEP_TEST", &odd, 3).await, vec![1, 3, 5]);
+
+        // Switching the counter to the even residue class never reissues a value.
+        let even = PrefixRule { offset: 0, ..odd.clone() };
+        assert_eq!(next_values(generator, "STEP_TEST", &even, 2).await, vec![6, 8]);
+
+        let tens = PrefixRule { initial_seq: 1, step: 10, ..Default::default() };
+        assert_eq!(next_values(generator, "STEP_TEN_TEST", &tens, 2).await, vec![10, 20]);
+    }
+
+    async fn check_recycled_values_are_reissued_smallest_first(generator: &(impl SequenceGenerator + Sync)) {
+        let rule = PrefixRule { initial_seq: 1, recycle: true, ..Default::default() };
+        for _ in 0..5 {
+            generator.generate("RECYCLE_TEST", &rule).await.unwrap();
+        }
+        assert!(generator.release("RECYCLE_TEST", &rule, 4).await.unwrap());
+        assert!(generator.release("RECYCLE_TEST", &rule, 2).await.unwrap());
+        assert!(!generator.release("RECYCLE_TEST", &rule, 2).await.unwrap());
+        assert!(matches!(
+            generator.release("RECYCLE_TEST", &rule, 9).await,
+            Err(SequenceGeneratorError::NotIssued { sequence: 9 })
+        ));
+
+        let mut values = Vec::new();
+        for _ in 0..3 {
+            values.push(generator.generate("RECYCLE_TEST", &rule).await.unwrap());
+        }
+        assert_eq!(values, vec![2, 4, 6]);
+    }
+
+    async fn check_generate_range_is_contiguous(generator: &(impl SequenceGenerator + Sync)) {
+        let rule = PrefixRule { initial_seq: 100, step: 2, ..Default::default() };
+        let range = generator.generate_range("RANGE_TEST", &rule, 5).await.unwrap();
+        assert_eq!(range.values().collect::<Vec<_>>(), vec![100, 102, 104, 106, 108]);
+        assert_eq!(generator.generate("RANGE_TEST", &rule).await.unwrap(), 110);
+    }
+
+    #[tokio::test]
+    async fn test_initial_sequence_processing() {
+        check_initial_sequence_processing(&MemorySequenceGenerator::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_step_and_offset() {
+        check_step_and_offset(&MemorySequenceGenerator::new()).await;
+    }
+
+    #[tokio::test]
+    async fn test_recycled_values_are_reissued_smallest_first() {
+        let generator = MemorySequenceGenerator::new();
+        check_recycled_values_are_reissued_smallest_first(&generator).await;
+        // Nothing was ever issued from an unused counter.
+        assert!(matches!(
+            generator.release("UNUSED", &PrefixRule { initial_seq: 1, recycle: true, ..Default::default() }, 1).await,
+            Err(SequenceGeneratorError::NotIssued { sequence: 1 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_generate_range_is_contiguous() {
+        check_generate_range_is_contiguous(&MemorySequenceGenerator::new()).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at localhost:6379"]
+    async fn test_redis_initial_sequence_processing() {
+        delete_redis_keys(&["seq:INITIAL_TEST"]);
+        check_initial_sequence_processing(&RedisSequenceGenerator::new(REDIS_URL.to_string()).unwrap()).await;
+        delete_redis_keys(&["seq:INITIAL_TEST"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at localhost:6379"]
+    async fn test_redis_step_and_offset() {
+        delete_redis_keys(&["seq:STEP_TEST", "seq:STEP_TEN_TEST"]);
+        check_step_and_offset(&RedisSequenceGenerator::new(REDIS_URL.to_string()).unwrap()).await;
+        delete_redis_keys(&["seq:STEP_TEST", "seq:STEP_TEN_TEST"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at localhost:6379"]
+    async fn test_redis_recycled_values_are_reissued_smallest_first() {
+        delete_redis_keys(&["seq:RECYCLE_TEST", "seq_free:RECYCLE_TEST"]);
+        check_recycled_values_are_reissued_smallest_first(&RedisSequenceGenerator::new(REDIS_URL.to_string()).unwrap()).await;
+        delete_redis_keys(&["seq:RECYCLE_TEST", "seq_free:RECYCLE_TEST"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a Redis server at localhost:6379"]
+    async fn test_redis_generate_range_is_contiguous() {
+        delete_redis_keys(&["seq:RANGE_TEST"]);
+        check_generate_range_is_contiguous(&RedisSequenceGenerator::new(REDIS_URL.to_string()).unwrap()).await;
+        delete_redis_keys(&["seq:RANGE_TEST"]);
     }
 }
@@ -0,0 +1,155 @@
+//! Keyed, format-preserving scrambling of sequence values.
+//!
+//! Consecutive counter values rendered as-is leak how many numbers a prefix has issued.
+//! A prefix with a scramble key instead renders `permute(sequence)`, where `permute` is a
+//! bijection over the values the sequence placeholder's width can hold: every counter
+//! value still maps to a distinct, fixed-width number, but neighbours look unrelated.
+//!
+//! The permutation is a balanced Feistel network over the smallest even number of bits
+//! covering the domain, with cycle walking to stay inside it. Round keys come from
+//! SipHash keyed by the secret, so the mapping is stable across releases and platforms.
+
+use siphasher::sip::SipHasher24;
+use std::hash::Hasher;
+
+const ROUNDS: u64 = 8;
+
+/// SipHash key derived from a prefix's secret.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrambleKey {
+    k0: u64,
+    k1: u64,
+}
+
+impl ScrambleKey {
+    pub fn from_secret(secret: &str) -> Self {
+        let derive = |label: u8| {
+            let mut hasher = SipHasher24::new();
+            hasher.write_u8(label);
+            hasher.write(secret.as_bytes());
+            hasher.finish()
+        };
+        ScrambleKey { k0: derive(0), k1: derive(1) }
+    }
+}
+
+/// Bijection over `0..domain` (`None` standing for the full `u64` range).
+pub struct SequencePermutation {
+    key: ScrambleKey,
+    domain: Option<u64>,
+    half_bits: u32,
+}
+
+impl SequencePermutation {
+    pub fn new(key: ScrambleKey, domain: Option<u64>) -> Self {
+        let bits = match domain {
+            Some(domain) => (u64::BITS - domain.saturating_sub(1).leading_zeros()).max(2),
+            None => u64::BITS,
+        };
+        SequencePermutation { key, domain, half_bits: bits.div_ceil(2) }
+    }
+
+    /// Whether `value` lies in the permuted domain.
+    pub fn contains(&self, value: u64) -> bool {
+        self.domain.is_none_or(|domain| value < domain)
+    }
+
+    pub fn permute(&self, value: u64) -> Option<u64> {
+        self.walk(value, |x| self.encrypt(x))
+    }
+
+    pub fn invert(&self, value: u64) -> Option<u64> {
+        self.walk(value, |x| self.decrypt(x))
+    }
+
+    /// Applies `step` until the result falls back inside the domain. Because the Feistel
+    /// network permutes a superset at most four times the domain's size, this terminates
+    /// after a few steps on average and keeps the mapping bijective.
+    fn walk(&self, value: u64, step: impl Fn(u64) -> u64) -> Option<u64> {
+        if !self.contains(value) {
+            return None;
+        }
+        let mut x = step(value);
+        while !self.contains(x) {
+            x = step(x);
+        }
+        Some(x)
+    }
+
+    fn mask(&self) -> u64 {
+        u64::MAX >> (u64::BITS - self.half_bits)
+    }
+
+    fn round(&self, round: u64, half: u64) -> u64 {
+        let mut hasher = SipHasher24::new_with_keys(self.key.k0, self.key.k1);
+        hasher.write_u64(round);
+        hasher.write_u64(half);
+        hasher.finish() & self.mask()
+    }
+
+    fn encrypt(&self, x: u64) -> u64 {
+        let (mut left, mut right) = (x >> self.half_bits, x & self.mask());
+        for round in 0..ROUNDS {
+            (left, right) = (right, left ^ self.round(round, right));
+        }
+        (left << self.half_bits) | right
+    }
+
+    fn decrypt(&self, x: u64) -> u64 {
+        let (mut left, mut right) = (x >> self.half_bits, x & self.mask());
+        for round in (0..ROUNDS).rev() {
+            (left, right) = (right ^ self.round(round, left), left);
+        }
+        (left << self.half_bits) | right
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_permutation_is_a_bijection() {
+        let permutation = SequencePermutation::new(ScrambleKey::from_secret("s3cret"), Some(1_000));
+        let images: HashSet<u64> = (0..1_000).map(|v| permutation.permute(v).unwrap()).collect();
+        assert_eq!(images.len(), 1_000);
+        assert!(images.iter().all(|&v| v < 1_000));
+    }
+
+    #[test]
+    fn test_invert_round_trip() {
+        let key = ScrambleKey::from_secret("s3cret");
+        for domain in [Some(2), Some(10), Some(100_000_000), Some(36u64.pow(6)), None] {
+            let permutation = SequencePermutation::new(key, domain);
+            for value in [0, 1, 42, 99_999_999, u64::MAX] {
+                if permutation.contains(value) {
+                    let scrambled = permutation.permute(value).unwrap();
+                    assert_eq!(permutation.invert(scrambled), Some(value));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_consecutive_values_are_scattered() {
+        let permutation = SequencePermutation::new(ScrambleKey::from_secret("s3cret"), Some(100_000_000));
+        let first = permutation.permute(1).unwrap();
+        let second = permutation.permute(2).unwrap();
+        assert_ne!(second, first + 1);
+    }
+
+    #[test]
+    fn test_key_changes_the_mapping() {
+        let a = SequencePermutation::new(ScrambleKey::from_secret("a"), Some(100_000_000));
+        let b = SequencePermutation::new(ScrambleKey::from_secret("b"), Some(100_000_000));
+        assert_ne!((1..10).map(|v| a.permute(v)).collect::<Vec<_>>(), (1..10).map(|v| b.permute(v)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_values_outside_domain_are_rejected() {
+        let permutation = SequencePermutation::new(ScrambleKey::from_secret("s3cret"), Some(100));
+        assert_eq!(permutation.permute(100), None);
+        assert_eq!(permutation.invert(100), None);
+    }
+}
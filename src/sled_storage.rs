@@ -0,0 +1,191 @@
+use async_trait::async_trait;
+use sled::{Db, Tree};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::storage_backend::{CounterStep, StorageBackend, StorageError};
+
+/// An embedded database in a local directory, for a single node that should keep its
+/// counters across restarts without running Redis.
+///
+/// Values and counters share the default tree; each sorted set lives in the `sets` tree
+/// under its key, a zero byte and the member in big-endian order, so members scan
+/// smallest first. Counters are moved with compare-and-swap and every write is flushed
+/// before it is acknowledged: after a crash a counter may not go back and reissue values.
+/// Counters with a `ttl_secs` have their deadline (Unix seconds, big-endian) in the
+/// `deadlines` tree; overdue ones are dropped whenever a new such counter is created.
+pub struct SledStorage {
+    db: Db,
+    sets: Tree,
+    deadlines: Tree,
+}
+
+impl SledStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        Self::with_db(sled::open(path)?)
+    }
+
+    /// Uses an already opened database, e.g. a temporary one.
+    pub fn with_db(db: Db) -> Result<Self, StorageError> {
+        let sets = db.open_tree("sets")?;
+        let deadlines = db.open_tree("deadlines")?;
+        Ok(SledStorage { db, sets, deadlines })
+    }
+
+    /// The underlying database, for the ledgers to keep their trees in. Sled locks the
+    /// directory, so it can only be opened once per process.
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    fn member_key(key: &str, value: u64) -> Vec<u8> {
+        let mut member_key = Self::set_prefix(key);
+        member_key.extend_from_slice(&value.to_be_bytes());
+        member_key
+    }
+
+    fn set_prefix(key: &str) -> Vec<u8> {
+        let mut prefix = key.as_bytes().to_vec();
+        prefix.push(0);
+        prefix
+    }
+
+    /// Records when the new counter `key` is due, and drops the counters that are overdue.
+    fn schedule_expiry(&self, key: &str, ttl_secs: u64) -> Result<(), StorageError> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+        for entry in self.deadlines.iter() {
+            let (counter, deadline) = entry?;
+            let deadline = deadline.as_ref().try_into().map_or(0, u64::from_be_bytes);
+            if deadline <= now {
+                self.db.remove(&counter)?;
+                self.deadlines.remove(&counter)?;
+            }
+        }
+        self.deadlines.insert(key, &now.saturating_add(ttl_secs).to_be_bytes())?;
+        Ok(())
+    }
+
+    fn parse_counter(key: &str, value: &[u8]) -> Result<i64, StorageError> {
+        std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| StorageError::Other(format!("{} does not hold a counter", key)))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for SledStorage {
+    async fn get(&self, key: &str) -> Result<Option<String>, StorageError> {
+        match self.db.get(key)? {
+            Some(value) => String::from_utf8(value.to_vec())
+                .map(Some)
+                .map_err(|_| StorageError::Other(format!("{} does not hold a string", key))),
+            None => Ok(None),
+        }
+    }
+
+    async fn set(&self, key: &str, value: &str) -> Result<(), StorageError> {
+        self.db.insert(key, value.as_bytes())?;
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    async fn advance_counter(&self, key: &str, counter_step: &CounterStep) -> Result<i64, StorageError> {
+        loop {
+            let current = self.db.get(key)?;
+            let value = current.as_deref().map(|value| Self::parse_counter(key, value)).transpose()?;
+            let end = counter_step.apply(value)?;
+            let created = current.is_none();
+            if self.db.compare_and_swap(key, current, Some(end.to_string().as_bytes()))?.is_ok() {
+                if let (true, Some(ttl_secs)) = (created, counter_step.ttl_secs) {
+                    self.schedule_expiry(key, ttl_secs)?;
+                }
+                self.db.flush_async().await?;
+                return Ok(end);
+            }
+        }
+    }
+
+    async fn add_member(&self, key: &str, value: u64) -> Result<bool, StorageError> {
+        let added = self
+            .sets
+            .compare_and_swap(Self::member_key(key, value), None as Option<&[u8]>, Some(&[] as &[u8]))?
+            .is_ok();
+        self.db.flush_async().await?;
+        Ok(added)
+    }
+
+    async fn pop_min(&self, key: &str) -> Result<Option<u64>, StorageError> {
+        let prefix = Self::set_prefix(key);
+        loop {
+            let Some((member_key, member)) = self.sets.scan_prefix(&prefix).next().transpose()? else {
+                return Ok(None);
+            };
+            // Another caller may have taken the same member in between; look again.
+            if self.sets.compare_and_swap(&member_key, Some(member), None as Option<&[u8]>)?.is_ok() {
+                self.db.flush_async().await?;
+                let bytes: [u8; 8] = member_key[prefix.len()..]
+                    .try_into()
+                    .map_err(|_| StorageError::Other(format!("{} does not hold a sorted set", key)))?;
+                return Ok(Some(u64::from_be_bytes(bytes)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prefix_rule::PrefixRule;
+
+    #[tokio::test]
+    async fn test_values_survive_reopening() {
+        let path = std::env::temp_dir().join(format!("number_generator_sled_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let counter_step = CounterStep::for_rule(&PrefixRule { initial_seq: 1, ..Default::default() }, 2).unwrap();
+        {
+            let storage = SledStorage::open(&path).unwrap();
+            storage.set("prefix_rule:SLED", "{}").await.unwrap();
+            assert_eq!(storage.advance_counter("seq:SLED", &counter_step).await.unwrap(), 2);
+            assert!(storage.add_member("seq_free:SLED", 300).await.unwrap());
+            assert!(storage.add_member("seq_free:SLED", 2).await.unwrap());
+            assert!(!storage.add_member("seq_free:SLED", 2).await.unwrap());
+        }
+
+        // Sled's flusher thread lets go of the directory lock shortly after the drop.
+        let mut attempts = 0;
+        let storage = loop {
+            match SledStorage::open(&path) {
+                Ok(storage) => break storage,
+                Err(_) if attempts < 50 => {
+                    attempts += 1;
+                    tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                }
+                Err(e) => panic!("{}", e),
+            }
+        };
+        assert_eq!(storage.get("prefix_rule:SLED").await.unwrap().as_deref(), Some("{}"));
+        assert_eq!(storage.advance_counter("seq:SLED", &counter_step).await.unwrap(), 4);
+        // Members of another set sharing the key's prefix are not mixed in.
+        assert!(storage.add_member("seq_free:SLED2", 1).await.unwrap());
+        assert_eq!(storage.pop_min("seq_free:SLED").await.unwrap(), Some(2));
+        assert_eq!(storage.pop_min("seq_free:SLED").await.unwrap(), Some(300));
+        assert_eq!(storage.pop_min("seq_free:SLED").await.unwrap(), None);
+        drop(storage);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[tokio::test]
+    async fn test_expired_counters_are_dropped() {
+        let storage = SledStorage::with_db(sled::Config::new().temporary(true).open().unwrap()).unwrap();
+        let counter_step = CounterStep { ttl_secs: Some(0), ..CounterStep::for_rule(&PrefixRule::default(), 1).unwrap() };
+        storage.advance_counter("seq:A:20261016", &counter_step).await.unwrap();
+        storage.advance_counter("seq:B", &CounterStep::for_rule(&PrefixRule::default(), 1).unwrap()).await.unwrap();
+        assert!(storage.counter("seq:A:20261016").await.unwrap().is_some());
+
+        storage.advance_counter("seq:A:20261017", &counter_step).await.unwrap();
+        assert_eq!(storage.counter("seq:A:20261016").await.unwrap(), None);
+        assert!(storage.counter("seq:A:20261017").await.unwrap().is_some());
+        assert!(storage.counter("seq:B").await.unwrap().is_some());
+    }
+}
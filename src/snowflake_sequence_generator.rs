@@ -0,0 +1,233 @@
+//! Snowflake-style sequence values that need no shared store.
+//!
+//! Each value packs, from the most significant bit down: a zero sign bit, 41 bits of
+//! milliseconds since `SNOWFLAKE_EPOCH_MS`, a 10-bit worker id and a 12-bit counter that
+//! restarts every millisecond. Values are unique as long as every node generating for the
+//! same prefixes has its own worker id, and they grow with time on each node.
+//!
+//! A clock that steps backwards would reissue old timestamps. Small regressions (NTP
+//! slewing, leap-second smearing) are waited out; larger ones are refused until the clock
+//! has caught up again.
+
+use async_trait::async_trait;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::prefix_rule::PrefixRule;
+use crate::sequence_generator::{SequenceGenerator, SequenceGeneratorError, SequenceRange};
+
+/// 2024-01-01T00:00:00Z, the zero of the timestamp bits.
+pub const SNOWFLAKE_EPOCH_MS: u64 = 1_704_067_200_000;
+
+const TIMESTAMP_BITS: u32 = 41;
+const WORKER_ID_BITS: u32 = 10;
+const COUNTER_BITS: u32 = 12;
+
+pub const MAX_WORKER_ID: u64 = (1 << WORKER_ID_BITS) - 1;
+
+/// Values issued per worker and millisecond; also the largest range one call can reserve.
+pub const COUNTER_CAPACITY: u64 = 1 << COUNTER_BITS;
+
+/// Largest value a Snowflake generator issues.
+pub const SNOWFLAKE_MAX: u64 = i64::MAX as u64;
+
+/// Clock regressions up to this long are waited out rather than refused.
+pub const DEFAULT_MAX_CLOCK_REGRESSION: Duration = Duration::from_millis(5);
+
+#[derive(Debug, Default)]
+struct State {
+    last_ms: u64,
+    /// Counter values already issued in `last_ms`.
+    issued: u64,
+}
+
+/// What a caller does after looking at the clock.
+enum Reservation {
+    Issued(SequenceRange),
+    /// Not enough values left in this millisecond, or the clock is slightly behind.
+    Wait(Duration),
+}
+
+pub struct SnowflakeSequenceGenerator {
+    worker_id: u64,
+    max_clock_regression: Duration,
+    clock: Box<dyn Fn() -> u64 + Send + Sync>,
+    state: Mutex<State>,
+}
+
+impl SnowflakeSequenceGenerator {
+    pub fn new(worker_id: u64) -> Result<Self, SequenceGeneratorError> {
+        if worker_id > MAX_WORKER_ID {
+            return Err(SequenceGeneratorError::Other(format!(
+                "Snowflake worker id must be at most {}, got {}",
+                MAX_WORKER_ID, worker_id
+            )));
+        }
+        Ok(SnowflakeSequenceGenerator {
+            worker_id,
+            max_clock_regression: DEFAULT_MAX_CLOCK_REGRESSION,
+            clock: Box::new(|| SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)),
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    pub fn with_max_clock_regression(mut self, max_clock_regression: Duration) -> Self {
+        self.max_clock_regression = max_clock_regression;
+        self
+    }
+
+    /// Replaces the wall clock (milliseconds since the Unix epoch), e.g. in tests.
+    pub fn with_clock(mut self, clock: impl Fn() -> u64 + Send + Sync + 'static) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    fn reserve(&self, count: u64) -> Result<Reservation, SequenceGeneratorError> {
+        let now = (self.clock)();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if now < state.last_ms {
+            let behind = Duration::from_millis(state.last_ms - now);
+            if behind > self.max_clock_regression {
+                return Err(SequenceGeneratorError::ClockMovedBackwards { millis: state.last_ms - now });
+            }
+            return Ok(Reservation::Wait(behind));
+        }
+        if now > state.last_ms {
+            *state = State { last_ms: now, issued: 0 };
+        }
+        if state.issued + count > COUNTER_CAPACITY {
+            return Ok(Reservation::Wait(Duration::from_millis(1)));
+        }
+
+        let elapsed = now.checked_sub(SNOWFLAKE_EPOCH_MS).ok_or_else(|| {
+            SequenceGeneratorError::Other("Clock is set before the Snowflake epoch".to_string())
+        })?;
+        if elapsed >> TIMESTAMP_BITS != 0 {
+            return Err(SequenceGeneratorError::Overflow { limit: SNOWFLAKE_MAX });
+        }
+        let start = (elapsed << (WORKER_ID_BITS + COUNTER_BITS)) | (self.worker_id << COUNTER_BITS) | state.issued;
+        state.issued += count;
+        Ok(Reservation::Issued(SequenceRange { start, count, step: 1 }))
+    }
+}
+
+#[async_trait]
+impl SequenceGenerator for SnowflakeSequenceGenerator {
+    async fn generate(&self, sequence_key: &str, prefix_rule: &PrefixRule) -> Result<u64, SequenceGeneratorError> {
+        Ok(self.generate_range(sequence_key, prefix_rule, 1).await?.start)
+    }
+
+    /// Ranges come from a single millisecond's counter, so at most `COUNTER_CAPACITY`
+    /// values can be reserved at once. The sequence key plays no part: values are unique
+    /// across all prefixes of a worker.
+    async fn generate_range(
+        &self,
+        _sequence_key: &str,
+        _prefix_rule: &PrefixRule,
+        count: u64,
+    ) -> Result<SequenceRange, SequenceGeneratorError> {
+        if count > COUNTER_CAPACITY {
+            return Err(SequenceGeneratorError::Other(format!(
+                "Snowflake ranges are limited to {} values",
+                COUNTER_CAPACITY
+            )));
+        }
+        loop {
+            match self.reserve(count)? {
+                Reservation::Issued(range) => return Ok(range),
+                Reservation::Wait(duration) => tokio::time::sleep(duration).await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+
+    const NOW: u64 = SNOWFLAKE_EPOCH_MS + 1_000;
+
+    fn with_clock(worker_id: u64, clock: Arc<AtomicU64>) -> SnowflakeSequenceGenerator {
+        SnowflakeSequenceGenerator::new(worker_id).unwrap().with_clock(move || clock.load(Ordering::SeqCst))
+    }
+
+    #[test]
+    fn test_layout() {
+        let generator = with_clock(3, Arc::new(AtomicU64::new(NOW)));
+        let Ok(Reservation::Issued(range)) = generator.reserve(1) else {
+            panic!("expected a value");
+        };
+        assert_eq!(range.start >> 22, 1_000);
+        assert_eq!((range.start >> 12) & MAX_WORKER_ID, 3);
+        assert_eq!(range.start & (COUNTER_CAPACITY - 1), 0);
+    }
+
+    #[test]
+    fn test_worker_id_is_bounded() {
+        assert!(SnowflakeSequenceGenerator::new(MAX_WORKER_ID).is_ok());
+        assert!(SnowflakeSequenceGenerator::new(MAX_WORKER_ID + 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_values_are_unique_and_increasing() {
+        let generator = SnowflakeSequenceGenerator::new(1).unwrap();
+        let rule = PrefixRule::default();
+        let mut last = 0;
+        let mut seen = HashSet::new();
+        for _ in 0..10_000 {
+            let value = generator.generate("SNOW", &rule).await.unwrap();
+            assert!(value > last);
+            assert!(seen.insert(value));
+            last = value;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ranges_stay_within_one_millisecond() {
+        let clock = Arc::new(AtomicU64::new(NOW));
+        let generator = with_clock(0, clock.clone());
+        let rule = PrefixRule::default();
+        let first = generator.generate_range("SNOW", &rule, 4000).await.unwrap();
+        assert_eq!(first.start & (COUNTER_CAPACITY - 1), 0);
+
+        // Not enough left in this millisecond; the next range waits for the clock.
+        let waiting = tokio::spawn(async move { generator.generate_range("SNOW", &rule, 100).await });
+        clock.store(NOW + 1, Ordering::SeqCst);
+        let second = waiting.await.unwrap().unwrap();
+        assert_eq!(second.start >> 22, 1_001);
+        assert!(second.start > first.end());
+    }
+
+    #[tokio::test]
+    async fn test_clock_regression() {
+        let clock = Arc::new(AtomicU64::new(NOW));
+        let generator = with_clock(0, clock.clone()).with_max_clock_regression(Duration::from_millis(5));
+        let rule = PrefixRule::default();
+        let before = generator.generate("SNOW", &rule).await.unwrap();
+
+        clock.store(NOW - 100, Ordering::SeqCst);
+        assert!(matches!(
+            generator.generate("SNOW", &rule).await,
+            Err(SequenceGeneratorError::ClockMovedBackwards { millis: 100 })
+        ));
+
+        clock.store(NOW - 2, Ordering::SeqCst);
+        let generator = Arc::new(generator);
+        let waiting = {
+            let generator = generator.clone();
+            tokio::spawn(async move { generator.generate("SNOW", &rule).await })
+        };
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        clock.store(NOW, Ordering::SeqCst);
+        assert!(waiting.await.unwrap().unwrap() > before);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_range_is_refused() {
+        let generator = SnowflakeSequenceGenerator::new(0).unwrap();
+        assert!(generator.generate_range("SNOW", &PrefixRule::default(), COUNTER_CAPACITY + 1).await.is_err());
+    }
+}
@@ -0,0 +1,226 @@
+//! Gap-free counters in a relational database.
+//!
+//! Redis `INCR` is fast but numbers are lost whenever a request fails after the
+//! increment, which some documents (invoices, vouchers) are not allowed to do. Here each
+//! counter is a row of `sequence_counters`, moved forward inside a transaction that
+//! first locks the row and then claims the next values with a compare-and-swap
+//! `UPDATE ... WHERE value = <value read>`. The transaction stays open in the returned
+//! `Claim` until the caller has applied the overflow policy and assembled the numbers;
+//! only then is it committed, and a request that fails before that rolls back, so the
+//! next caller is issued the same values. Conflicting claims are retried a bounded number
+//! of times.
+//!
+//! Until a claim is committed, other callers of the same counter wait for it (in SQLite,
+//! every writer of the database does), so claims are held only for as long as it takes
+//! to build the response.
+//!
+//! The statements are plain SQL understood by both SQLite and PostgreSQL, reached through
+//! sqlx's `Any` driver, so the same generator runs against a local SQLite file in tests
+//! and a shared Postgres database in production.
+
+use async_trait::async_trait;
+use sqlx::any::{install_default_drivers, AnyPoolOptions};
+use sqlx::{Any, AnyPool, Transaction};
+use std::time::Duration;
+
+use crate::prefix_rule::PrefixRule;
+use crate::sequence_generator::{next_range, Claim, PendingClaim, SequenceGenerator, SequenceGeneratorError, SequenceRange};
+
+/// Conflicting updates tolerated before a request gives up.
+pub const DEFAULT_MAX_RETRIES: u32 = 100;
+
+const CREATE_TABLE: &str = "CREATE TABLE IF NOT EXISTS sequence_counters (
+    sequence_key VARCHAR(255) PRIMARY KEY,
+    value BIGINT NOT NULL
+)";
+// Writes the row without changing it, to take its lock before it is read.
+const LOCK_COUNTER: &str = "UPDATE sequence_counters SET value = value WHERE sequence_key = $1";
+const SELECT_COUNTER: &str = "SELECT value FROM sequence_counters WHERE sequence_key = $1";
+const INSERT_COUNTER: &str =
+    "INSERT INTO sequence_counters (sequence_key, value) VALUES ($1, $2) ON CONFLICT (sequence_key) DO NOTHING";
+const UPDATE_COUNTER: &str = "UPDATE sequence_counters SET value = $1 WHERE sequence_key = $2 AND value = $3";
+
+pub struct SqlSequenceGenerator {
+    pool: AnyPool,
+    max_retries: u32,
+}
+
+impl SqlSequenceGenerator {
+    /// Connects to `database_url` (`sqlite://...` or `postgres://...`) and creates the
+    /// counter table if it does not exist yet.
+    pub async fn connect(database_url: &str) -> Result<Self, SequenceGeneratorError> {
+        install_default_drivers();
+        let pool = AnyPoolOptions::new().connect(database_url).await?;
+        sqlx::query(CREATE_TABLE).execute(&pool).await?;
+        Ok(SqlSequenceGenerator { pool, max_retries: DEFAULT_MAX_RETRIES })
+    }
+
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    /// Tries once to move the counter from the value just read to the end of the next
+    /// range, in a transaction left open for the caller to commit. `None` means another
+    /// caller got there first.
+    async fn try_claim(
+        &self,
+        sequence_key: &str,
+        prefix_rule: &PrefixRule,
+        count: u64,
+    ) -> Result<Option<Claim>, SequenceGeneratorError> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(LOCK_COUNTER).bind(sequence_key).execute(&mut *tx).await?;
+        let current: Option<i64> = sqlx::query_scalar(SELECT_COUNTER)
+            .bind(sequence_key)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let range = next_range(current.map(|value| value as u64), prefix_rule, count)?;
+        let claimed = match current {
+            None => sqlx::query(INSERT_COUNTER)
+                .bind(sequence_key)
+                .bind(range.end() as i64)
+                .execute(&mut *tx)
+                .await?,
+            Some(current) => sqlx::query(UPDATE_COUNTER)
+                .bind(range.end() as i64)
+                .bind(sequence_key)
+                .bind(current)
+                .execute(&mut *tx)
+                .await?,
+        };
+        // A transaction dropped here rolls back.
+        Ok((claimed.rows_affected() == 1).then(|| Claim::pending(range, Box::new(SqlClaim(tx)))))
+    }
+}
+
+/// A claim's open transaction; dropping it rolls the counter back.
+struct SqlClaim(Transaction<'static, Any>);
+
+#[async_trait]
+impl PendingClaim for SqlClaim {
+    async fn commit(self: Box<Self>) -> Result<(), SequenceGeneratorError> {
+        Ok(self.0.commit().await?)
+    }
+}
+
+#[async_trait]
+impl SequenceGenerator for SqlSequenceGenerator {
+    async fn generate(&self, sequence_key: &str, prefix_rule: &PrefixRule) -> Result<u64, SequenceGeneratorError> {
+        Ok(self.generate_range(sequence_key, prefix_rule, 1).await?.start)
+    }
+
+    async fn generate_range(
+        &self,
+        sequence_key: &str,
+        prefix_rule: &PrefixRule,
+        count: u64,
+    ) -> Result<SequenceRange, SequenceGeneratorError> {
+        self.claim_range(sequence_key, prefix_rule, count).await?.commit().await
+    }
+
+    async fn claim_range(
+        &self,
+        sequence_key: &str,
+        prefix_rule: &PrefixRule,
+        count: u64,
+    ) -> Result<Claim, SequenceGeneratorError> {
+        for attempt in 0..=self.max_retries {
+            if let Some(claim) = self.try_claim(sequence_key, prefix_rule, count).await? {
+                return Ok(claim);
+            }
+            // Back off a little longer on each conflict so contending callers spread out.
+            tokio::time::sleep(Duration::from_millis(u64::from(attempt.min(10)))).await;
+        }
+        Err(SequenceGeneratorError::Other(format!(
+            "Counter {} is too contended: gave up after {} conflicting updates",
+            sequence_key, self.max_retries
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    /// Removes a test's SQLite file, and the journals SQLite keeps beside it, when dropped.
+    struct TempDatabase(PathBuf);
+
+    impl Drop for TempDatabase {
+        fn drop(&mut self) {
+            for suffix in ["", "-journal", "-wal", "-shm"] {
+                let mut path = self.0.clone().into_os_string();
+                path.push(suffix);
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    /// A fresh SQLite file per test; an in-memory database would give each pooled
+    /// connection its own copy.
+    async fn sqlite_generator(name: &str) -> (SqlSequenceGenerator, TempDatabase) {
+        let database = TempDatabase(std::env::temp_dir().join(format!("number_generator_{}_{}.db", name, std::process::id())));
+        let _ = std::fs::remove_file(&database.0);
+        let url = format!("sqlite://{}?mode=rwc", database.0.display());
+        (SqlSequenceGenerator::connect(&url).await.unwrap(), database)
+    }
+
+    #[tokio::test]
+    async fn test_initial_sequence_and_step() {
+        let (generator, _database) = sqlite_generator("initial").await;
+        let rule = PrefixRule { initial_seq: 1000, ..Default::default() };
+        assert_eq!(generator.generate("SQL", &rule).await.unwrap(), 1000);
+        assert_eq!(generator.generate("SQL", &rule).await.unwrap(), 1001);
+
+        let rule = PrefixRule { initial_seq: 1, step: 2, offset: 1, ..Default::default() };
+        let range = generator.generate_range("SQL_ODD", &rule, 3).await.unwrap();
+        assert_eq!(range.values().collect::<Vec<_>>(), vec![1, 3, 5]);
+        assert_eq!(generator.generate("SQL_ODD", &rule).await.unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn test_values_of_a_failed_claim_are_reissued() {
+        let (generator, _database) = sqlite_generator("failed_claim").await;
+        let rule = PrefixRule { initial_seq: 1, ..Default::default() };
+        assert_eq!(generator.generate("SQL_CLAIM", &rule).await.unwrap(), 1);
+
+        // The request fails after claiming, before its numbers are delivered.
+        let claim = generator.claim_range("SQL_CLAIM", &rule, 2).await.unwrap();
+        assert_eq!(claim.range.values().collect::<Vec<_>>(), vec![2, 3]);
+        drop(claim);
+
+        let claim = generator.claim_range("SQL_CLAIM", &rule, 1).await.unwrap();
+        assert_eq!(claim.commit().await.unwrap().start, 2);
+        assert_eq!(generator.generate("SQL_CLAIM", &rule).await.unwrap(), 3);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_generation_is_gap_free() {
+        let (generator, _database) = sqlite_generator("concurrent").await;
+        let generator = Arc::new(generator);
+        let rule = PrefixRule { initial_seq: 1, ..Default::default() };
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let generator = generator.clone();
+                let rule = rule.clone();
+                tokio::spawn(async move {
+                    let mut values = Vec::new();
+                    for _ in 0..25 {
+                        values.push(generator.generate("SQL_CONCURRENT", &rule).await.unwrap());
+                    }
+                    values
+                })
+            })
+            .collect();
+        let mut seen = HashSet::new();
+        for task in tasks {
+            for value in task.await.unwrap() {
+                assert!(seen.insert(value), "duplicate value {}", value);
+            }
+        }
+        assert_eq!(seen, (1..=200).collect::<HashSet<_>>());
+    }
+}
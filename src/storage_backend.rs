@@ -0,0 +1,240 @@
+//! Where prefix rules and counters are kept.
+//!
+//! `StoragePrefixRuleManager` and `StorageSequenceGenerator` only need a handful of
+//! operations from their store: plain string values for the rules, counters that move
+//! atomically by the rules of `CounterStep`, and sorted sets of integers for the free
+//! lists of recycling rules. `StorageBackend` is that contract. Redis (`RedisStorage`)
+//! suits a cluster of nodes; an embedded sled database (`SledStorage`) or plain memory
+//! (`MemoryStorage`) lets a single node run without any external service.
+
+use async_trait::async_trait;
+use redis::RedisError;
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use crate::prefix_rule::PrefixRule;
+use crate::sequence_generator::{SequenceRange, REDIS_COUNTER_MAX};
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("Redis error: {0}")]
+    Redis(#[from] RedisError),
+    #[error("Sled error: {0}")]
+    Sled(#[from] sled::Error),
+    #[error("Counter overflow: past its maximum of {limit}")]
+    Overflow { limit: u64 },
+    #[error("{0}")]
+    Other(String),
+}
+
+/// One move of a counter: raise it to `seed` when it is missing or below it, otherwise
+/// realign it down to the residue class `offset` modulo `step`, then add `increment`.
+///
+/// This is how every store reserves values, so a counter behaves the same whichever
+/// backend holds it: a value is never issued twice, a higher `initial_seq` skips ahead and
+/// a changed step or offset realigns the counter before the next value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CounterStep {
+    /// The first value minus one step.
+    pub seed: i64,
+    pub step: i64,
+    pub offset: i64,
+    /// The step times the number of values reserved.
+    pub increment: i64,
+    /// Seconds a counter is kept after its first move (see `ResetPolicy::counter_ttl_secs`);
+    /// `None` keeps it for good.
+    pub ttl_secs: Option<u64>,
+}
+
+impl CounterStep {
+    /// The move reserving `count` values of a counter following `prefix_rule`.
+    pub fn for_rule(prefix_rule: &PrefixRule, count: u64) -> Result<Self, StorageError> {
+        let overflow = || StorageError::Overflow { limit: REDIS_COUNTER_MAX };
+        let step = prefix_rule.step();
+        let increment = step
+            .checked_mul(count)
+            .filter(|&increment| increment <= REDIS_COUNTER_MAX)
+            .ok_or_else(overflow)?;
+        let first = i64::try_from(prefix_rule.first_sequence()).map_err(|_| overflow())?;
+        Ok(CounterStep {
+            seed: first - step as i64,
+            step: step as i64,
+            offset: (prefix_rule.offset % step) as i64,
+            increment: increment as i64,
+            ttl_secs: prefix_rule.reset_policy.counter_ttl_secs(),
+        })
+    }
+
+    /// The counter's value after this move, given its value before.
+    pub fn apply(&self, current: Option<i64>) -> Result<i64, StorageError> {
+        let base = match current {
+            Some(current) if current >= self.seed => current - (current - self.offset).rem_euclid(self.step),
+            _ => self.seed,
+        };
+        base.checked_add(self.increment).ok_or(StorageError::Overflow { limit: REDIS_COUNTER_MAX })
+    }
+
+    /// The values reserved by a move that left the counter at `end`.
+    pub fn range(&self, end: i64) -> SequenceRange {
+        let step = self.step as u64;
+        SequenceRange {
+            start: (end + self.step - self.increment) as u64,
+            count: (self.increment / self.step) as u64,
+            step,
+        }
+    }
+}
+
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<String>, StorageError>;
+
+    async fn set(&self, key: &str, value: &str) -> Result<(), StorageError>;
+
+    /// Applies `counter_step` to the counter `key` atomically and returns its new value.
+    /// A counter created with a `ttl_secs` is dropped that long after its first move.
+    async fn advance_counter(&self, key: &str, counter_step: &CounterStep) -> Result<i64, StorageError>;
+
+    /// The counter's current value, `None` if it was never advanced.
+    async fn counter(&self, key: &str) -> Result<Option<i64>, StorageError> {
+        match self.get(key).await? {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| StorageError::Other(format!("{} does not hold a counter", key))),
+            None => Ok(None),
+        }
+    }
+
+    /// Adds `value` to the sorted set `key`; `false` if it was already a member.
+    async fn add_member(&self, key: &str, value: u64) -> Result<bool, StorageError>;
+
+    /// Removes and returns the smallest member of the sorted set `key`.
+    async fn pop_min(&self, key: &str) -> Result<Option<u64>, StorageError>;
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    values: HashMap<String, String>,
+    sets: HashMap<String, BTreeSet<u64>>,
+    /// When each counter with a `ttl_secs` is dropped.
+    deadlines: HashMap<String, Instant>,
+}
+
+/// Keeps everything in the process: nothing survives a restart, and every node counts on
+/// its own. Meant for tests, demos and single-node deployments that can afford to lose
+/// their counters.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    state: Mutex<MemoryState>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MemoryState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryStorage {
+    async fn get(&self, key: &str) -> Result<Option<String>, StorageError> {
+        Ok(self.state().values.get(key).cloned())
+    }
+
+    async fn set(&self, key: &str, value: &str) -> Result<(), StorageError> {
+        self.state().values.insert(key.to_string(), value.to_string());
+        Ok(())
+    }
+
+    async fn advance_counter(&self, key: &str, counter_step: &CounterStep) -> Result<i64, StorageError> {
+        let mut state = self.state();
+        let current = match state.values.get(key) {
+            Some(value) => Some(value.parse().map_err(|_| StorageError::Other(format!("{} does not hold a counter", key)))?),
+            None => None,
+        };
+        let end = counter_step.apply(current)?;
+        state.values.insert(key.to_string(), end.to_string());
+        if let (None, Some(ttl_secs)) = (current, counter_step.ttl_secs) {
+            // A new period's counter: a good moment to drop those of past periods.
+            let now = Instant::now();
+            let MemoryState { values, deadlines, .. } = &mut *state;
+            deadlines.retain(|key, deadline| {
+                let live = *deadline > now;
+                if !live {
+                    values.remove(key);
+                }
+                live
+            });
+            deadlines.insert(key.to_string(), now + Duration::from_secs(ttl_secs));
+        }
+        Ok(end)
+    }
+
+    async fn add_member(&self, key: &str, value: u64) -> Result<bool, StorageError> {
+        Ok(self.state().sets.entry(key.to_string()).or_default().insert(value))
+    }
+
+    async fn pop_min(&self, key: &str) -> Result<Option<u64>, StorageError> {
+        Ok(self.state().sets.get_mut(key).and_then(BTreeSet::pop_first))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counter_step() {
+        let rule = PrefixRule { initial_seq: 1, step: 2, offset: 1, ..Default::default() };
+        let counter_step = CounterStep::for_rule(&rule, 3).unwrap();
+        assert_eq!(counter_step, CounterStep { seed: -1, step: 2, offset: 1, increment: 6, ttl_secs: None });
+
+        let end = counter_step.apply(None).unwrap();
+        assert_eq!(counter_step.range(end).values().collect::<Vec<_>>(), vec![1, 3, 5]);
+        // A counter left in another residue class is realigned first.
+        assert_eq!(counter_step.apply(Some(6)).unwrap(), 11);
+
+        let counter_step = CounterStep::for_rule(&PrefixRule::default(), 1).unwrap();
+        assert!(matches!(counter_step.apply(Some(i64::MAX)), Err(StorageError::Overflow { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_memory_storage() {
+        let storage = MemoryStorage::new();
+        assert_eq!(storage.get("rule").await.unwrap(), None);
+        storage.set("rule", "{}").await.unwrap();
+        assert_eq!(storage.get("rule").await.unwrap().as_deref(), Some("{}"));
+
+        let counter_step = CounterStep::for_rule(&PrefixRule { initial_seq: 100, ..Default::default() }, 1).unwrap();
+        assert_eq!(storage.advance_counter("seq", &counter_step).await.unwrap(), 100);
+        assert_eq!(storage.advance_counter("seq", &counter_step).await.unwrap(), 101);
+        assert_eq!(storage.counter("seq").await.unwrap(), Some(101));
+
+        assert!(storage.add_member("free", 7).await.unwrap());
+        assert!(storage.add_member("free", 3).await.unwrap());
+        assert!(!storage.add_member("free", 7).await.unwrap());
+        assert_eq!(storage.pop_min("free").await.unwrap(), Some(3));
+        assert_eq!(storage.pop_min("free").await.unwrap(), Some(7));
+        assert_eq!(storage.pop_min("free").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_memory_storage_drops_expired_counters() {
+        let storage = MemoryStorage::new();
+        let counter_step = CounterStep { ttl_secs: Some(0), ..CounterStep::for_rule(&PrefixRule::default(), 1).unwrap() };
+        storage.advance_counter("seq:A:20261016", &counter_step).await.unwrap();
+        storage.advance_counter("seq:B", &CounterStep::for_rule(&PrefixRule::default(), 1).unwrap()).await.unwrap();
+        assert!(storage.counter("seq:A:20261016").await.unwrap().is_some());
+
+        storage.advance_counter("seq:A:20261017", &counter_step).await.unwrap();
+        assert_eq!(storage.counter("seq:A:20261016").await.unwrap(), None);
+        assert!(storage.counter("seq:A:20261017").await.unwrap().is_some());
+        assert!(storage.counter("seq:B").await.unwrap().is_some());
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+use tokio::time::sleep;
+
+use crate::prefix_rule_manager::PrefixRuleManager;
+use crate::prefix_rule::PrefixRule;
+use crate::storage_backend::{MemoryStorage, StorageBackend};
+
+const LOCAL_CACHE_SIZE: usize = 1000;
+
+/// Prefix rules kept as JSON under `prefix_rule:{prefix_key}` in a `StorageBackend`,
+/// with a local cache in front.
+pub struct StoragePrefixRuleManager<S: ?Sized> {
+    storage: Arc<S>,
+    local_cache: Arc<Mutex<HashMap<String, PrefixRule>>>,
+}
+
+impl<S: StorageBackend + ?Sized> StoragePrefixRuleManager<S> {
+    pub fn with_storage(storage: Arc<S>) -> Self {
+        let local_cache = Arc::new(Mutex::new(HashMap::with_capacity(LOCAL_CACHE_SIZE)));
+        StoragePrefixRuleManager {
+            storage,
+            local_cache,
+        }
+    }
+
+    fn get_storage_key(prefix_key: &str) -> String {
+        format!("prefix_rule:{}", prefix_key)
+    }
+
+    async fn get_prefix_rule_from_storage(&self, prefix_key: String) -> Result<Option<PrefixRule>, Box<dyn std::error::Error + Send>> {
+        let storage_key = Self::get_storage_key(&prefix_key);
+        let prefix_rule_json = self.storage.get(&storage_key).await.map_err(|e| Box::new(std::io::Error::other(e.to_string())) as Box<dyn std::error::Error + Send>)?;
+
+        match prefix_rule_json {
+            Some(json) => {
+                let mut prefix_rule: PrefixRule = serde_json::from_str(&json).map_err(|e| Box::new(std::io::Error::other(e.to_string())) as Box<dyn std::error::Error + Send>)?;
+                prefix_rule.compile_stored().map_err(|e| Box::new(std::io::Error::other(e)) as Box<dyn std::error::Error + Send>)?;
+                Ok(Some(prefix_rule))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Rules kept in this process only; see `MemoryStorage`.
+pub type MemoryPrefixRuleManager = StoragePrefixRuleManager<MemoryStorage>;
+
+impl MemoryPrefixRuleManager {
+    pub fn new() -> Self {
+        Self::with_storage(Arc::new(MemoryStorage::new()))
+    }
+}
+
+impl Default for MemoryPrefixRuleManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl<S: StorageBackend + ?Sized> PrefixRuleManager for StoragePrefixRuleManager<S> {
+    async fn register_prefix_rule(&self, prefix_key: String, mut prefix_rule: PrefixRule) -> Result<(), Box<dyn std::error::Error + Send>> {
+        prefix_rule.compile().map_err(|e| Box::new(std::io::Error::other(e)) as Box<dyn std::error::Error + Send>)?;
+        let storage_key = Self::get_storage_key(&prefix_key);
+        let prefix_rule_json = serde_json::to_string(&prefix_rule).map_err(|e| Box::new(std::io::Error::other(e.to_string())) as Box<dyn std::error::Error + Send>)?;
+        self.storage.set(&storage_key, &prefix_rule_json).await.map_err(|e| Box::new(std::io::Error::other(e.to_string())) as Box<dyn std::error::Error + Send>)?;
+
+        let mut cache = self.local_cache.lock().await;
+        cache.insert(prefix_key.clone(), prefix_rule.clone());
+
+        Ok(())
+    }
+
+    async fn get_prefix_rule(&self, prefix_key: String) -> Result<Option<PrefixRule>, Box<dyn std::error::Error + Send>> {
+        // 1. Try to get from local cache
+        let mut cache = self.local_cache.lock().await;
+        if let Some(rule) = cache.get(&prefix_key) {
+            return Ok(Some(rule.clone()));
+        }
+
+        // 2. If not in cache, try to get from storage
+        match self.get_prefix_rule_from_storage(prefix_key.clone()).await {
+            Ok(Some(rule)) => {
+                // 3. Store in local cache
+                cache.insert(prefix_key.clone(), rule.clone());
+                Ok(Some(rule))
+            }
+            Ok(None) => Ok(None),
+            Err(e) => {
+                // Attempt to reconnect to the store
+                eprintln!("Error getting prefix from storage: {}. Retrying...", e);
+                sleep(Duration::from_secs(1)).await;
+                match self.get_prefix_rule_from_storage(prefix_key.clone()).await {
+                    Ok(Some(rule)) => {
+                        // 3. Store in local cache
+                        cache.insert(prefix_key.clone(), rule.clone());
+                        Ok(Some(rule))
+                    }
+                    Ok(None) => Ok(None),
+                    Err(e) => {
+                        eprintln!("Error getting prefix from storage after retry: {}", e);
+                        Err(e)
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_rules_are_read_back_from_storage() {
+        let storage = Arc::new(MemoryStorage::new());
+        let manager = StoragePrefixRuleManager::with_storage(storage.clone());
+        let rule = PrefixRule { format: "INV-{SEQ:4}".to_string(), initial_seq: 7, ..Default::default() };
+        manager.register_prefix_rule("INV".to_string(), rule).await.unwrap();
+        assert!(storage.get("prefix_rule:INV").await.unwrap().is_some());
+
+        // A second node sharing the store, with an empty cache.
+        let other = StoragePrefixRuleManager::with_storage(storage);
+        let loaded = other.get_prefix_rule("INV".to_string()).await.unwrap().unwrap();
+        assert_eq!(loaded.initial_seq, 7);
+        assert!(other.get_prefix_rule("MISSING".to_string()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rules_stored_by_the_tolerant_parser_still_load() {
+        let storage = Arc::new(MemoryStorage::new());
+        let json = r#"{"prefix_key":"OLD","format":"OLD-{yaer}-{SEQ}","seq_length":4,"initial_seq":1,"network_partition":false}"#;
+        storage.set("prefix_rule:OLD", json).await.unwrap();
+
+        let manager = StoragePrefixRuleManager::with_storage(storage);
+        let rule = manager.get_prefix_rule("OLD".to_string()).await.unwrap().unwrap();
+        assert!(rule.compiled_format.is_some());
+        assert!(rule.template().unwrap().sequence_width().is_some());
+    }
+}
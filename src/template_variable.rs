@@ -0,0 +1,133 @@
+//! Caller-supplied template variables.
+//!
+//! A rule can declare named variables (e.g. `region`, `dept`) and reference them in its
+//! format as `{region}`. Their values come with each generate request and are checked
+//! against the declaration: an allowed-value list, a regex, or both.
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::format_template::{is_reserved_name, TemplateError};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TemplateVariable {
+    pub name: String,
+    /// Exhaustive list of accepted values; empty means any value.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed: Vec<String>,
+    /// Regex the whole value has to match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    /// `pattern` anchored and compiled once by `compile`.
+    #[serde(skip)]
+    compiled_pattern: Option<Regex>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum VariableError {
+    #[error("invalid template variable name `{0}`")]
+    InvalidName(String),
+    #[error("invalid pattern `{pattern}` for template variable `{name}`")]
+    InvalidPattern { name: String, pattern: String },
+    #[error("missing value for template variable `{0}`")]
+    Missing(String),
+    #[error("unknown template variable `{0}`")]
+    Unknown(String),
+    #[error("value `{value}` is not allowed for template variable `{name}`")]
+    NotAllowed { name: String, value: String },
+    /// The rule's format does not parse, so the variables it uses are not known.
+    #[error("invalid prefix format: {0}")]
+    Format(#[from] TemplateError),
+}
+
+impl TemplateVariable {
+    pub fn new(name: impl Into<String>) -> Self {
+        TemplateVariable { name: name.into(), ..Default::default() }
+    }
+
+    pub fn allowing<S: Into<String>>(mut self, values: impl IntoIterator<Item = S>) -> Self {
+        self.allowed = values.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn matching(mut self, pattern: impl Into<String>) -> Self {
+        self.pattern = Some(pattern.into());
+        self
+    }
+
+    /// Checks the declaration and compiles its pattern. Names are identifiers and may
+    /// not shadow a built-in placeholder such as `year` or `prefix`.
+    pub fn compile(&mut self) -> Result<(), VariableError> {
+        let mut chars = self.name.chars();
+        let is_identifier = chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !is_identifier || is_reserved_name(&self.name) {
+            return Err(VariableError::InvalidName(self.name.clone()));
+        }
+        self.compiled_pattern = self.anchored_pattern()?;
+        Ok(())
+    }
+
+    /// Checks a supplied value against the declaration.
+    pub fn check(&self, value: &str) -> Result<(), VariableError> {
+        let not_allowed = || VariableError::NotAllowed { name: self.name.clone(), value: value.to_string() };
+        if value.is_empty() || (!self.allowed.is_empty() && !self.allowed.iter().any(|allowed| allowed == value)) {
+            return Err(not_allowed());
+        }
+        let pattern = match &self.compiled_pattern {
+            Some(pattern) => Some(pattern.clone()),
+            None => self.anchored_pattern()?,
+        };
+        match pattern {
+            Some(pattern) if !pattern.is_match(value) => Err(not_allowed()),
+            _ => Ok(()),
+        }
+    }
+
+    fn anchored_pattern(&self) -> Result<Option<Regex>, VariableError> {
+        let Some(pattern) = &self.pattern else {
+            return Ok(None);
+        };
+        Regex::new(&format!("^(?:{})$", pattern))
+            .map(Some)
+            .map_err(|_| VariableError::InvalidPattern { name: self.name.clone(), pattern: pattern.clone() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowed_values() {
+        let region = TemplateVariable::new("region").allowing(["TW", "JP"]);
+        assert_eq!(region.check("TW"), Ok(()));
+        assert_eq!(
+            region.check("US"),
+            Err(VariableError::NotAllowed { name: "region".to_string(), value: "US".to_string() })
+        );
+    }
+
+    #[test]
+    fn test_pattern_must_match_whole_value() {
+        let mut dept = TemplateVariable::new("dept").matching("[A-Z]{2}");
+        dept.compile().unwrap();
+        assert_eq!(dept.check("HR"), Ok(()));
+        assert!(dept.check("HRX").is_err());
+        assert!(dept.check("").is_err());
+    }
+
+    #[test]
+    fn test_compile_rejects_bad_declarations() {
+        assert_eq!(
+            TemplateVariable::new("year").compile(),
+            Err(VariableError::InvalidName("year".to_string()))
+        );
+        assert!(TemplateVariable::new("my-var").compile().is_err());
+        assert!(matches!(
+            TemplateVariable::new("dept").matching("[A-Z").compile(),
+            Err(VariableError::InvalidPattern { .. })
+        ));
+    }
+}